@@ -0,0 +1,600 @@
+//! Bencoded values, a decoder borrowing from its input, an encoder and helpers to find values
+//! in raw bytes.
+
+use anyhow::Result;
+use std::{borrow::Cow, collections::BTreeMap, fmt, ops::Range};
+
+/// Deepest nesting of lists and dictionaries decoded by default.
+pub const MAX_DEPTH: usize = 128;
+
+/// Most values decoded from one input by default, each costs far more memory decoded than the
+/// couple of bytes it takes encoded.
+pub const MAX_ITEMS: usize = 1 << 22;
+
+/// A bencoded value. Strings are bytes as they often are not utf-8, like piece hashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(Cow<'a, [u8]>),
+    List(Vec<Value<'a>>),
+    /// Entries sorted by key, the order they are encoded in.
+    Dict(BTreeMap<Cow<'a, [u8]>, Value<'a>>),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The string, `None` if it is not utf-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Cow<'a, [u8]>, Value<'a>>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Value stored under `key`, `None` if it is missing or this is not a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&Value<'a>> {
+        self.as_dict()?.get(key)
+    }
+
+    /// Copies the borrowed strings, detaching the value from the decoded input.
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Value::Int(value) => Value::Int(value),
+            Value::Bytes(bytes) => Value::Bytes(Cow::Owned(bytes.into_owned())),
+            Value::List(list) => Value::List(list.into_iter().map(Value::into_owned).collect()),
+            Value::Dict(dict) => Value::Dict(
+                dict.into_iter()
+                    .map(|(key, value)| (Cow::Owned(key.into_owned()), value.into_owned()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Appends the canonical encoding of the value.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(value) => out.extend_from_slice(format!("i{value}e").as_bytes()),
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+    out.extend_from_slice(bytes);
+}
+
+impl From<i64> for Value<'_> {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        Value::Bytes(Cow::Borrowed(bytes))
+    }
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(string: &'a str) -> Self {
+        Value::Bytes(Cow::Borrowed(string.as_bytes()))
+    }
+}
+
+impl From<Vec<u8>> for Value<'_> {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(Cow::Owned(bytes))
+    }
+}
+
+impl From<String> for Value<'_> {
+    fn from(string: String) -> Self {
+        Value::Bytes(Cow::Owned(string.into_bytes()))
+    }
+}
+
+impl<'a> From<Vec<Value<'a>>> for Value<'a> {
+    fn from(list: Vec<Value<'a>>) -> Self {
+        Value::List(list)
+    }
+}
+
+impl<'a> From<BTreeMap<Cow<'a, [u8]>, Value<'a>>> for Value<'a> {
+    fn from(dict: BTreeMap<Cow<'a, [u8]>, Value<'a>>) -> Self {
+        Value::Dict(dict)
+    }
+}
+
+/// Decoding failure and the byte offset of the input it happened at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    UnexpectedEnd,
+    UnexpectedByte(u8),
+    InvalidInteger,
+    InvalidLength,
+    /// Leading zeros or a negative zero, only rejected by strict decoders.
+    NonCanonical,
+    /// Dictionary key smaller than the one before, only rejected by strict decoders.
+    UnsortedKey,
+    DuplicateKey,
+    TooDeep,
+    TooManyItems,
+    TrailingData,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self.offset;
+        match self.kind {
+            DecodeErrorKind::UnexpectedEnd => {
+                write!(f, "Unexpected end of bencode at byte {offset}")
+            }
+            DecodeErrorKind::UnexpectedByte(byte) => {
+                write!(f, "Unexpected byte {:?} at byte {offset}", byte as char)
+            }
+            DecodeErrorKind::InvalidInteger => write!(f, "Invalid integer at byte {offset}"),
+            DecodeErrorKind::InvalidLength => write!(f, "Invalid string length at byte {offset}"),
+            DecodeErrorKind::NonCanonical => {
+                write!(f, "Non canonical number at byte {offset}")
+            }
+            DecodeErrorKind::UnsortedKey => write!(f, "Unsorted dictionary key at byte {offset}"),
+            DecodeErrorKind::DuplicateKey => {
+                write!(f, "Duplicate dictionary key at byte {offset}")
+            }
+            DecodeErrorKind::TooDeep => write!(f, "Nesting too deep at byte {offset}"),
+            DecodeErrorKind::TooManyItems => write!(f, "Too many values at byte {offset}"),
+            DecodeErrorKind::TrailingData => write!(f, "Trailing data at byte {offset}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes the canonical bencoded value making up all of `buf`.
+pub fn decode(buf: &[u8]) -> Result<Value<'_>, DecodeError> {
+    let mut decoder = Decoder::new(buf);
+    let value = decoder.decode()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Decodes values one after the other, the strings borrow from the input.
+///
+/// Decoders are strict by default and reject input which is not canonical, as it would not
+/// encode back to the same bytes.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    at: usize,
+    strict: bool,
+    max_depth: usize,
+    max_items: usize,
+    items: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            at: 0,
+            strict: true,
+            max_depth: MAX_DEPTH,
+            max_items: MAX_ITEMS,
+            items: 0,
+        }
+    }
+
+    /// Whether to reject leading zeros and unsorted dictionary keys.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Deepest nesting of lists and dictionaries.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Most values decoded over the lifetime of the decoder, nested ones included.
+    pub fn with_max_items(mut self, max_items: usize) -> Self {
+        self.max_items = max_items;
+        self
+    }
+
+    /// Offset of the next value.
+    pub fn position(&self) -> usize {
+        self.at
+    }
+
+    /// Input after the decoded values, like the raw bytes following a ut_metadata message.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.at..]
+    }
+
+    /// Decodes the next value.
+    pub fn decode(&mut self) -> Result<Value<'a>, DecodeError> {
+        self.value(0)
+    }
+
+    /// Fails if there is input left.
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        match self.at < self.buf.len() {
+            true => Err(self.error(DecodeErrorKind::TrailingData)),
+            false => Ok(()),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value<'a>, DecodeError> {
+        self.items += 1;
+        if self.items > self.max_items {
+            return Err(self.error(DecodeErrorKind::TooManyItems));
+        }
+        match self.peek()? {
+            b'i' => self.int().map(Value::Int),
+            b'0'..=b'9' => self.bytes().map(Value::from),
+            b'l' => {
+                self.enter(depth)?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.at += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.enter(depth)?;
+                let mut dict = BTreeMap::new();
+                let mut last: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let start = self.at;
+                    let key = self.bytes()?;
+                    if dict.contains_key(key) {
+                        return Err(DecodeError::new(start, DecodeErrorKind::DuplicateKey));
+                    }
+                    if self.strict && last.is_some_and(|last| key < last) {
+                        return Err(DecodeError::new(start, DecodeErrorKind::UnsortedKey));
+                    }
+                    last = Some(key);
+                    let value = self.value(depth + 1)?;
+                    dict.insert(Cow::Borrowed(key), value);
+                }
+                self.at += 1;
+                Ok(Value::Dict(dict))
+            }
+            byte => Err(self.error(DecodeErrorKind::UnexpectedByte(byte))),
+        }
+    }
+
+    /// Steps into a list or dictionary.
+    fn enter(&mut self, depth: usize) -> Result<(), DecodeError> {
+        if depth >= self.max_depth {
+            return Err(self.error(DecodeErrorKind::TooDeep));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    fn int(&mut self) -> Result<i64, DecodeError> {
+        let start = self.at;
+        self.at += 1;
+        let digits = self.take_while(|byte| byte.is_ascii_digit() || byte == b'-')?;
+        self.expect(b'e')?;
+        let value = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or(DecodeError::new(start, DecodeErrorKind::InvalidInteger))?;
+        let magnitude = digits.strip_prefix(b"-").unwrap_or(digits);
+        if self.strict && (magnitude.len() > 1 && magnitude[0] == b'0' || digits == b"-0") {
+            return Err(DecodeError::new(start, DecodeErrorKind::NonCanonical));
+        }
+        Ok(value)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.at;
+        let digits = self.take_while(|byte| byte.is_ascii_digit())?;
+        if digits.is_empty() {
+            return Err(self.error(DecodeErrorKind::UnexpectedByte(self.peek()?)));
+        }
+        self.expect(b':')?;
+        let length: usize = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or(DecodeError::new(start, DecodeErrorKind::InvalidLength))?;
+        if self.strict && digits.len() > 1 && digits[0] == b'0' {
+            return Err(DecodeError::new(start, DecodeErrorKind::NonCanonical));
+        }
+        let end = self
+            .at
+            .checked_add(length)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or(DecodeError::new(
+                self.buf.len(),
+                DecodeErrorKind::UnexpectedEnd,
+            ))?;
+        let bytes = &self.buf[self.at..end];
+        self.at = end;
+        Ok(bytes)
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.buf
+            .get(self.at)
+            .copied()
+            .ok_or(self.error(DecodeErrorKind::UnexpectedEnd))
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), DecodeError> {
+        match self.peek()? {
+            next if next == byte => {
+                self.at += 1;
+                Ok(())
+            }
+            next => Err(self.error(DecodeErrorKind::UnexpectedByte(next))),
+        }
+    }
+
+    fn take_while(&mut self, accept: impl Fn(u8) -> bool) -> Result<&'a [u8], DecodeError> {
+        let start = self.at;
+        while accept(self.peek()?) {
+            self.at += 1;
+        }
+        Ok(&self.buf[start..self.at])
+    }
+
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(self.at, kind)
+    }
+}
+
+impl DecodeError {
+    fn new(offset: usize, kind: DecodeErrorKind) -> Self {
+        Self { offset, kind }
+    }
+}
+
+/// Length of the bencoded value at the start of `buf`, for messages which carry raw bytes
+/// after a bencoded dictionary.
+pub fn value_length(buf: &[u8]) -> Result<usize> {
+    let mut decoder = Decoder::new(buf).with_strict(false);
+    decoder.decode()?;
+    Ok(decoder.position())
+}
+
+/// Byte range of the value stored under `key` in the dictionary at the start of `buf`, like
+/// the `info` dictionary of a torrent file which has to be hashed exactly as it was read.
+pub fn dict_value(buf: &[u8], key: &[u8]) -> Result<Option<Range<usize>>> {
+    let entries = dict_entries(buf)?;
+    Ok(entries
+        .into_iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value))
+}
+
+/// Keys of the dictionary at the start of `buf` and the byte ranges of their values, in the
+/// order they appear.
+pub fn dict_entries(buf: &[u8]) -> Result<Vec<(&[u8], Range<usize>)>> {
+    if buf.first() != Some(&b'd') {
+        anyhow::bail!("Expected a bencoded dictionary");
+    }
+    let mut decoder = Decoder::new(buf).with_strict(false);
+    decoder.at = 1;
+    let mut entries = Vec::new();
+    while decoder.peek()? != b'e' {
+        let key = decoder.bytes()?;
+        let start = decoder.position();
+        decoder.value(1)?;
+        entries.push((key, start..decoder.position()));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn dict<'a>(entries: Vec<(&'a str, Value<'a>)>) -> Value<'a> {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (Cow::Borrowed(key.as_bytes()), value))
+                .collect(),
+        )
+    }
+
+    fn error(offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError { offset, kind }
+    }
+
+    #[test]
+    fn test_decode() {
+        let buf = b"d4:listli-42ei0e0:e4:name4:spame";
+        let value = decode(buf).unwrap();
+        assert_eq!(
+            value,
+            dict(vec![
+                (
+                    "list",
+                    Value::List(vec![Value::Int(-42), Value::Int(0), "".into()])
+                ),
+                ("name", "spam".into()),
+            ])
+        );
+        assert_eq!(value.get(b"name").and_then(Value::as_str), Some("spam"));
+        assert_eq!(
+            value.get(b"list").unwrap().as_list().unwrap()[0].as_int(),
+            Some(-42)
+        );
+        assert!(matches!(
+            value.get(b"name"),
+            Some(Value::Bytes(Cow::Borrowed(_)))
+        ));
+        assert_eq!(value.to_bytes(), buf);
+        assert_eq!(value.into_owned().to_bytes(), buf);
+
+        let mut decoder = Decoder::new(b"i1e3:abc\x01\x02");
+        assert_eq!(decoder.decode(), Ok(Value::Int(1)));
+        assert_eq!(decoder.decode(), Ok("abc".into()));
+        assert_eq!(decoder.remaining(), b"\x01\x02");
+        assert_eq!(
+            decoder.finish(),
+            Err(error(8, DecodeErrorKind::TrailingData))
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        use DecodeErrorKind::*;
+        let cases: [(&[u8], DecodeError); 10] = [
+            (b"", error(0, UnexpectedEnd)),
+            (b"li1e", error(4, UnexpectedEnd)),
+            (b"5:spam", error(6, UnexpectedEnd)),
+            (b"i12", error(3, UnexpectedEnd)),
+            (b"i1-2e", error(0, InvalidInteger)),
+            (b"ie", error(0, InvalidInteger)),
+            (b"i99999999999999999999e", error(0, InvalidInteger)),
+            (b"di1ei2ee", error(1, UnexpectedByte(b'i'))),
+            (b"d1:ai1e1:ai2ee", error(7, DuplicateKey)),
+            (b"l1:ax", error(4, UnexpectedByte(b'x'))),
+        ];
+        for (buf, expected) in cases {
+            assert_eq!(decode(buf), Err(expected), "{buf:?}");
+        }
+        assert_eq!(
+            decode(b"99999999999999999999999:a")
+                .unwrap_err()
+                .to_string(),
+            "Invalid string length at byte 0"
+        );
+    }
+
+    #[test]
+    fn test_canonical() {
+        use DecodeErrorKind::*;
+        for (buf, expected) in [
+            (&b"i03e"[..], error(0, NonCanonical)),
+            (b"i-0e", error(0, NonCanonical)),
+            (b"l02:abe", error(1, NonCanonical)),
+            (b"d1:bi1e1:ai2ee", error(7, UnsortedKey)),
+        ] {
+            assert_eq!(decode(buf), Err(expected), "{buf:?}");
+            let mut lenient = Decoder::new(buf).with_strict(false);
+            assert!(lenient.decode().is_ok(), "{buf:?}");
+        }
+        let unsorted = Decoder::new(b"d1:bi1e1:ai2ee")
+            .with_strict(false)
+            .decode()
+            .unwrap();
+        assert_eq!(unsorted.to_bytes(), b"d1:ai2e1:bi1ee");
+    }
+
+    #[test]
+    fn test_limits() {
+        let nested = [&[b'l'; 10][..], &[b'e'; 10]].concat();
+        assert!(Decoder::new(&nested).with_max_depth(10).decode().is_ok());
+        assert_eq!(
+            Decoder::new(&nested).with_max_depth(9).decode(),
+            Err(error(9, DecodeErrorKind::TooDeep))
+        );
+        assert_eq!(
+            decode(&[b'l'; 1 << 16]),
+            Err(error(MAX_DEPTH, DecodeErrorKind::TooDeep))
+        );
+
+        let list = b"li1ei2ei3ee";
+        assert!(Decoder::new(list).with_max_items(4).decode().is_ok());
+        assert_eq!(
+            Decoder::new(list).with_max_items(3).decode(),
+            Err(error(7, DecodeErrorKind::TooManyItems))
+        );
+    }
+
+    #[test]
+    fn test_round_trip_torrents() -> Result<()> {
+        for entry in fs::read_dir("../resources")? {
+            let buf = fs::read(entry?.path())?;
+            assert_eq!(decode(&buf)?.to_bytes(), buf);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_length() {
+        assert_eq!(value_length(b"i42eabc").unwrap(), 4);
+        assert_eq!(value_length(b"4:spamxyz").unwrap(), 6);
+        assert_eq!(value_length(b"d1:ai1e1:bl1:xee\x01\x02").unwrap(), 16);
+        for invalid in [&b""[..], b"d1:a", b"i42", b"5:spam", b"e", b"x"] {
+            assert!(value_length(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_dict_value() {
+        let dict = b"d4:infod4:name1:xe1:zi1ee";
+        assert_eq!(dict_value(dict, b"info").unwrap(), Some(7..18));
+        assert_eq!(dict_value(dict, b"z").unwrap(), Some(21..24));
+        assert_eq!(dict_value(dict, b"name").unwrap(), None);
+        assert!(dict_value(b"l4:infoe", b"info").is_err());
+        assert!(dict_value(b"d4:infod", b"info").is_err());
+
+        let keys: Vec<_> = dict_entries(dict)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, [&b"info"[..], b"z"]);
+    }
+}
@@ -1,11 +1,28 @@
 use core::ops::Deref;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Blockinfo {
     pub offset: u64,
     pub length: u64,
     pub index: usize,
 }
 
+/// Asks for hashes of a v2 file's merkle tree, see BEP 52. The same fields echo back in the
+/// answer or the rejection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashRequest {
+    /// Identifies the file.
+    pub pieces_root: [u8; 32],
+    /// Layer of the requested hashes, 0 are the hashes of the 16 KiB blocks.
+    pub base_layer: u32,
+    /// Index of the first hash in the base layer, a multiple of `length`.
+    pub index: u32,
+    /// Number of hashes, a power of two.
+    pub length: u32,
+    /// Number of uncle hashes above the requested ones which prove them.
+    pub proof_layers: u32,
+}
+
 /// Block is basic unit of all operations in storage module. No modules outside storage should be
 /// aware of anything other than [Block] and TorrentEngine.
 ///
@@ -13,6 +30,7 @@ pub struct Blockinfo {
 /// tell the storage engine about what data to store and for which torrent.
 ///
 /// [Block] serves as a way of a data holder between engine and storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Block {
     pub block_info: Blockinfo,
     data: Vec<u8>,
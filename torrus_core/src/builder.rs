@@ -0,0 +1,619 @@
+use crate::{
+    merkle::{block_hash, Hash, MerkleTree, LEAF_SIZE},
+    metainfo::{File, FileTree, Info, Metainfo, TreeFile},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Smallest piece length, v2 torrents need at least a whole merkle leaf per piece.
+const MIN_PIECE_LENGTH: u64 = LEAF_SIZE;
+
+/// Largest piece length picked automatically.
+const MAX_PIECE_LENGTH: u64 = 1 << 24;
+
+/// Number of pieces an automatically picked piece length aims for.
+const TARGET_PIECES: u64 = 1500;
+
+/// Creates the metainfo of a file or a directory.
+///
+/// ```no_run
+/// # use torrus_core::builder::MetainfoBuilder;
+/// let metainfo = MetainfoBuilder::new("dist")
+///     .with_tier(vec!["udp://tracker.example.org:1337/announce".into()])
+///     .with_hybrid(true)
+///     .build(|hashed, total| println!("{hashed}/{total} pieces"))?;
+/// std::fs::write("dist.torrent", metainfo.to_bytes()?)?;
+/// # anyhow::Ok(())
+/// ```
+pub struct MetainfoBuilder {
+    path: PathBuf,
+    name: Option<String>,
+    piece_length: Option<u64>,
+    tiers: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    source: Option<String>,
+    creation_date: Option<i64>,
+    pad_files: bool,
+    hybrid: bool,
+    threads: Option<usize>,
+}
+
+/// A file of the torrent on disk.
+struct SourceFile {
+    /// Path in the torrent, below the torrent directory for multi file torrents.
+    path: Vec<String>,
+    location: PathBuf,
+    length: u64,
+}
+
+/// Part of the data the pieces are cut from, `None` are the zeros of a padding file.
+struct Segment<'a> {
+    file: Option<&'a SourceFile>,
+    offset: u64,
+    length: u64,
+}
+
+struct PieceHashes {
+    sha1: Vec<u8>,
+    /// Hashes of the 16 KiB blocks of file data in the piece, hybrid torrents only.
+    leaves: Vec<Hash>,
+}
+
+impl MetainfoBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            name: None,
+            piece_length: None,
+            tiers: Vec::new(),
+            web_seeds: Vec::new(),
+            private: false,
+            comment: None,
+            created_by: None,
+            source: None,
+            creation_date: None,
+            pad_files: false,
+            hybrid: false,
+            threads: None,
+        }
+    }
+
+    /// Name of the torrent, the file or directory name by default.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Piece length in bytes, a power of two of at least 16 KiB. Picked from the size of the
+    /// content by default.
+    pub fn with_piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tier of trackers, tiers are tried in the order they were added.
+    pub fn with_tier(mut self, trackers: Vec<String>) -> Self {
+        self.tiers.push(trackers);
+        self
+    }
+
+    /// Adds a web seed url, see BEP 19.
+    pub fn with_web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn with_created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    /// Source tag of private trackers, changes the info hash.
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Creation date as a unix timestamp, the current time by default.
+    pub fn with_creation_date(mut self, creation_date: i64) -> Self {
+        self.creation_date = Some(creation_date);
+        self
+    }
+
+    /// Pads every file but the last to a piece boundary with padding files, see BEP 47.
+    pub fn with_pad_files(mut self, pad_files: bool) -> Self {
+        self.pad_files = pad_files;
+        self
+    }
+
+    /// Adds the merkle trees of v2 next to the v1 piece hashes, which implies pad files.
+    pub fn with_hybrid(mut self, hybrid: bool) -> Self {
+        self.hybrid = hybrid;
+        self
+    }
+
+    /// Number of threads hashing pieces, one per core by default.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads.max(1));
+        self
+    }
+
+    /// Hashes the content and creates the metainfo. `progress` is called with the number of
+    /// hashed pieces and the number of pieces after every piece.
+    pub fn build(self, mut progress: impl FnMut(usize, usize)) -> Result<Metainfo> {
+        let single_file = fs::metadata(&self.path)
+            .with_context(|| format!("Can not read {}", self.path.display()))?
+            .is_file();
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => file_name(&self.path)?,
+        };
+        let files = match single_file {
+            true => vec![SourceFile {
+                path: vec![name.clone()],
+                location: self.path.clone(),
+                length: fs::metadata(&self.path)?.len(),
+            }],
+            false => {
+                let mut files = Vec::new();
+                walk(&self.path, &mut Vec::new(), &mut files)?;
+                files
+            }
+        };
+
+        let total_length: u64 = files.iter().map(|file| file.length).sum();
+        if total_length == 0 {
+            bail!("Nothing to hash in {}", self.path.display());
+        }
+        let piece_length = self.piece_length(total_length)?;
+
+        // v1 files and the data they make up, padding files included.
+        let mut entries = Vec::new();
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        for (number, file) in files.iter().enumerate() {
+            entries.push(File::new(file.path.clone(), file.length));
+            segments.push(Segment {
+                file: Some(file),
+                offset,
+                length: file.length,
+            });
+            offset += file.length;
+
+            let padding = (piece_length - offset % piece_length) % piece_length;
+            if (self.pad_files || self.hybrid) && padding > 0 && number + 1 < files.len() {
+                entries.push(File::padding(padding));
+                segments.push(Segment {
+                    file: None,
+                    offset,
+                    length: padding,
+                });
+                offset += padding;
+            }
+        }
+
+        let hashes = self.hash_pieces(&segments, offset, piece_length, &mut progress)?;
+        let pieces: Vec<u8> = hashes.iter().flat_map(|piece| piece.sha1.clone()).collect();
+
+        let mut file_tree = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        if self.hybrid {
+            let piece_layer = (piece_length / LEAF_SIZE).trailing_zeros();
+            for segment in &segments {
+                let Some(file) = segment.file else {
+                    continue;
+                };
+                let mut tree_file = TreeFile {
+                    length: file.length,
+                    pieces_root: None,
+                };
+                if file.length > 0 {
+                    let first = (segment.offset / piece_length) as usize;
+                    let count = file.length.div_ceil(piece_length) as usize;
+                    let leaves = hashes[first..first + count]
+                        .iter()
+                        .flat_map(|piece| piece.leaves.clone())
+                        .collect();
+                    let tree = MerkleTree::new(leaves, 0);
+                    tree_file.pieces_root = Some(ByteBuf::from(tree.root().to_vec()));
+                    // Files of a single piece are verified against their root alone.
+                    if count > 1 {
+                        let layer = tree.layer(piece_layer).unwrap()[..count].concat();
+                        piece_layers
+                            .insert(ByteBuf::from(tree.root().to_vec()), ByteBuf::from(layer));
+                    }
+                }
+                insert(&mut file_tree, &file.path, tree_file);
+            }
+        }
+
+        let info = Info {
+            name,
+            pieces: ByteBuf::from(pieces),
+            piece_length,
+            md5sum: None,
+            length: if single_file { total_length } else { 0 },
+            files: (!single_file).then_some(entries),
+            private: self.private.then_some(1),
+            root_hash: None,
+            meta_version: self.hybrid.then_some(2),
+            file_tree: self.hybrid.then_some(FileTree::Directory(file_tree)),
+            source: self.source,
+        };
+        let mut metainfo = Metainfo::from_info_bytes(serde_bencode::to_bytes(&info)?)?;
+
+        let mut trackers = self.tiers.iter().flatten();
+        metainfo.announce = trackers.next().cloned();
+        if trackers.next().is_some() {
+            metainfo.announce_list = Some(self.tiers);
+        }
+        metainfo.url_list = (!self.web_seeds.is_empty()).then_some(self.web_seeds);
+        metainfo.comment = self.comment;
+        metainfo.created_by = self.created_by;
+        metainfo.creation_date = Some(self.creation_date.unwrap_or_else(now));
+        metainfo.piece_layers = (!piece_layers.is_empty()).then_some(piece_layers);
+        Ok(metainfo)
+    }
+
+    fn piece_length(&self, total_length: u64) -> Result<u64> {
+        match self.piece_length {
+            Some(length) if length < MIN_PIECE_LENGTH || !length.is_power_of_two() => {
+                bail!("Piece length {length} is not a power of two of at least 16 KiB")
+            }
+            Some(length) => Ok(length),
+            None => Ok((total_length / TARGET_PIECES)
+                .next_power_of_two()
+                .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)),
+        }
+    }
+
+    /// Hashes every piece on all threads, the results are in piece order.
+    fn hash_pieces(
+        &self,
+        segments: &[Segment],
+        length: u64,
+        piece_length: u64,
+        progress: &mut impl FnMut(usize, usize),
+    ) -> Result<Vec<PieceHashes>> {
+        let num_pieces = length.div_ceil(piece_length) as usize;
+        let threads = self
+            .threads
+            .or_else(|| thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1);
+        let next = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            for _ in 0..threads.min(num_pieces) {
+                let tx = tx.clone();
+                let next = &next;
+                scope.spawn(move || loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= num_pieces {
+                        break;
+                    }
+                    let start = index as u64 * piece_length;
+                    let end = length.min(start + piece_length);
+                    let hashed = self.hash_piece(segments, start..end);
+                    // The receiver is gone once hashing failed somewhere.
+                    if tx.send((index, hashed)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(tx);
+
+            let mut pieces: Vec<Option<PieceHashes>> = (0..num_pieces).map(|_| None).collect();
+            for (done, (index, hashed)) in rx.into_iter().enumerate() {
+                pieces[index] = Some(hashed?);
+                progress(done + 1, num_pieces);
+            }
+            pieces
+                .into_iter()
+                .map(|piece| piece.ok_or_else(|| anyhow!("A piece was not hashed")))
+                .collect()
+        })
+    }
+
+    fn hash_piece(&self, segments: &[Segment], range: std::ops::Range<u64>) -> Result<PieceHashes> {
+        let mut data = vec![0; (range.end - range.start) as usize];
+        // With pad files the file data of a piece comes before its padding.
+        let mut file_bytes = 0;
+        let first =
+            segments.partition_point(|segment| segment.offset + segment.length <= range.start);
+        for segment in &segments[first..] {
+            if segment.offset >= range.end {
+                break;
+            }
+            let from = range.start.max(segment.offset);
+            let to = range.end.min(segment.offset + segment.length);
+            let Some(file) = segment.file.filter(|_| from < to) else {
+                continue;
+            };
+            let mut handle = fs::File::open(&file.location)?;
+            handle.seek(SeekFrom::Start(from - segment.offset))?;
+            let buf = &mut data[(from - range.start) as usize..(to - range.start) as usize];
+            handle
+                .read_exact(buf)
+                .with_context(|| format!("{} changed while hashing", file.location.display()))?;
+            file_bytes = (to - range.start) as usize;
+        }
+
+        let leaves = match self.hybrid {
+            true => data[..file_bytes]
+                .chunks(LEAF_SIZE as usize)
+                .map(block_hash)
+                .collect(),
+            false => Vec::new(),
+        };
+        Ok(PieceHashes {
+            sha1: Sha1::digest(&data).to_vec(),
+            leaves,
+        })
+    }
+}
+
+/// Collects the files below `dir` sorted by path, which is the order of a v2 file tree.
+/// Symbolic links are skipped, they could point to a file twice or to a parent directory.
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<SourceFile>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let location = entry.path();
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("File name {name:?} is not valid utf-8"))?;
+        prefix.push(name);
+        let metadata = fs::symlink_metadata(&location)?;
+        if metadata.is_dir() {
+            walk(&location, prefix, files)?;
+        } else if metadata.is_file() {
+            files.push(SourceFile {
+                path: prefix.clone(),
+                location,
+                length: metadata.len(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    let name = path
+        .canonicalize()?
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no name", path.display()))?
+        .to_owned();
+    name.into_string()
+        .map_err(|name| anyhow!("File name {name:?} is not valid utf-8"))
+}
+
+/// Adds a file to a v2 file tree, creating the directories on its path.
+fn insert(tree: &mut BTreeMap<String, FileTree>, path: &[String], file: TreeFile) {
+    let (name, parents) = path.split_last().unwrap();
+    let mut entries = tree;
+    for parent in parents {
+        let node = entries
+            .entry(parent.clone())
+            .or_insert_with(|| FileTree::Directory(BTreeMap::new()));
+        let FileTree::Directory(children) = node else {
+            unreachable!("{parent} is a directory on disk");
+        };
+        entries = children;
+    }
+    entries.insert(name.clone(), FileTree::File(file));
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        merkle::MerkleTree,
+        metainfo::MetaVersion,
+        prelude::{Sha1Hash, ID},
+    };
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrus-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn data(length: usize, seed: u8) -> Vec<u8> {
+        (0..length).map(|n| (n % 251) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn test_build_hybrid() -> Result<()> {
+        let dir = test_dir("build-hybrid").join("content");
+        fs::create_dir_all(dir.join("sub"))?;
+        fs::write(dir.join("a"), data(20000, 1))?;
+        fs::write(dir.join("c"), [])?;
+        fs::write(dir.join("sub/b"), data(40000, 2))?;
+
+        let mut calls = Vec::new();
+        let built = MetainfoBuilder::new(&dir)
+            .with_piece_length(1 << 15)
+            .with_tier(vec!["http://a/announce".into(), "http://b/announce".into()])
+            .with_tier(vec!["udp://c:1337".into()])
+            .with_web_seed("https://example.com/content/")
+            .with_private(true)
+            .with_comment("comment")
+            .with_created_by("torrus")
+            .with_source("source")
+            .with_creation_date(1)
+            .with_hybrid(true)
+            .build(|hashed, total| calls.push((hashed, total)))?;
+        assert_eq!(calls, [(1, 3), (2, 3), (3, 3)]);
+
+        let metainfo = Metainfo::new(&built.to_bytes()?)?;
+        assert_eq!(metainfo.as_sha1(), built.as_sha1());
+        assert_eq!(metainfo.validate()?, []);
+        assert_eq!(metainfo.announce.as_deref(), Some("http://a/announce"));
+        assert_eq!(metainfo.announce_list.as_ref().unwrap().len(), 2);
+        assert_eq!(metainfo.url_list.as_ref().unwrap().len(), 1);
+        assert_eq!(metainfo.comment.as_deref(), Some("comment"));
+        assert_eq!(metainfo.created_by.as_deref(), Some("torrus"));
+        assert_eq!(metainfo.creation_date, Some(1));
+
+        let info = &metainfo.info;
+        assert_eq!(info.name, "content");
+        assert_eq!(info.version(), MetaVersion::Hybrid);
+        assert!(info.is_private());
+        assert_eq!(info.source.as_deref(), Some("source"));
+
+        // The empty file needs no padding, the last file none either.
+        let files = info.files();
+        let paths: Vec<_> = files.iter().map(|file| file.path.join("/")).collect();
+        assert_eq!(
+            paths,
+            [
+                "content/a",
+                "content/.pad/12768",
+                "content/c",
+                "content/sub/b"
+            ]
+        );
+        assert!(files[1].padding);
+        assert_eq!(files[3].offset, 1 << 15);
+
+        let mut stream = data(20000, 1);
+        stream.resize(1 << 15, 0);
+        stream.extend(data(40000, 2));
+        for (index, piece) in stream.chunks(1 << 15).enumerate() {
+            assert_eq!(
+                info.piece_hash(index),
+                Some(ID::from(Sha1::digest(piece).to_vec()))
+            );
+        }
+
+        let a = MerkleTree::from_data(&data(20000, 1));
+        let b = MerkleTree::from_data(&data(40000, 2));
+        assert_eq!(files[0].pieces_root, Some(a.root()));
+        assert_eq!(files[2].pieces_root, None);
+        assert_eq!(files[3].pieces_root, Some(b.root()));
+        assert_eq!(metainfo.piece_layer(&a.root()), None);
+        assert_eq!(
+            metainfo.piece_layer(&b.root()),
+            Some(&b.layer(1).unwrap()[..2].concat()[..])
+        );
+
+        fs::remove_dir_all(dir.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_single_file() -> Result<()> {
+        let dir = test_dir("build-single");
+        let path = dir.join("file.iso");
+        let content = data(100_000, 3);
+        fs::write(&path, &content)?;
+
+        let build = |threads| {
+            MetainfoBuilder::new(&path)
+                .with_tier(vec!["http://a/announce".into()])
+                .with_pad_files(true)
+                .with_threads(threads)
+                .build(|_, _| {})
+        };
+        let metainfo = build(1)?;
+        assert_eq!(metainfo.as_sha1(), build(4)?.as_sha1());
+        assert_eq!(metainfo.announce.as_deref(), Some("http://a/announce"));
+        assert_eq!(metainfo.announce_list, None);
+        assert!(metainfo.creation_date.is_some());
+
+        let info = &metainfo.info;
+        assert_eq!(info.name, "file.iso");
+        assert_eq!(info.version(), MetaVersion::V1);
+        assert_eq!(info.piece_length, MIN_PIECE_LENGTH);
+        assert_eq!(info.length, 100_000);
+        assert!(info.files.is_none());
+        for (index, piece) in content.chunks(MIN_PIECE_LENGTH as usize).enumerate() {
+            assert_eq!(
+                info.piece_hash(index),
+                Some(ID::from(Sha1::digest(piece).to_vec()))
+            );
+        }
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_skips_symlinks() -> Result<()> {
+        let dir = test_dir("build-symlinks").join("content");
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("a"), data(20000, 1))?;
+        std::os::unix::fs::symlink(dir.join("a"), dir.join("b"))?;
+        std::os::unix::fs::symlink(&dir, dir.join("parent"))?;
+
+        let metainfo = MetainfoBuilder::new(&dir).build(|_, _| {})?;
+        let files = metainfo.info.files.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, ["a"]);
+
+        fs::remove_dir_all(dir.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_errors() -> Result<()> {
+        let dir = test_dir("build-errors");
+        assert!(MetainfoBuilder::new(&dir).build(|_, _| {}).is_err());
+        assert!(MetainfoBuilder::new(dir.join("missing"))
+            .build(|_, _| {})
+            .is_err());
+
+        fs::write(dir.join("a"), [1; 10])?;
+        for piece_length in [1 << 13, 3 << 14] {
+            assert!(MetainfoBuilder::new(&dir)
+                .with_piece_length(piece_length)
+                .build(|_, _| {})
+                .is_err());
+        }
+
+        let builder = MetainfoBuilder::new(&dir);
+        assert_eq!(builder.piece_length(1 << 20)?, MIN_PIECE_LENGTH);
+        assert_eq!(builder.piece_length(1 << 40)?, MAX_PIECE_LENGTH);
+        assert_eq!(builder.piece_length(3 << 30)?, 1 << 22);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
 use serde::{Deserialize, Deserializer, Serialize};
-use std::{ops::Deref, str::FromStr};
+use std::{fmt::Display, ops::Deref, str::FromStr};
 
 /// [ID] is for info hash, 20 byte identity for Peers.
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub struct ID([u8; 20]);
 
 impl Deref for ID {
@@ -33,6 +33,55 @@ impl FromStr for ID {
     }
 }
 
+impl Display for ID {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// SHA-256 info hash of a v2 or hybrid torrent, see BEP 52.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct InfoHashV2([u8; 32]);
+
+impl InfoHashV2 {
+    /// The first 20 bytes, which stand in for the info hash where only 20 bytes fit like in
+    /// the handshake or tracker announces.
+    pub fn truncated(&self) -> ID {
+        ID(self.0[..20].try_into().unwrap())
+    }
+}
+
+impl Deref for InfoHashV2 {
+    type Target = [u8; 32];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for InfoHashV2 {
+    fn from(value: [u8; 32]) -> Self {
+        InfoHashV2(value)
+    }
+}
+
+impl FromStr for InfoHashV2 {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut out = [0u8; 32];
+        if s.len() != 64 {
+            anyhow::bail!("Expected size of 64");
+        }
+        hex::decode_to_slice(s, &mut out)?;
+        Ok(InfoHashV2(out))
+    }
+}
+
+impl Display for InfoHashV2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
 impl Serialize for ID {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
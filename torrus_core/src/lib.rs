@@ -1,13 +1,20 @@
+pub mod bencode;
 pub mod block;
+pub mod builder;
 pub mod id;
+pub mod magnet;
+pub mod merkle;
 pub mod metainfo;
 pub mod peer;
 pub mod store;
+pub mod validate;
 
 pub mod prelude {
     pub use super::block::*;
-    pub use super::id::ID;
-    pub use super::metainfo::{Info, Metainfo};
+    pub use super::builder::MetainfoBuilder;
+    pub use super::id::{InfoHashV2, ID};
+    pub use super::magnet::MagnetLink;
+    pub use super::metainfo::{Info, MetaVersion, Metainfo, TorrentFile};
     pub use super::peer::*;
 
     pub trait Sha1Hash {
@@ -0,0 +1,302 @@
+use crate::prelude::{InfoHashV2, ID};
+use anyhow::{bail, Context, Result};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{fmt::Display, ops::RangeInclusive, str::FromStr};
+
+/// Characters left as they are in parameter values, `:` and `/` keep urls readable.
+const VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b':')
+    .remove(b'/');
+
+/// Multihash prefix of a SHA-256 digest, `btmh` hashes start with it.
+const SHA256_MULTIHASH: [u8; 2] = [0x12, 0x20];
+
+/// A magnet link, which identifies a torrent by its info hash so the info dictionary can be
+/// fetched from peers.
+///
+/// ```text
+/// magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>&ws=<web seed>&x.pe=<peer>&so=0,2-4
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagnetLink {
+    /// v1 info hash, from `xt=urn:btih:` in hex or base32.
+    pub info_hash: Option<ID>,
+    /// SHA-256 v2 info hash, from `xt=urn:btmh:`.
+    pub info_hash_v2: Option<InfoHashV2>,
+    /// Display name, `dn`.
+    pub name: Option<String>,
+    /// Tracker urls, `tr`.
+    pub trackers: Vec<String>,
+    /// Web seed urls, `ws`.
+    pub web_seeds: Vec<String>,
+    /// Peer addresses as `host:port`, `x.pe`.
+    pub peers: Vec<String>,
+    /// Indices of the files to download, `so`. Empty selects every file.
+    pub select_only: Vec<RangeInclusive<usize>>,
+}
+
+impl MagnetLink {
+    pub fn new(info_hash: ID) -> Self {
+        Self {
+            info_hash: Some(info_hash),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the file at `index` is selected by `so`.
+    pub fn is_selected(&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|r| r.contains(&index))
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(query) = s
+            .get(..8)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("magnet:?"))
+            .map(|_| &s[8..])
+        else {
+            bail!("Expected a magnet: uri");
+        };
+
+        let mut magnet = MagnetLink::default();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = decode(value)?;
+            match key {
+                "xt" => magnet.parse_topic(&value)?,
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                "x.pe" => magnet.peers.push(value),
+                "so" => magnet.select_only.extend(parse_select_only(&value)?),
+                // Parameters like `xl` or `kt` are not used.
+                _ => {}
+            }
+        }
+
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            bail!("Magnet link has no btih or btmh info hash");
+        }
+        Ok(magnet)
+    }
+}
+
+impl MagnetLink {
+    fn parse_topic(&mut self, topic: &str) -> Result<()> {
+        let Some((urn, hash)) = topic.rsplit_once(':') else {
+            bail!("Invalid exact topic {topic}");
+        };
+        match urn.to_ascii_lowercase().as_str() {
+            "urn:btih" => {
+                if self.info_hash.is_some() {
+                    bail!("Magnet link has more than one btih info hash");
+                }
+                self.info_hash = Some(parse_btih(hash)?);
+            }
+            "urn:btmh" => {
+                if self.info_hash_v2.is_some() {
+                    bail!("Magnet link has more than one btmh info hash");
+                }
+                self.info_hash_v2 = Some(parse_btmh(hash)?);
+            }
+            // Other hashes like `urn:sha1` do not identify a torrent.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn decode(value: &str) -> Result<String> {
+    let value = value.replace('+', " ");
+    let decoded = percent_decode_str(&value)
+        .decode_utf8()
+        .context("Magnet link is not valid utf-8")?;
+    Ok(decoded.into_owned())
+}
+
+fn parse_btih(hash: &str) -> Result<ID> {
+    match hash.len() {
+        40 => hash.parse(),
+        32 => {
+            let bytes = data_encoding::BASE32
+                .decode(hash.to_ascii_uppercase().as_bytes())
+                .context("Invalid base32 btih info hash")?;
+            Ok(ID::from(bytes))
+        }
+        len => bail!("Expected a btih info hash of 40 or 32 characters, got {len}"),
+    }
+}
+
+fn parse_btmh(hash: &str) -> Result<InfoHashV2> {
+    let bytes = hex::decode(hash).context("Invalid btmh info hash")?;
+    let Some(digest) = bytes.strip_prefix(&SHA256_MULTIHASH) else {
+        bail!("Expected a SHA-256 multihash as btmh info hash");
+    };
+    let digest: [u8; 32] = digest
+        .try_into()
+        .context("Expected a 32 byte btmh info hash")?;
+    Ok(digest.into())
+}
+
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>> {
+    value
+        .split(',')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let range = match part.split_once('-') {
+                Some((start, end)) => start.parse()?..=end.parse()?,
+                None => {
+                    let index = part.parse()?;
+                    index..=index
+                }
+            };
+            if range.is_empty() {
+                bail!("Invalid file range {part}");
+            }
+            Ok(range)
+        })
+        .collect()
+}
+
+impl Display for MagnetLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("magnet:?")?;
+        let mut separator = "";
+        let mut param = |f: &mut std::fmt::Formatter<'_>, key: &str, value: &str| {
+            let result = write!(f, "{separator}{key}={}", utf8_percent_encode(value, VALUE));
+            separator = "&";
+            result
+        };
+
+        if let Some(info_hash) = &self.info_hash {
+            param(f, "xt", &format!("urn:btih:{info_hash}"))?;
+        }
+        if let Some(info_hash) = &self.info_hash_v2 {
+            let hash = hex::encode([&SHA256_MULTIHASH[..], &info_hash[..]].concat());
+            param(f, "xt", &format!("urn:btmh:{hash}"))?;
+        }
+        if let Some(name) = &self.name {
+            param(f, "dn", name)?;
+        }
+        for tracker in &self.trackers {
+            param(f, "tr", tracker)?;
+        }
+        for web_seed in &self.web_seeds {
+            param(f, "ws", web_seed)?;
+        }
+        for peer in &self.peers {
+            param(f, "x.pe", peer)?;
+        }
+        if !self.select_only.is_empty() {
+            let ranges: Vec<_> = self
+                .select_only
+                .iter()
+                .map(|range| match range.start() == range.end() {
+                    true => range.start().to_string(),
+                    false => format!("{}-{}", range.start(), range.end()),
+                })
+                .collect();
+            param(f, "so", &ranges.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "c9e15763f722f23e98a29decdfae341b98d53056";
+
+    #[test]
+    fn test_parse_hex_info_hash() {
+        let magnet: MagnetLink = format!("magnet:?xt=urn:btih:{HASH}").parse().unwrap();
+        assert_eq!(magnet, MagnetLink::new(HASH.parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_base32_info_hash() {
+        let base32 = data_encoding::BASE32.encode(&hex::decode(HASH).unwrap());
+        let magnet: MagnetLink = format!("magnet:?xt=urn:btih:{}", base32.to_lowercase())
+            .parse()
+            .unwrap();
+        assert_eq!(magnet.info_hash, Some(HASH.parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_every_parameter() {
+        let hash_v2 = "1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let uri = format!(
+            "magnet:?xt=urn:btih:{HASH}&xt=urn:btmh:{hash_v2}&dn=Big+Buck%20Bunny\
+            &tr=udp%3A%2F%2Ftracker.example.org%3A1337&tr=http://tracker.example.com/announce?a=b%26c\
+            &ws=https://example.com/files/&x.pe=10.0.0.1:6881&x.pe=[::1]:6881&so=0,2,4-6&xl=100"
+        );
+        let magnet: MagnetLink = uri.parse().unwrap();
+
+        let mut expected_v2 = [0u8; 32];
+        hex::decode_to_slice(&hash_v2[4..], &mut expected_v2).unwrap();
+        assert_eq!(magnet.info_hash_v2, Some(expected_v2.into()));
+        assert_eq!(magnet.name.as_deref(), Some("Big Buck Bunny"));
+        assert_eq!(
+            magnet.trackers,
+            [
+                "udp://tracker.example.org:1337",
+                "http://tracker.example.com/announce?a=b&c"
+            ]
+        );
+        assert_eq!(magnet.web_seeds, ["https://example.com/files/"]);
+        assert_eq!(magnet.peers, ["10.0.0.1:6881", "[::1]:6881"]);
+        assert_eq!(magnet.select_only, [0..=0, 2..=2, 4..=6]);
+        assert!(magnet.is_selected(5));
+        assert!(!magnet.is_selected(3));
+    }
+
+    #[test]
+    fn test_parse_v2_only() {
+        let hash_v2 = format!("1220{}", "ab".repeat(32));
+        let magnet: MagnetLink = format!("magnet:?xt=urn:btmh:{hash_v2}").parse().unwrap();
+        assert_eq!(magnet.info_hash, None);
+        assert_eq!(magnet.info_hash_v2, Some([0xab; 32].into()));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let magnet = MagnetLink {
+            info_hash: Some(HASH.parse().unwrap()),
+            info_hash_v2: Some([7; 32].into()),
+            name: Some("a & b + c/d".into()),
+            trackers: vec![
+                "udp://tracker.example.org:1337/announce".into(),
+                "http://tracker.example.com/announce?key=a+b&c=d".into(),
+            ],
+            web_seeds: vec!["https://example.com/seed".into()],
+            peers: vec!["10.0.0.1:6881".into(), "[::1]:51413".into()],
+            select_only: vec![1..=1, 3..=7],
+        };
+        let uri = magnet.to_string();
+        assert_eq!(uri.parse::<MagnetLink>().unwrap(), magnet);
+        assert_eq!(uri.parse::<MagnetLink>().unwrap().to_string(), uri);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for uri in [
+            "http://example.com",
+            "magnet:?dn=name",
+            "magnet:?xt=urn:btih:1234",
+            "magnet:?xt=urn:btih:zz1e15763f722f23e98a29decdfae341b98d53056",
+            "magnet:?xt=urn:btmh:1114c9e15763f722f23e98a29decdfae341b98d53056",
+            &format!("magnet:?xt=urn:btih:{HASH}&xt=urn:btih:{HASH}"),
+            &format!("magnet:?xt=urn:btih:{HASH}&so=4-2"),
+            &format!("magnet:?xt=urn:btih:{HASH}&so=a"),
+        ] {
+            assert!(uri.parse::<MagnetLink>().is_err(), "{uri}");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Merkle hash trees of v2 torrents, see BEP 52.
+//!
+//! Every file has its own tree over the SHA-256 hashes of its 16 KiB blocks, padded with zero
+//! hashes to a power of two.
+
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks whose hashes are the leaves of a tree.
+pub const LEAF_SIZE: u64 = 1 << 14;
+
+pub type Hash = [u8; 32];
+
+/// Hash of a 16 KiB block, only the last block of a file may be shorter.
+pub fn block_hash(block: &[u8]) -> Hash {
+    Sha256::digest(block).into()
+}
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Hash of the nodes of `layer` past the end of a file, the root of a subtree of zero leaves.
+pub fn pad_hash(layer: u32) -> Hash {
+    (0..layer).fold([0; 32], |hash, _| hash_pair(&hash, &hash))
+}
+
+/// Root reached from the node at `index` of a layer and the uncle hashes above it.
+pub fn root_from_proof(mut node: Hash, mut index: usize, uncles: &[Hash]) -> Hash {
+    for uncle in uncles {
+        node = match index % 2 {
+            0 => hash_pair(&node, uncle),
+            _ => hash_pair(uncle, &node),
+        };
+        index /= 2;
+    }
+    node
+}
+
+/// Every layer of a tree from a base layer up to the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    base: u32,
+    /// Layers from the base upwards, the base is padded to a power of two.
+    layers: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    /// Tree above the `hashes` of layer `base`, padded with [pad_hash]es to a power of two.
+    pub fn new(mut hashes: Vec<Hash>, base: u32) -> Self {
+        hashes.resize(hashes.len().next_power_of_two(), pad_hash(base));
+        let mut layers = vec![hashes];
+        while let Some(layer) = layers.last().filter(|layer| layer.len() > 1) {
+            let parent = layer
+                .chunks_exact(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1]))
+                .collect();
+            layers.push(parent);
+        }
+        Self { base, layers }
+    }
+
+    /// Tree over the 16 KiB blocks of `data`.
+    pub fn from_data(data: &[u8]) -> Self {
+        let leaves = data.chunks(LEAF_SIZE as usize).map(block_hash).collect();
+        Self::new(leaves, 0)
+    }
+
+    pub fn root(&self) -> Hash {
+        self.layers[self.layers.len() - 1][0]
+    }
+
+    /// Layer the root is in, blocks are layer 0.
+    pub fn root_layer(&self) -> u32 {
+        self.base + self.layers.len() as u32 - 1
+    }
+
+    pub fn layer(&self, layer: u32) -> Option<&[Hash]> {
+        let layer = self.layers.get(layer.checked_sub(self.base)? as usize)?;
+        Some(layer)
+    }
+
+    /// Uncle hashes from the node at `index` of `layer` up to the root, see [root_from_proof].
+    pub fn proof(&self, layer: u32, mut index: usize) -> Vec<Hash> {
+        let start = layer.saturating_sub(self.base) as usize;
+        let end = self.layers.len() - 1;
+        self.layers[start.min(end)..end]
+            .iter()
+            .map(|layer| {
+                let uncle = layer[index ^ 1];
+                index /= 2;
+                uncle
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Five blocks, the last one short.
+    fn data() -> Vec<u8> {
+        (0..LEAF_SIZE * 4 + 100).map(|n| (n % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        let data = data();
+        let tree = MerkleTree::from_data(&data);
+        let leaves: Vec<_> = data.chunks(LEAF_SIZE as usize).map(block_hash).collect();
+
+        // Five leaves are padded to eight.
+        assert_eq!(tree.root_layer(), 3);
+        assert_eq!(tree.layer(0).unwrap()[5..], [[0; 32]; 3]);
+        assert_eq!(tree.layer(1).unwrap()[3], pad_hash(1));
+        let left = hash_pair(
+            &hash_pair(&leaves[0], &leaves[1]),
+            &hash_pair(&leaves[2], &leaves[3]),
+        );
+        let right = hash_pair(&hash_pair(&leaves[4], &[0; 32]), &pad_hash(1));
+        assert_eq!(tree.root(), hash_pair(&left, &right));
+
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = tree.proof(0, index);
+            assert_eq!(proof.len(), 3);
+            assert_eq!(root_from_proof(*leaf, index, &proof), tree.root());
+        }
+
+        // A tree above the piece layer has the same root.
+        let pieces = tree.layer(1).unwrap()[..3].to_vec();
+        assert_eq!(MerkleTree::new(pieces, 1).root(), tree.root());
+        assert_eq!(MerkleTree::from_data(&[]).root(), [0; 32]);
+    }
+}
@@ -1,12 +1,19 @@
 use anyhow::Result;
+use serde::{de, ser::SerializeMap, Deserializer, Serializer};
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
-use std::fmt::Display;
+use sha2::Sha256;
+use std::{collections::BTreeMap, fmt::Display};
 
-use crate::{id::ID, prelude::Sha1Hash};
+use crate::{
+    bencode,
+    id::{InfoHashV2, ID},
+    prelude::Sha1Hash,
+};
 
-#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Node(String, i64);
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -15,6 +22,152 @@ pub struct File {
     pub length: u64,
     #[serde(default)]
     md5sum: Option<String>,
+    /// File attributes of BEP 47, `p` marks padding files.
+    #[serde(default)]
+    pub attr: Option<String>,
+}
+
+impl File {
+    pub fn new(path: Vec<String>, length: u64) -> Self {
+        Self {
+            path,
+            length,
+            md5sum: None,
+            attr: None,
+        }
+    }
+
+    /// Padding file of `length` bytes, named after its length like other clients do.
+    pub fn padding(length: u64) -> Self {
+        Self {
+            attr: Some("p".into()),
+            ..Self::new(vec![".pad".into(), length.to_string()], length)
+        }
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// Directory tree of a v2 torrent. A file is a dictionary holding its [TreeFile] under an
+/// empty key, everything else is a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileTree {
+    File(TreeFile),
+    Directory(BTreeMap<String, FileTree>),
+}
+
+/// A file in the [FileTree] of a v2 torrent.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TreeFile {
+    pub length: u64,
+    /// Root of the merkle tree over the file's 16 KiB blocks, empty files have none.
+    #[serde(default)]
+    #[serde(rename = "pieces root")]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+impl TreeFile {
+    pub fn pieces_root(&self) -> Option<[u8; 32]> {
+        self.pieces_root.as_ref()?.as_slice().try_into().ok()
+    }
+}
+
+impl FileTree {
+    /// Files below this node with their path, in the order their data is laid out.
+    pub fn files(&self) -> Vec<(Vec<String>, &TreeFile)> {
+        let mut files = Vec::new();
+        self.collect(&mut Vec::new(), &mut files);
+        files
+    }
+
+    fn collect<'a>(&'a self, path: &mut Vec<String>, files: &mut Vec<(Vec<String>, &'a TreeFile)>) {
+        match self {
+            FileTree::File(file) => files.push((path.clone(), file)),
+            FileTree::Directory(entries) => {
+                for (name, entry) in entries {
+                    path.push(name.clone());
+                    entry.collect(path, files);
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+impl serde::Serialize for FileTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            FileTree::File(file) => map.serialize_entry("", file)?,
+            FileTree::Directory(entries) => {
+                for (name, entry) in entries {
+                    map.serialize_entry(name, entry)?;
+                }
+            }
+        }
+        map.end()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FileTree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = FileTree;
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(formatter, "A file tree dictionary")
+            }
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut file = None;
+                let mut entries = BTreeMap::new();
+                while let Some(name) = map.next_key::<String>()? {
+                    match name.is_empty() {
+                        true => file = Some(map.next_value()?),
+                        false => {
+                            entries.insert(name, map.next_value()?);
+                        }
+                    }
+                }
+                match file {
+                    Some(file) if entries.is_empty() => Ok(FileTree::File(file)),
+                    Some(_) => Err(de::Error::custom(
+                        "File tree node is a file and a directory",
+                    )),
+                    None => Ok(FileTree::Directory(entries)),
+                }
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+/// Which kind of hashes a torrent carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    /// SHA-1 piece hashes in `pieces`.
+    V1,
+    /// Per file merkle trees of SHA-256 hashes in `file tree`, see BEP 52.
+    V2,
+    /// Both, the v1 files are padded so pieces never span two files.
+    Hybrid,
+}
+
+/// A file of a v1, v2 or hybrid torrent, see [Info::files].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    /// Path of the file, starting with the torrent name for multi file torrents.
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Where the file starts in the data the pieces are cut from.
+    pub offset: u64,
+    /// Root of the file's merkle tree, v2 and hybrid torrents only. Empty files have none.
+    pub pieces_root: Option<[u8; 32]>,
+    /// Padding files only align the next file to a piece boundary and are never stored.
+    pub padding: bool,
 }
 
 /// Represents Bittorrent Info dictionary
@@ -23,12 +176,16 @@ pub struct File {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Info {
     pub name: String,
+    /// SHA-1 piece hashes, empty for v2 only torrents.
+    #[serde(default)]
     pub pieces: ByteBuf,
     #[serde(rename = "piece length")]
     pub piece_length: u64,
     #[serde(default)]
     pub md5sum: Option<String>,
+    /// Length of a single file torrent, multi file torrents have `files` instead.
     #[serde(default)]
+    #[serde(skip_serializing_if = "is_zero")]
     pub length: u64,
     pub files: Option<Vec<File>>,
     #[serde(default)]
@@ -36,26 +193,156 @@ pub struct Info {
     #[serde(default)]
     #[serde(rename = "root hash")]
     pub root_hash: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<u64>,
+    #[serde(default)]
+    #[serde(rename = "file tree")]
+    pub file_tree: Option<FileTree>,
+    /// Set by private trackers so the same content gets a different info hash on each one.
+    #[serde(default)]
+    pub source: Option<String>,
 }
 
-impl Sha1Hash for Info {
-    fn as_sha1(&self) -> crate::id::ID {
-        let bytes = serde_bencode::to_bytes(&self).unwrap();
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
 
-        let mut hasher = Sha1::new();
+impl Info {
+    pub fn version(&self) -> MetaVersion {
+        let v2 = self.meta_version == Some(2) && self.file_tree.is_some();
+        match (v2, self.pieces.is_empty()) {
+            (true, true) => MetaVersion::V2,
+            (true, false) => MetaVersion::Hybrid,
+            (false, _) => MetaVersion::V1,
+        }
+    }
+
+    /// Number of pieces, `pieces` holds a 20 byte SHA-1 hash for each of them.
+    pub fn num_pieces(&self) -> usize {
+        match self.version() {
+            MetaVersion::V2 => self.total_length().div_ceil(self.piece_length) as usize,
+            _ => self.pieces.len() / 20,
+        }
+    }
 
-        hasher.update(bytes);
+    /// Expected SHA-1 hash of the piece at `index`, v2 only torrents have none.
+    pub fn piece_hash(&self, index: usize) -> Option<ID> {
+        let hash = self.pieces.get(index * 20..index * 20 + 20)?;
+        Some(ID::from(hash.to_vec()))
+    }
+
+    /// Length of the piece at `index`, only the last piece may be shorter than `piece_length`.
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let offset = index as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length().saturating_sub(offset))
+    }
+
+    /// Private torrents only get peers from their trackers, see BEP 27.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Length of the whole torrent, the sum of all file lengths for multi file torrents.
+    /// Files of v2 only torrents start at a piece boundary, the gaps count as well.
+    pub fn total_length(&self) -> u64 {
+        match (self.version(), &self.files) {
+            (MetaVersion::V2, _) => self
+                .files()
+                .last()
+                .map_or(0, |file| file.offset + file.length),
+            (_, Some(files)) => files.iter().map(|file| file.length).sum(),
+            (_, None) => self.length,
+        }
+    }
+
+    /// Files in the order their data is laid out in pieces, the same for every
+    /// [MetaVersion]. v1 and hybrid torrents list `files` including padding files, v2 only
+    /// torrents the files of the `file tree`.
+    pub fn files(&self) -> Vec<TorrentFile> {
+        let tree = self.tree_files();
+        let pieces_root = |path: &[String]| {
+            let (_, file) = tree.iter().find(|(p, _)| p == path)?;
+            file.pieces_root()
+        };
+
+        let mut offset = 0;
+        let mut files = Vec::new();
+        match (self.version(), &self.files) {
+            (MetaVersion::V2, _) => {
+                for (path, file) in &tree {
+                    files.push(TorrentFile {
+                        path: self.full_path(path),
+                        length: file.length,
+                        offset,
+                        pieces_root: file.pieces_root(),
+                        padding: false,
+                    });
+                    offset = (offset + file.length).div_ceil(self.piece_length) * self.piece_length;
+                }
+            }
+            (_, Some(entries)) => {
+                for entry in entries {
+                    files.push(TorrentFile {
+                        path: self.full_path(&entry.path),
+                        length: entry.length,
+                        offset,
+                        pieces_root: pieces_root(&entry.path),
+                        padding: entry.is_padding(),
+                    });
+                    offset += entry.length;
+                }
+            }
+            (_, None) => files.push(TorrentFile {
+                path: vec![self.name.clone()],
+                length: self.length,
+                offset,
+                pieces_root: pieces_root(std::slice::from_ref(&self.name)),
+                padding: false,
+            }),
+        }
+        files
+    }
+
+    /// Files of the `file tree`, a tree holding a single file is a single file torrent.
+    fn tree_files(&self) -> Vec<(Vec<String>, &TreeFile)> {
+        self.file_tree
+            .as_ref()
+            .map(FileTree::files)
+            .unwrap_or_default()
+    }
+
+    fn full_path(&self, path: &[String]) -> Vec<String> {
+        match self.is_single_file() {
+            true => path.to_vec(),
+            false => [std::slice::from_ref(&self.name), path].concat(),
+        }
+    }
 
-        let hashed = hasher.finalize().to_owned().to_vec();
-        ID::from(hashed)
+    fn is_single_file(&self) -> bool {
+        match self.version() {
+            MetaVersion::V2 => matches!(
+                &self.file_tree,
+                Some(FileTree::Directory(entries))
+                    if entries.len() == 1 && matches!(entries.values().next(), Some(FileTree::File(_)))
+            ),
+            _ => self.files.is_none(),
+        }
     }
 }
 
-/// V1 Bittorrent metainfo
+/// Bittorrent metainfo, v1, v2 or hybrid
 #[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Metainfo {
+    /// Written from `info_bytes` by [Metainfo::to_bytes].
+    #[serde(skip_serializing)]
     pub info: Info,
+    /// The info dictionary exactly as it was read, [Info] drops keys it does not know so
+    /// encoding it again could change the info hash.
+    #[serde(skip)]
+    info_bytes: Vec<u8>,
     #[serde(default)]
     pub announce: Option<String>,
     #[serde(default)]
@@ -64,6 +351,10 @@ pub struct Metainfo {
     pub encoding: Option<String>,
     #[serde(default)]
     pub httpseeds: Option<Vec<String>>,
+    /// Web seeds of BEP 19, some torrents have a single url instead of a list.
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[serde(rename = "url-list")]
+    pub url_list: Option<Vec<String>>,
     #[serde(default)]
     #[serde(rename = "announce-list")]
     pub announce_list: Option<Vec<Vec<String>>>,
@@ -75,11 +366,115 @@ pub struct Metainfo {
     #[serde(default)]
     #[serde(rename = "created by")]
     pub created_by: Option<String>,
+    /// Hashes of one merkle tree layer for each v2 file larger than a piece, keyed by its
+    /// pieces root. The layer is the one whose hashes cover a piece each.
+    #[serde(default)]
+    #[serde(rename = "piece layers")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let urls = match <OneOrMany as serde::Deserialize>::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    };
+    Ok(Some(
+        urls.into_iter().filter(|url| !url.is_empty()).collect(),
+    ))
 }
 
 impl Metainfo {
+    /// Parses a torrent file, inconsistent ones fail with a
+    /// [MetainfoError](crate::validate::MetainfoError).
     pub fn new(data: &[u8]) -> Result<Self> {
-        Ok(serde_bencode::de::from_bytes::<Metainfo>(data)?)
+        let mut metainfo = serde_bencode::de::from_bytes::<Metainfo>(data)?;
+        let Some(span) = bencode::dict_value(data, b"info")? else {
+            anyhow::bail!("Metainfo has no info dictionary");
+        };
+        metainfo.info_bytes = data[span].to_vec();
+        metainfo.validate()?;
+        Ok(metainfo)
+    }
+
+    /// Metainfo of a bare info dictionary, like the one fetched for a magnet link. Every
+    /// other field is left empty.
+    pub fn from_info_bytes(info_bytes: Vec<u8>) -> Result<Self> {
+        if bencode::value_length(&info_bytes)? != info_bytes.len() {
+            anyhow::bail!("Unexpected data after the info dictionary");
+        }
+        let metainfo = Self {
+            info: serde_bencode::from_bytes(&info_bytes)?,
+            info_bytes,
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            url_list: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            piece_layers: None,
+        };
+        metainfo.validate()?;
+        Ok(metainfo)
+    }
+
+    /// The bencoded info dictionary the info hash is computed from.
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
+    }
+
+    /// Encodes the torrent file, the info dictionary is written as [Metainfo::info_bytes].
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let rest = serde_bencode::to_bytes(self)?;
+        // Keys are sorted, `info` goes right after the last key before it.
+        let at = bencode::dict_entries(&rest)?
+            .into_iter()
+            .take_while(|(key, _)| *key < &b"info"[..])
+            .last()
+            .map_or(1, |(_, value)| value.end);
+
+        let mut bytes = rest[..at].to_vec();
+        bytes.extend_from_slice(b"4:info");
+        bytes.extend_from_slice(&self.info_bytes);
+        bytes.extend_from_slice(&rest[at..]);
+        Ok(bytes)
+    }
+
+    /// SHA-256 of the info dictionary for v2 and hybrid torrents.
+    pub fn info_hash_v2(&self) -> Option<InfoHashV2> {
+        if self.info.version() == MetaVersion::V1 {
+            return None;
+        }
+        Some(InfoHashV2::from(<[u8; 32]>::from(Sha256::digest(
+            &self.info_bytes,
+        ))))
+    }
+
+    /// Concatenated 32 byte piece hashes of the file with the pieces root, files no larger
+    /// than a piece have none as their root is the hash of their only piece.
+    pub fn piece_layer(&self, pieces_root: &[u8; 32]) -> Option<&[u8]> {
+        let layers = self.piece_layers.as_ref()?;
+        layers
+            .get(serde_bytes::Bytes::new(pieces_root))
+            .map(|layer| layer.as_slice())
+    }
+}
+
+impl Sha1Hash for Metainfo {
+    /// The info hash, SHA-1 of the info dictionary.
+    fn as_sha1(&self) -> ID {
+        ID::from(Sha1::digest(&self.info_bytes).to_vec())
     }
 }
 
@@ -89,11 +484,12 @@ impl Display for Metainfo {
         f.write_fmt(format_args!("announce:\t\t{:?}\n", self.announce))?;
         f.write_fmt(format_args!("nodes:\t\t{:?}\n", self.nodes))?;
         if let Some(al) = &self.announce_list {
-            for a in al {
-                f.write_fmt(format_args!("announce list:\t{}\n", a[0]))?;
+            for tier in al {
+                f.write_fmt(format_args!("announce list:\t{:?}\n", tier))?;
             }
         }
         f.write_fmt(format_args!("httpsseeds:\t{:?}\n", self.httpseeds))?;
+        f.write_fmt(format_args!("url list:\t{:?}\n", self.url_list))?;
         f.write_fmt(format_args!("creation date:\t{:?}\n", self.creation_date))?;
         f.write_fmt(format_args!("comment:\t{:?}\n", self.comment))?;
         f.write_fmt(format_args!("created by:\t{:?}\n", self.created_by))?;
@@ -103,8 +499,10 @@ impl Display for Metainfo {
             self.info.piece_length
         ))?;
         f.write_fmt(format_args!("private:\t{:?}\n", self.info.private))?;
+        f.write_fmt(format_args!("source:\t\t{:?}\n", self.info.source))?;
         f.write_fmt(format_args!("root hash:\t{:?}\n", self.info.root_hash))?;
         f.write_fmt(format_args!("md5sum:\t\t{:?}\n", self.info.md5sum))?;
+        f.write_fmt(format_args!("meta version:\t{:?}\n", self.info.version()))?;
         if let Some(files) = &self.info.files {
             for file in files {
                 f.write_fmt(format_args!("file path:\t{:?}\n", file.path))?;
@@ -132,12 +530,247 @@ mod tests {
     }
 
     #[test]
-    fn test_encode() -> Result<()> {
+    fn test_info_hash() -> Result<()> {
+        let expected = [
+            (
+                "archlinux-2022.12.01-x86_64.iso.torrent",
+                "47ddf56667e33d5092e4301900832a11ea80335c",
+            ),
+            (
+                "manjaro-kde-21.3.7-220816-linux515.iso.torrent",
+                "8ebb417af0701b2e924617757a81bdf1b68e15a1",
+            ),
+            ("multi.torrent", "5fb4a91df7d107dc998e367d8af5105c38b61f51"),
+            (
+                "ubuntu-22.10-desktop-amd64.iso.torrent",
+                "99c82bb73505a3c0b453f9fa0e881d6e5a32a0c1",
+            ),
+        ];
+        for entry in fs::read_dir("../resources")? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let Some((_, hash)) = expected.iter().find(|(file, _)| *file == name) else {
+                panic!("No known info hash for {name}");
+            };
+            let metainfo = Metainfo::new(&fs::read(&path)?)?;
+            assert_eq!(metainfo.as_sha1(), hash.parse()?, "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_bytes() -> Result<()> {
         for entry in fs::read_dir("../resources")? {
             let data = fs::read(entry?.path())?;
-            let metadata = Metainfo::new(&data)?;
-            metadata.info.as_sha1();
+            let metainfo = Metainfo::new(&data)?;
+            let encoded = Metainfo::new(&metainfo.to_bytes()?)?;
+            assert_eq!(encoded.info_bytes(), metainfo.info_bytes());
+            assert_eq!(encoded.announce, metainfo.announce);
+            assert_eq!(encoded.announce_list, metainfo.announce_list);
+            assert_eq!(encoded.url_list, metainfo.url_list);
+            assert_eq!(encoded.creation_date, metainfo.creation_date);
+            assert_eq!(encoded.comment, metainfo.comment);
         }
         Ok(())
     }
+
+    #[test]
+    fn test_info_hash_unknown_keys() -> Result<()> {
+        let info = b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+            6:source3:xyz12:x_cross_seed3:abce";
+        let mut data = b"d8:announce3:url4:info".to_vec();
+        data.extend_from_slice(info);
+        // A single web seed instead of a list.
+        data.extend_from_slice(b"8:url-list3:webe");
+
+        let metainfo = Metainfo::new(&data)?;
+        assert_eq!(metainfo.info_bytes(), info);
+        assert_eq!(metainfo.info.source.as_deref(), Some("xyz"));
+        assert_eq!(metainfo.url_list, Some(vec!["web".to_string()]));
+        assert_eq!(metainfo.as_sha1(), ID::from(Sha1::digest(info).to_vec()));
+
+        let bare = Metainfo::from_info_bytes(info.to_vec())?;
+        assert_eq!(bare.as_sha1(), metainfo.as_sha1());
+        assert!(Metainfo::from_info_bytes([&info[..], b"e"].concat()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pieces() -> Result<()> {
+        for entry in fs::read_dir("../resources")? {
+            let data = fs::read(entry?.path())?;
+            let info = Metainfo::new(&data)?.info;
+
+            let num_pieces = info.num_pieces();
+            let last = num_pieces - 1;
+            assert_eq!(info.pieces.len(), num_pieces * 20);
+            assert_eq!(
+                last as u64 * info.piece_length + info.piece_size(last),
+                info.total_length()
+            );
+            assert_eq!(info.piece_size(0), info.piece_length);
+            assert_eq!(*info.piece_hash(last).unwrap(), info.pieces[last * 20..]);
+            assert_eq!(info.piece_hash(num_pieces), None);
+
+            let files = info.files();
+            let last = files.last().unwrap();
+            assert_eq!(last.offset + last.length, info.total_length());
+            assert_eq!(files[0].path[0], info.name);
+            assert_eq!(info.version(), MetaVersion::V1);
+        }
+        Ok(())
+    }
+
+    fn string(bytes: &[u8]) -> Vec<u8> {
+        [format!("{}:", bytes.len()).as_bytes(), bytes].concat()
+    }
+
+    fn int(n: u64) -> Vec<u8> {
+        format!("i{n}e").into_bytes()
+    }
+
+    /// Bencoded dictionary, the keys are sorted like bencode requires.
+    fn dict(mut entries: Vec<(&[u8], Vec<u8>)>) -> Vec<u8> {
+        entries.sort_by_key(|(key, _)| *key);
+        let mut out = b"d".to_vec();
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend(value);
+        }
+        out.push(b'e');
+        out
+    }
+
+    fn tree_file(length: u64, root: Option<[u8; 32]>) -> Vec<u8> {
+        let mut attributes = vec![(&b"length"[..], int(length))];
+        if let Some(root) = &root {
+            attributes.push((b"pieces root", string(root)));
+        }
+        dict(vec![(b"", dict(attributes))])
+    }
+
+    /// Torrent named `dir` with the files `a` of 20000 bytes and `sub/b` of 100 bytes in
+    /// 16 KiB pieces. Hybrid torrents pad `a` to two pieces.
+    fn v2_torrent(hybrid: bool) -> Vec<u8> {
+        let tree = dict(vec![
+            (b"a", tree_file(20000, Some([1; 32]))),
+            (b"sub", dict(vec![(b"b", tree_file(100, Some([2; 32])))])),
+        ]);
+        let mut info = vec![
+            (&b"file tree"[..], tree),
+            (b"meta version", int(2)),
+            (b"name", string(b"dir")),
+            (b"piece length", int(16384)),
+        ];
+        if hybrid {
+            let file = |path: Vec<&[u8]>, length, attr: Option<&[u8]>| {
+                let path = [
+                    b"l".to_vec(),
+                    path.into_iter().flat_map(string).collect(),
+                    b"e".to_vec(),
+                ];
+                let mut entries = vec![(&b"length"[..], int(length)), (b"path", path.concat())];
+                if let Some(attr) = attr {
+                    entries.push((b"attr", string(attr)));
+                }
+                dict(entries)
+            };
+            let files = [
+                file(vec![b"a"], 20000, None),
+                file(vec![b".pad", b"12768"], 12768, Some(b"p")),
+                file(vec![b"sub", b"b"], 100, None),
+            ];
+            info.push((
+                b"files",
+                [b"l".to_vec(), files.concat(), b"e".to_vec()].concat(),
+            ));
+            info.push((b"pieces", string(&[7; 60])));
+        }
+        let layers = dict(vec![(&[1; 32][..], string(&[3; 64]))]);
+        dict(vec![(b"info", dict(info)), (b"piece layers", layers)])
+    }
+
+    #[test]
+    fn test_v2() -> Result<()> {
+        let metainfo = Metainfo::new(&v2_torrent(false))?;
+        let info = &metainfo.info;
+        assert_eq!(info.version(), MetaVersion::V2);
+
+        let files = info.files();
+        let paths: Vec<_> = files.iter().map(|file| file.path.join("/")).collect();
+        assert_eq!(paths, ["dir/a", "dir/sub/b"]);
+        assert_eq!(files[1].offset, 32768);
+        assert_eq!(files[1].pieces_root, Some([2; 32]));
+        assert_eq!(info.total_length(), 32868);
+        assert_eq!(info.num_pieces(), 3);
+        assert_eq!(info.piece_hash(0), None);
+
+        let hash = metainfo.info_hash_v2().unwrap();
+        assert_eq!(
+            hash,
+            InfoHashV2::from(<[u8; 32]>::from(Sha256::digest(metainfo.info_bytes())))
+        );
+        assert_eq!(*hash.truncated(), hash[..20]);
+        assert_eq!(hash.to_string().parse::<InfoHashV2>()?, hash);
+
+        assert_eq!(metainfo.piece_layer(&[1; 32]), Some(&[3; 64][..]));
+        assert_eq!(metainfo.piece_layer(&[2; 32]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hybrid() -> Result<()> {
+        let metainfo = Metainfo::new(&v2_torrent(true))?;
+        let info = &metainfo.info;
+        assert_eq!(info.version(), MetaVersion::Hybrid);
+        assert!(metainfo.info_hash_v2().is_some());
+        assert_eq!(info.num_pieces(), 3);
+        assert_eq!(info.total_length(), 32868);
+
+        let files = info.files();
+        assert_eq!(files.len(), 3);
+        assert!(files[1].padding);
+        assert_eq!(files[1].pieces_root, None);
+        assert_eq!(files[2].path, ["dir", "sub", "b"]);
+        assert_eq!(files[2].offset, 32768);
+        assert_eq!(files[2].pieces_root, Some([2; 32]));
+        assert_eq!(files[0].pieces_root, Some([1; 32]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_v2_single_file() -> Result<()> {
+        let info = dict(vec![
+            (
+                b"file tree",
+                dict(vec![(b"a.iso", tree_file(5, Some([1; 32])))]),
+            ),
+            (b"meta version", int(2)),
+            (b"name", string(b"a.iso")),
+            (b"piece length", int(16384)),
+        ]);
+        let metainfo = Metainfo::from_info_bytes(info)?;
+        let files = metainfo.info.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, ["a.iso"]);
+        assert_eq!(metainfo.info.total_length(), 5);
+
+        // A node can not be a file and a directory at once.
+        let mut node = tree_file(1, None);
+        node.pop();
+        node.extend(string(b"b"));
+        node.extend(tree_file(2, None));
+        node.push(b'e');
+        let invalid = dict(vec![
+            (b"file tree", dict(vec![(b"a", node)])),
+            (b"name", string(b"a")),
+            (b"piece length", int(16384)),
+        ]);
+        let error = Metainfo::from_info_bytes(invalid).unwrap_err();
+        assert!(
+            error.to_string().contains("file and a directory"),
+            "{error}"
+        );
+        Ok(())
+    }
 }
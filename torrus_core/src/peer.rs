@@ -1,37 +1,85 @@
-use crate::prelude::{Sha1Hash, ID};
-use std::net::IpAddr;
+use crate::prelude::ID;
+use std::net::SocketAddr;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PeerInfo {
+    /// Peer id, all zeros until the handshake told us the real one.
     pub id: ID,
-    pub addr: IpAddr,
+    pub addr: SocketAddr,
+}
+
+impl PeerInfo {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            id: ID::default(),
+            addr,
+        }
+    }
 }
 
 pub trait PeerSource {
     fn get_peers(&mut self) -> impl Iterator<Item = PeerInfo>;
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ChokeStatus {
     NotChocked,
     Chocked,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum IntrestStatus {
     NotInterested,
     Interested,
 }
 
+/// Choke and interest state of both ends of a peer connection.
+///
+/// Both sides start out choking and not interested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PeerState {
-    pub choke: ChokeStatus,
-    pub intrest_status: IntrestStatus,
+    /// Whether we choke the remote peer.
+    pub am_choking: ChokeStatus,
+    /// Whether we are interested in pieces of the remote peer.
+    pub am_interested: IntrestStatus,
+    /// Whether the remote peer chokes us.
+    pub peer_choking: ChokeStatus,
+    /// Whether the remote peer is interested in our pieces.
+    pub peer_interested: IntrestStatus,
+}
+
+impl PeerState {
+    /// Blocks can be requested from the remote peer.
+    pub fn can_download(&self) -> bool {
+        self.am_interested == IntrestStatus::Interested
+            && self.peer_choking == ChokeStatus::NotChocked
+    }
+
+    /// The remote peer may request blocks from us.
+    pub fn can_upload(&self) -> bool {
+        self.peer_interested == IntrestStatus::Interested
+            && self.am_choking == ChokeStatus::NotChocked
+    }
+}
+
+/// What the remote peer told about itself in its extended handshake, see BEP 10.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Client name and version, e.g. `Transmission 4.0.5`.
+    pub name: Option<String>,
+    /// Number of outstanding requests the peer accepts.
+    pub request_queue: Option<usize>,
+    /// Port the peer accepts connections on, which may differ from the port it connected from.
+    pub listen_port: Option<u16>,
 }
 
 impl Default for PeerState {
     fn default() -> Self {
         Self {
-            choke: ChokeStatus::Chocked,
-            intrest_status: IntrestStatus::NotInterested,
+            am_choking: ChokeStatus::Chocked,
+            am_interested: IntrestStatus::NotInterested,
+            peer_choking: ChokeStatus::Chocked,
+            peer_interested: IntrestStatus::NotInterested,
         }
     }
 }
@@ -0,0 +1,507 @@
+//! Consistency checks for metainfo from untrusted sources and names safe to store files under.
+
+use crate::{
+    bencode,
+    metainfo::{FileTree, MetaVersion, Metainfo},
+};
+use std::{collections::HashSet, fmt::Display};
+
+/// Longest file name most filesystems accept, in bytes.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Largest piece length accepted, pieces are held in memory while they are downloaded.
+const MAX_PIECE_LENGTH: u64 = 1 << 28;
+
+/// Names Windows reserves for devices, with any extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why a torrent is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetainfoError {
+    /// `piece length` is zero or larger than 256 MiB, v2 torrents need a power of two of at
+    /// least 16 KiB.
+    InvalidPieceLength(u64),
+    /// `pieces` is not made of 20 byte hashes, holds its length.
+    InvalidPieces(usize),
+    /// The number of piece hashes does not match the length of the torrent.
+    PieceCount { expected: u64, actual: usize },
+    /// Both the `length` of a single file and the `files` of a multi file torrent.
+    LengthAndFiles,
+    /// The torrent has no files or only empty ones.
+    NoData,
+    /// The length of the torrent overflows.
+    TooLarge,
+    /// A path with an empty, `.` or `..` component, or one holding a path separator.
+    InvalidPath(Vec<String>),
+    /// Two files with the same path, or a file which is also a directory. Paths are compared
+    /// as stored on disk, after [sanitize_name] and ignoring case.
+    DuplicatePath(Vec<String>),
+    /// A non empty v2 file without a 32 byte pieces root.
+    InvalidPiecesRoot(Vec<String>),
+    /// The piece layer of a v2 file does not hold a hash for each of its pieces.
+    InvalidPieceLayer(Vec<String>),
+    /// A v1 file of a hybrid torrent missing from its file tree, or not piece aligned.
+    HybridMismatch(Vec<String>),
+}
+
+impl Display for MetainfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetainfoError::InvalidPieceLength(length) => write!(f, "Invalid piece length {length}"),
+            MetainfoError::InvalidPieces(length) => {
+                write!(f, "Pieces of {length} bytes are not whole SHA-1 hashes")
+            }
+            MetainfoError::PieceCount { expected, actual } => {
+                write!(f, "Expected {expected} piece hashes, got {actual}")
+            }
+            MetainfoError::LengthAndFiles => write!(f, "Torrent has both a length and files"),
+            MetainfoError::NoData => write!(f, "Torrent has no data"),
+            MetainfoError::TooLarge => write!(f, "Torrent length overflows"),
+            MetainfoError::InvalidPath(path) => write!(f, "Invalid file path {path:?}"),
+            MetainfoError::DuplicatePath(path) => write!(f, "Duplicate file path {path:?}"),
+            MetainfoError::InvalidPiecesRoot(path) => {
+                write!(f, "Invalid pieces root of {path:?}")
+            }
+            MetainfoError::InvalidPieceLayer(path) => write!(f, "Invalid piece layer of {path:?}"),
+            MetainfoError::HybridMismatch(path) => {
+                write!(f, "v1 and v2 files of {path:?} do not match")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetainfoError {}
+
+/// Oddities which do not keep a torrent from being downloaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    PieceLengthNotPowerOfTwo(u64),
+    /// `private` is neither 0 nor 1, the torrent is treated as public.
+    UnknownPrivateFlag(u8),
+    /// A private torrent without trackers can not find peers.
+    PrivateWithoutTrackers,
+    /// A v2 file larger than a piece without its piece layer, its hashes have to be
+    /// requested from peers.
+    MissingPieceLayer(Vec<String>),
+    /// A path which is stored under a different name, see [sanitize_name].
+    SanitizedPath(Vec<String>),
+    /// The info dictionary is not canonical bencode, encoding it again changes the info hash.
+    NonCanonical,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::PieceLengthNotPowerOfTwo(length) => {
+                write!(f, "Piece length {length} is not a power of two")
+            }
+            Warning::UnknownPrivateFlag(flag) => write!(f, "Unknown private flag {flag}"),
+            Warning::PrivateWithoutTrackers => write!(f, "Private torrent without trackers"),
+            Warning::MissingPieceLayer(path) => write!(f, "Missing piece layer of {path:?}"),
+            Warning::SanitizedPath(path) => write!(f, "File path {path:?} is renamed on disk"),
+            Warning::NonCanonical => write!(f, "Info dictionary is not canonical bencode"),
+        }
+    }
+}
+
+impl Metainfo {
+    /// Checks the torrent is consistent and its files stay inside its directory. Returns what
+    /// is odd but harmless.
+    pub fn validate(&self) -> Result<Vec<Warning>, MetainfoError> {
+        let info = &self.info;
+        let version = info.version();
+        let mut warnings = Vec::new();
+
+        let piece_length = info.piece_length;
+        if piece_length == 0
+            || piece_length > MAX_PIECE_LENGTH
+            || version != MetaVersion::V1
+                && (piece_length < 1 << 14 || !piece_length.is_power_of_two())
+        {
+            return Err(MetainfoError::InvalidPieceLength(piece_length));
+        }
+        if !piece_length.is_power_of_two() {
+            warnings.push(Warning::PieceLengthNotPowerOfTwo(piece_length));
+        }
+        if info.length > 0 && info.files.is_some() {
+            return Err(MetainfoError::LengthAndFiles);
+        }
+        if let Some(entry) = info
+            .files
+            .iter()
+            .flatten()
+            .find(|file| file.path.is_empty())
+        {
+            return Err(MetainfoError::InvalidPath(entry.path.clone()));
+        }
+
+        // Offsets of the files are computed without checks, v2 files start at a piece boundary.
+        let lengths: Vec<u64> = match (version, &info.files) {
+            (MetaVersion::V2, _) => tree_files(&info.file_tree)
+                .into_iter()
+                .map(|(_, length)| length.div_ceil(piece_length).saturating_mul(piece_length))
+                .collect(),
+            (_, Some(files)) => files.iter().map(|file| file.length).collect(),
+            (_, None) => vec![info.length],
+        };
+        let total_length = lengths
+            .into_iter()
+            .try_fold(0u64, u64::checked_add)
+            .ok_or(MetainfoError::TooLarge)?;
+        if total_length == 0 {
+            return Err(MetainfoError::NoData);
+        }
+
+        if version != MetaVersion::V2 {
+            if !info.pieces.len().is_multiple_of(20) {
+                return Err(MetainfoError::InvalidPieces(info.pieces.len()));
+            }
+            let expected = total_length.div_ceil(piece_length);
+            if expected != info.num_pieces() as u64 {
+                return Err(MetainfoError::PieceCount {
+                    expected,
+                    actual: info.num_pieces(),
+                });
+            }
+        }
+
+        let files = info.files();
+        // Paths as they end up on disk, on case insensitive filesystems as well.
+        let mut paths = HashSet::new();
+        let mut sorted = Vec::new();
+        for file in &files {
+            if !file.path.iter().all(|name| is_valid_name(name)) {
+                return Err(MetainfoError::InvalidPath(file.path.clone()));
+            }
+            if file.padding {
+                continue;
+            }
+            let stored: Vec<String> = file
+                .path
+                .iter()
+                .map(|name| sanitize_name(name).to_lowercase())
+                .collect();
+            if !paths.insert(stored.clone()) {
+                return Err(MetainfoError::DuplicatePath(file.path.clone()));
+            }
+            sorted.push((stored, &file.path));
+            if file.path.iter().any(|name| sanitize_name(name) != *name) {
+                warnings.push(Warning::SanitizedPath(file.path.clone()));
+            }
+        }
+        // A file which is also a directory sorts right before the first file inside it.
+        sorted.sort();
+        if let Some(pair) = sorted
+            .windows(2)
+            .find(|pair| pair[1].0.starts_with(&pair[0].0))
+        {
+            return Err(MetainfoError::DuplicatePath(pair[0].1.clone()));
+        }
+
+        if version == MetaVersion::Hybrid {
+            let v1: Vec<_> = files
+                .iter()
+                .filter(|file| !file.padding)
+                .map(|file| (file.path.clone(), file.length))
+                .collect();
+            let v2: Vec<_> = tree_files(&info.file_tree)
+                .into_iter()
+                .map(|(path, length)| (self.full_path(path), length))
+                .collect();
+            if let Some((path, _)) = v1.iter().zip(&v2).find(|(v1, v2)| v1 != v2) {
+                return Err(MetainfoError::HybridMismatch(path.0.clone()));
+            }
+            if v1.len() != v2.len() {
+                return Err(MetainfoError::HybridMismatch(vec![info.name.clone()]));
+            }
+            let unaligned = files.iter().find(|file| {
+                !file.padding && file.length > 0 && !file.offset.is_multiple_of(piece_length)
+            });
+            if let Some(file) = unaligned {
+                return Err(MetainfoError::HybridMismatch(file.path.clone()));
+            }
+        }
+
+        if version != MetaVersion::V1 {
+            for file in files.iter().filter(|file| !file.padding && file.length > 0) {
+                let Some(root) = file.pieces_root else {
+                    return Err(MetainfoError::InvalidPiecesRoot(file.path.clone()));
+                };
+                if file.length <= piece_length {
+                    continue;
+                }
+                let num_pieces = file.length.div_ceil(piece_length);
+                match self.piece_layer(&root) {
+                    None => warnings.push(Warning::MissingPieceLayer(file.path.clone())),
+                    Some(layer) if layer.len() as u64 != num_pieces * 32 => {
+                        return Err(MetainfoError::InvalidPieceLayer(file.path.clone()))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        match info.private {
+            Some(flag) if flag > 1 => warnings.push(Warning::UnknownPrivateFlag(flag)),
+            _ => {}
+        }
+        let no_trackers = self.announce.is_none()
+            && self
+                .announce_list
+                .iter()
+                .flatten()
+                .all(|tier| tier.is_empty());
+        if info.is_private() && no_trackers {
+            warnings.push(Warning::PrivateWithoutTrackers);
+        }
+        if bencode::decode(self.info_bytes()).is_err() {
+            warnings.push(Warning::NonCanonical);
+        }
+        Ok(warnings)
+    }
+
+    /// Path of a file tree entry starting with the torrent name, like [Metainfo::files].
+    fn full_path(&self, path: Vec<String>) -> Vec<String> {
+        match self.info.files {
+            None => path,
+            Some(_) => [vec![self.info.name.clone()], path].concat(),
+        }
+    }
+}
+
+/// Paths and lengths of the files in a v2 file tree.
+fn tree_files(tree: &Option<FileTree>) -> Vec<(Vec<String>, u64)> {
+    tree.iter()
+        .flat_map(FileTree::files)
+        .map(|(path, file)| (path, file.length))
+        .collect()
+}
+
+/// Whether a path component names something inside its directory on every platform.
+fn is_valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\\', '\0'])
+}
+
+/// A file name which can be created on common filesystems. Characters Windows does not allow
+/// become `_`, as do trailing dots and spaces, reserved device names get a `_` prefix and
+/// long names are cut to 255 bytes.
+pub fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let trimmed = sanitized.trim_end_matches(['.', ' ']).len();
+    if trimmed < sanitized.len() {
+        sanitized.truncate(trimmed);
+        sanitized.push('_');
+    }
+    let stem = sanitized.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        sanitized.insert(0, '_');
+    }
+
+    let mut end = sanitized.len().min(MAX_NAME_LENGTH);
+    while !sanitized.is_char_boundary(end) {
+        end -= 1;
+    }
+    sanitized.truncate(end);
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::fs;
+
+    /// Single file torrent with the given info entries next to the name.
+    fn torrent(entries: &str) -> Vec<u8> {
+        format!("d4:infod{entries}ee").into_bytes()
+    }
+
+    fn error(data: &[u8]) -> MetainfoError {
+        Metainfo::new(data)
+            .unwrap_err()
+            .downcast::<MetainfoError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_validate_resources() -> Result<()> {
+        for entry in fs::read_dir("../resources")? {
+            let metainfo = Metainfo::new(&fs::read(entry?.path())?)?;
+            assert_eq!(metainfo.validate()?, []);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_errors() {
+        let pieces = |n: usize| format!("6:pieces{}:{}", n, "x".repeat(n));
+        let cases = [
+            (
+                torrent(&format!(
+                    "6:lengthi10e4:name1:a12:piece lengthi0e{}",
+                    pieces(20)
+                )),
+                MetainfoError::InvalidPieceLength(0),
+            ),
+            (
+                torrent(&format!(
+                    "6:lengthi10e4:name1:a12:piece lengthi{}e{}",
+                    1u64 << 40,
+                    pieces(20)
+                )),
+                MetainfoError::InvalidPieceLength(1 << 40),
+            ),
+            (
+                torrent(&format!(
+                    "6:lengthi10e4:name1:a12:piece lengthi16e{}",
+                    pieces(19)
+                )),
+                MetainfoError::InvalidPieces(19),
+            ),
+            (
+                torrent(&format!(
+                    "6:lengthi40e4:name1:a12:piece lengthi16e{}",
+                    pieces(20)
+                )),
+                MetainfoError::PieceCount {
+                    expected: 3,
+                    actual: 1,
+                },
+            ),
+            (
+                torrent(&format!(
+                    "5:filesld6:lengthi1e4:pathl1:beee6:lengthi10e4:name1:a\
+                        12:piece lengthi16e{}",
+                    pieces(20)
+                )),
+                MetainfoError::LengthAndFiles,
+            ),
+            (
+                torrent("6:lengthi0e4:name1:a12:piece lengthi16e6:pieces0:"),
+                MetainfoError::NoData,
+            ),
+            (
+                torrent(&format!(
+                    "6:lengthi10e4:name2:..12:piece lengthi16e{}",
+                    pieces(20)
+                )),
+                MetainfoError::InvalidPath(vec!["..".into()]),
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(error(&data), expected, "{}", String::from_utf8_lossy(&data));
+        }
+    }
+
+    #[test]
+    fn test_validate_paths() {
+        let files = |paths: &[&str]| {
+            let files: String = paths
+                .iter()
+                .map(|path| {
+                    let path: String = path
+                        .split('/')
+                        .map(|name| format!("{}:{name}", name.len()))
+                        .collect();
+                    format!("d6:lengthi1e4:pathl{path}ee")
+                })
+                .collect();
+            let pieces = "x".repeat(20);
+            torrent(&format!(
+                "5:filesl{files}e4:name3:dir12:piece lengthi16e6:pieces20:{pieces}"
+            ))
+        };
+
+        for (paths, invalid) in [
+            (&["a", "../b"][..], vec!["dir", "..", "b"]),
+            (&["/etc/passwd"], vec!["dir", "", "etc", "passwd"]),
+            (&["a\\..\\b"], vec!["dir", "a\\..\\b"]),
+            (&["a/./b"], vec!["dir", "a", ".", "b"]),
+        ] {
+            let path = invalid.into_iter().map(String::from).collect();
+            assert_eq!(error(&files(paths)), MetainfoError::InvalidPath(path));
+        }
+        assert_eq!(
+            error(&files(&["a", "a"])),
+            MetainfoError::DuplicatePath(vec!["dir".into(), "a".into()])
+        );
+        assert_eq!(
+            error(&files(&["a/b", "a"])),
+            MetainfoError::DuplicatePath(vec!["dir".into(), "a".into()])
+        );
+        // Names which are the same once sanitized or on case insensitive filesystems.
+        for (paths, duplicate) in [
+            (&["a:b", "a_b"][..], vec!["dir", "a_b"]),
+            (&["x.", "x_"], vec!["dir", "x_"]),
+            (&["con", "_con"], vec!["dir", "_con"]),
+            (&["README", "readme"], vec!["dir", "readme"]),
+            (&["a/b", "A"], vec!["dir", "A"]),
+        ] {
+            let path = duplicate.into_iter().map(String::from).collect();
+            assert_eq!(error(&files(paths)), MetainfoError::DuplicatePath(path));
+        }
+
+        let metainfo = Metainfo::new(&files(&["a:b", "c"])).unwrap();
+        assert_eq!(
+            metainfo.validate(),
+            Ok(vec![Warning::SanitizedPath(vec![
+                "dir".into(),
+                "a:b".into()
+            ])])
+        );
+    }
+
+    #[test]
+    fn test_validate_warnings() -> Result<()> {
+        let pieces = "x".repeat(20);
+        let data = torrent(&format!(
+            "6:lengthi10e4:name1:a12:piece lengthi24e6:pieces20:{pieces}7:privatei1e"
+        ));
+        assert_eq!(
+            Metainfo::new(&data)?.validate()?,
+            [
+                Warning::PieceLengthNotPowerOfTwo(24),
+                Warning::PrivateWithoutTrackers
+            ]
+        );
+
+        // Keys out of order.
+        let data = torrent(&format!(
+            "4:name1:a6:lengthi10e12:piece lengthi16e6:pieces20:{pieces}"
+        ));
+        assert_eq!(Metainfo::new(&data)?.validate()?, [Warning::NonCanonical]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        for (name, sanitized) in [
+            ("file.txt", "file.txt"),
+            ("a:b?c", "a_b_c"),
+            ("tab\there", "tab_here"),
+            ("trailing. ", "trailing_"),
+            ("con", "_con"),
+            ("LPT1.txt", "_LPT1.txt"),
+            ("console", "console"),
+            (".pad", ".pad"),
+        ] {
+            assert_eq!(sanitize_name(name), sanitized, "{name}");
+        }
+        let long = "é".repeat(200);
+        assert_eq!(sanitize_name(&long).len(), 254);
+    }
+}
@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rand::Rng;
+use torrus_engine::PiecePicker;
+
+const NUM_PIECES: usize = 400_000;
+const NUM_PEERS: usize = 50;
+
+/// Picker for a swarm of peers which each have about half of the pieces.
+fn swarm() -> (PiecePicker, Vec<Vec<bool>>) {
+    let mut rng = rand::thread_rng();
+    let peers: Vec<Vec<bool>> = (0..NUM_PEERS)
+        .map(|_| (0..NUM_PIECES).map(|_| rng.gen_bool(0.5)).collect())
+        .collect();
+    let mut picker = PiecePicker::new(NUM_PIECES);
+    for pieces in &peers {
+        picker.add_peer(pieces);
+    }
+    (picker, peers)
+}
+
+fn bench_picker(c: &mut Criterion) {
+    let (mut picker, peers) = swarm();
+
+    c.bench_function("add and remove peer", |b| {
+        b.iter(|| {
+            picker.add_peer(&peers[0]);
+            picker.remove_peer(&peers[0]);
+        })
+    });
+
+    let mut index = 0;
+    c.bench_function("have", |b| {
+        b.iter(|| {
+            picker.add_have(index);
+            index = (index + 1) % NUM_PIECES;
+        })
+    });
+
+    let mut peer = 0;
+    c.bench_function("pick and release", |b| {
+        b.iter(|| {
+            let index = picker.pick(&peers[peer]).unwrap();
+            picker.release(index, false);
+            peer = (peer + 1) % NUM_PEERS;
+        })
+    });
+
+    let seeder = vec![true; NUM_PIECES];
+    c.bench_function("download every piece", |b| {
+        b.iter_batched_ref(
+            swarm,
+            |(picker, _)| {
+                while let Some(index) = picker.pick(&seeder) {
+                    picker.set_have(index);
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_picker);
+criterion_main!(benches);
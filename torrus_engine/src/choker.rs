@@ -0,0 +1,127 @@
+use rand::seq::SliceRandom;
+use std::{cmp::Reverse, net::SocketAddr, time::Duration};
+use tokio::time::Instant;
+
+/// How often the choker runs.
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic unchoke moves on every third round, every 30 seconds.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// Peers connected for less than this are three times as likely to be unchoked optimistically,
+/// they have nothing to offer yet and would not get a chance otherwise.
+const NEW_PEER_TIME: Duration = Duration::from_secs(60);
+const NEW_PEER_WEIGHT: u32 = 3;
+
+/// What the [Choker] needs to know about a peer.
+#[derive(Debug, Clone, Copy)]
+pub struct ChokerPeer {
+    pub addr: SocketAddr,
+    /// Bytes per second downloaded from the peer.
+    pub download_rate: u64,
+    /// Bytes per second uploaded to the peer.
+    pub upload_rate: u64,
+    /// Whether the peer is interested in our pieces.
+    pub interested: bool,
+    /// The peer stopped sending the blocks we requested, it only gets the optimistic slot.
+    pub snubbed: bool,
+    pub connected_at: Instant,
+}
+
+/// Decides which peers may download from us, following the tit-for-tat algorithm.
+///
+/// Every round the interested peers we download fastest from get the regular upload slots, or
+/// the peers we upload fastest to while seeding. One more slot goes to a random interested peer
+/// and only moves every few rounds, so new peers get the chance to prove themselves.
+pub struct Choker {
+    upload_slots: usize,
+    optimistic: Option<SocketAddr>,
+    round: u32,
+}
+
+impl Choker {
+    /// `upload_slots` counts the optimistic slot as well.
+    pub fn new(upload_slots: usize) -> Self {
+        Self {
+            upload_slots,
+            optimistic: None,
+            round: 0,
+        }
+    }
+
+    pub fn upload_slots(&self) -> usize {
+        self.upload_slots
+    }
+
+    /// The peer holding the optimistic slot.
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    /// Runs a round, meant to be called every [CHOKE_INTERVAL]. Returns the peers to unchoke,
+    /// every other peer should be choked.
+    pub fn rechoke(
+        &mut self,
+        peers: &[ChokerPeer],
+        seeding: bool,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        if self.upload_slots == 0 {
+            self.optimistic = None;
+            return Vec::new();
+        }
+
+        let mut ranked: Vec<_> = peers
+            .iter()
+            .filter(|peer| peer.interested && !peer.snubbed)
+            .collect();
+        if seeding {
+            ranked.sort_by_key(|peer| Reverse(peer.upload_rate));
+        } else {
+            ranked.sort_by_key(|peer| Reverse(peer.download_rate));
+        }
+        let mut unchoked: Vec<_> = ranked
+            .iter()
+            .take(self.upload_slots - 1)
+            .map(|peer| peer.addr)
+            .collect();
+
+        let rotate = self.round.is_multiple_of(OPTIMISTIC_ROUNDS);
+        self.round = self.round.wrapping_add(1);
+        // The optimistic peer keeps its slot until the next rotation unless it earned a
+        // regular one or lost interest meanwhile.
+        let keep = self.optimistic.filter(|optimistic| {
+            !rotate
+                && !unchoked.contains(optimistic)
+                && peers
+                    .iter()
+                    .any(|peer| peer.addr == *optimistic && peer.interested)
+        });
+        self.optimistic = keep.or_else(|| pick_optimistic(peers, &unchoked, now));
+
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+fn pick_optimistic(
+    peers: &[ChokerPeer],
+    unchoked: &[SocketAddr],
+    now: Instant,
+) -> Option<SocketAddr> {
+    let candidates: Vec<_> = peers
+        .iter()
+        .filter(|peer| peer.interested && !unchoked.contains(&peer.addr))
+        .collect();
+    let weight = |peer: &&ChokerPeer| {
+        if now.saturating_duration_since(peer.connected_at) < NEW_PEER_TIME {
+            NEW_PEER_WEIGHT
+        } else {
+            1
+        }
+    };
+    candidates
+        .choose_weighted(&mut rand::thread_rng(), weight)
+        .ok()
+        .map(|peer| peer.addr)
+}
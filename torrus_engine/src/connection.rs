@@ -0,0 +1,564 @@
+use crate::torrent::MAX_REQUEST_QUEUE;
+use anyhow::{Context, Result};
+use futures::{SinkExt, StreamExt};
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+    time::{interval_at, timeout, Instant},
+};
+use tokio_util::codec::Framed;
+use torrus_core::prelude::{
+    Block, Blockinfo, ChokeStatus, HashRequest, IntrestStatus, PeerInfo, PeerState, ID,
+};
+use torrus_wire::{
+    ExtendedHandshake, ExtensionRegistry, Handshake, HandshakeCodec, Message, MessageCodec,
+    MetadataMessage, EXTENDED_HANDSHAKE_ID, UT_METADATA,
+};
+
+/// Number of requests kept in flight while the peer unchokes us.
+const DEFAULT_PIPELINE: usize = 16;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers are expected to send something, at least a keep-alive, every two minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Client name and version announced in the extended handshake.
+const CLIENT: &str = concat!("torrus ", env!("CARGO_PKG_VERSION"));
+
+type Connection<S> = Framed<S, MessageCodec>;
+
+/// Instructions from the engine to a [PeerConnection].
+#[derive(Debug)]
+pub enum PeerCommand {
+    /// Queue blocks to download, they are requested as soon as the peer unchokes us.
+    Request(Vec<Blockinfo>),
+    /// Drop a queued block, or cancel it if it was already requested.
+    Cancel(Blockinfo),
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// Upload a block the peer requested.
+    Piece(Block),
+    /// Send a ut_metadata message, dropped if the peer does not support the extension.
+    Metadata(MetadataMessage),
+    /// Ask for merkle hashes, rejected right away if the peer does not support v2 torrents.
+    HashRequest(HashRequest),
+    /// Answer a hash request of the peer.
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(HashRequest),
+    Shutdown,
+}
+
+/// Everything a [PeerConnection] reports back to the engine, tagged with the peer address.
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// The handshake completed, carries the peer id of the remote peer.
+    Connected(ID),
+    StateChanged(PeerState),
+    Have(u32),
+    Bitfield(Vec<u8>),
+    /// A block we requested arrived.
+    Block(Block),
+    /// The peer requested a block while we unchoke it.
+    Request(Blockinfo),
+    Cancel(Blockinfo),
+    /// The peer announced the extensions it supports.
+    ExtendedHandshake(ExtendedHandshake),
+    Metadata(MetadataMessage),
+    /// The peer asked for merkle hashes.
+    HashRequest(HashRequest),
+    /// Hashes answering our request, not verified yet.
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    /// The peer does not hand out the hashes we asked for.
+    HashReject(HashRequest),
+    Disconnected,
+}
+
+/// Handle to send [PeerCommand]s to a running [PeerConnection].
+#[derive(Debug, Clone)]
+pub struct PeerHandle {
+    addr: SocketAddr,
+    commands: mpsc::UnboundedSender<PeerCommand>,
+}
+
+impl PeerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns false when the connection is gone.
+    pub fn send(&self, command: PeerCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+}
+
+/// Connection to a single peer.
+///
+/// Performs the handshake, tracks the choke and interest state of both ends and keeps a
+/// pipeline of block requests in flight while the peer unchokes us. Following the strategy
+/// described in the README, interest is shown right after the handshake.
+pub struct PeerConnection {
+    peer_info: PeerInfo,
+    info_hash: ID,
+    peer_id: ID,
+    state: PeerState,
+    commands: mpsc::UnboundedReceiver<PeerCommand>,
+    events: mpsc::Sender<(SocketAddr, PeerEvent)>,
+    queue: VecDeque<Blockinfo>,
+    in_flight: Vec<Blockinfo>,
+    pipeline: usize,
+    interested: bool,
+    extensions: ExtensionRegistry<PeerEvent>,
+    /// Size of the info dictionary we serve, announced in our extended handshake.
+    metadata_size: Option<u64>,
+    listen_port: Option<u16>,
+    /// Whether we announce support for v2 torrents.
+    v2: bool,
+    /// Whether the peer announced support for v2 torrents.
+    peer_v2: bool,
+}
+
+impl PeerConnection {
+    /// `peer_id` is our own peer id, events are sent to `events` tagged with the peer address.
+    pub fn new(
+        peer_info: PeerInfo,
+        info_hash: ID,
+        peer_id: ID,
+        events: mpsc::Sender<(SocketAddr, PeerEvent)>,
+    ) -> (Self, PeerHandle) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let connection = Self {
+            peer_info,
+            info_hash,
+            peer_id,
+            state: PeerState::default(),
+            commands: rx,
+            events,
+            queue: VecDeque::new(),
+            in_flight: Vec::new(),
+            pipeline: DEFAULT_PIPELINE,
+            interested: true,
+            extensions: extensions(),
+            metadata_size: None,
+            listen_port: None,
+            v2: false,
+            peer_v2: false,
+        };
+
+        let handle = PeerHandle {
+            addr: peer_info.addr,
+            commands: tx,
+        };
+
+        (connection, handle)
+    }
+
+    /// Number of requests kept in flight.
+    pub fn with_pipeline(mut self, pipeline: usize) -> Self {
+        self.pipeline = pipeline.max(1);
+        self
+    }
+
+    /// Whether to send `Interested` right after the handshake, on by default.
+    pub fn interested_on_connect(mut self, interested: bool) -> Self {
+        self.interested = interested;
+        self
+    }
+
+    /// Size of the info dictionary announced to peers, for torrents which serve it over
+    /// ut_metadata.
+    pub fn with_metadata_size(mut self, metadata_size: u64) -> Self {
+        self.metadata_size = Some(metadata_size);
+        self
+    }
+
+    /// Port we accept connections on, announced in our extended handshake.
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    /// Announces support for v2 torrents in the handshake, for torrents with merkle hashes.
+    pub fn with_v2(mut self) -> Self {
+        self.v2 = true;
+        self
+    }
+
+    /// Connects to the peer and runs the connection until either side closes it.
+    pub async fn run(self) -> Result<()> {
+        let connect = TcpStream::connect(self.peer_info.addr);
+        let stream = match timeout(CONNECT_TIMEOUT, connect).await {
+            Ok(stream) => stream,
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        };
+
+        match stream {
+            Ok(stream) => self.run_on(stream).await,
+            Err(e) => {
+                let _ = self
+                    .events
+                    .send((self.peer_info.addr, PeerEvent::Disconnected))
+                    .await;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Runs the connection over an already established stream. [PeerEvent::Disconnected] is
+    /// always the last event sent.
+    pub async fn run_on<S>(mut self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let result = self.drive(stream).await;
+        let _ = self
+            .events
+            .send((self.peer_info.addr, PeerEvent::Disconnected))
+            .await;
+        result
+    }
+
+    /// Runs a connection the peer opened, after its handshake was read from `framed`. Answers
+    /// with our own handshake. [PeerEvent::Disconnected] is always the last event sent.
+    pub async fn run_accepted<S>(
+        mut self,
+        framed: Framed<S, HandshakeCodec>,
+        handshake: Handshake,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let result = self.accept(framed, handshake).await;
+        let _ = self
+            .events
+            .send((self.peer_info.addr, PeerEvent::Disconnected))
+            .await;
+        result
+    }
+
+    async fn accept<S>(
+        &mut self,
+        mut framed: Framed<S, HandshakeCodec>,
+        handshake: Handshake,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if handshake.info_hash != self.info_hash {
+            anyhow::bail!("Peer sent the handshake for a different torrent");
+        }
+        framed.send(self.handshake()).await?;
+        self.exchange(framed, handshake).await
+    }
+
+    async fn drive<S>(&mut self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        framed.send(self.handshake()).await?;
+
+        let handshake = timeout(HANDSHAKE_TIMEOUT, framed.next())
+            .await
+            .context("Peer did not complete the handshake in time")?
+            .context("Peer closed the connection during the handshake")??;
+
+        if handshake.info_hash != self.info_hash {
+            anyhow::bail!("Peer answered the handshake for a different torrent");
+        }
+        self.exchange(framed, handshake).await
+    }
+
+    fn handshake(&self) -> Handshake {
+        let handshake = Handshake::new(self.info_hash, self.peer_id).with_extensions();
+        match self.v2 {
+            true => handshake.with_v2(),
+            false => handshake,
+        }
+    }
+
+    /// Exchanges messages once both handshakes are through.
+    async fn exchange<S>(
+        &mut self,
+        framed: Framed<S, HandshakeCodec>,
+        handshake: Handshake,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.peer_info.id = handshake.peer_id;
+        self.peer_v2 = handshake.supports_v2();
+        self.emit(PeerEvent::Connected(handshake.peer_id)).await?;
+
+        let mut framed = framed.map_codec(|_| MessageCodec::new());
+
+        if handshake.supports_extensions() {
+            let mut extended = self.extensions.handshake();
+            extended.metadata_size = self.metadata_size;
+            extended.port = self.listen_port;
+            extended.request_queue = Some(MAX_REQUEST_QUEUE as u32);
+            extended.client = Some(CLIENT.to_string());
+            extended.set_your_ip(self.peer_info.addr.ip());
+            let message = Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload: extended.to_bytes()?,
+            };
+            framed.send(message).await?;
+        }
+
+        if self.interested {
+            self.set_interest(&mut framed, IntrestStatus::Interested)
+                .await?;
+        }
+
+        let start = Instant::now() + KEEP_ALIVE_INTERVAL;
+        let mut keep_alive = interval_at(start, KEEP_ALIVE_INTERVAL);
+        let mut last_received = Instant::now();
+
+        loop {
+            tokio::select! {
+                message = framed.next() => match message {
+                    Some(message) => {
+                        last_received = Instant::now();
+                        self.handle_message(&mut framed, message?).await?;
+                    }
+                    None => return Ok(()),
+                },
+                command = self.commands.recv() => match command {
+                    Some(PeerCommand::Shutdown) | None => return Ok(()),
+                    Some(command) => self.handle_command(&mut framed, command).await?,
+                },
+                _ = keep_alive.tick() => {
+                    if last_received.elapsed() >= IDLE_TIMEOUT {
+                        anyhow::bail!("Peer has been silent for too long");
+                    }
+                    framed.send(Message::KeepAlive).await?;
+                }
+            }
+        }
+    }
+
+    async fn handle_message<S>(
+        &mut self,
+        framed: &mut Connection<S>,
+        message: Message,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match message {
+            Message::KeepAlive | Message::Port(_) => {}
+            Message::Choke => {
+                // Choking discards all pending requests, they are sent again after an unchoke.
+                for block_info in self.in_flight.drain(..).rev() {
+                    self.queue.push_front(block_info);
+                }
+                self.state.peer_choking = ChokeStatus::Chocked;
+                self.emit(PeerEvent::StateChanged(self.state)).await?;
+            }
+            Message::Unchoke => {
+                self.state.peer_choking = ChokeStatus::NotChocked;
+                self.emit(PeerEvent::StateChanged(self.state)).await?;
+                self.fill_pipeline(framed).await?;
+            }
+            Message::Interested => {
+                self.state.peer_interested = IntrestStatus::Interested;
+                self.emit(PeerEvent::StateChanged(self.state)).await?;
+            }
+            Message::NotInterested => {
+                self.state.peer_interested = IntrestStatus::NotInterested;
+                self.emit(PeerEvent::StateChanged(self.state)).await?;
+            }
+            Message::Have(index) => self.emit(PeerEvent::Have(index)).await?,
+            Message::Bitfield(bitfield) => self.emit(PeerEvent::Bitfield(bitfield)).await?,
+            Message::Request(block_info) => {
+                // Requests from choked peers are ignored.
+                if self.state.am_choking == ChokeStatus::NotChocked {
+                    self.emit(PeerEvent::Request(block_info)).await?;
+                }
+            }
+            Message::Cancel(block_info) => self.emit(PeerEvent::Cancel(block_info)).await?,
+            Message::Piece(block) => {
+                // Blocks we did not ask for, or cancelled meanwhile, are dropped.
+                let requested = self
+                    .in_flight
+                    .iter()
+                    .position(|block_info| *block_info == block.block_info);
+                if let Some(position) = requested {
+                    self.in_flight.swap_remove(position);
+                    self.emit(PeerEvent::Block(block)).await?;
+                    self.fill_pipeline(framed).await?;
+                }
+            }
+            Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload,
+            } => {
+                let extended = ExtendedHandshake::from_bytes(&payload)?;
+                // Never keep more requests in flight than the peer is willing to queue.
+                if let Some(queue) = extended.request_queue {
+                    self.pipeline = self.pipeline.min(queue.max(1) as usize);
+                }
+                self.extensions.set_peer_handshake(extended.clone());
+                self.emit(PeerEvent::ExtendedHandshake(extended)).await?;
+            }
+            Message::Extended { id, payload } => {
+                if let Some(event) = self.extensions.dispatch(id, &payload)? {
+                    self.emit(event).await?;
+                }
+            }
+            Message::HashRequest(request) => self.emit(PeerEvent::HashRequest(request)).await?,
+            Message::Hashes { request, hashes } => {
+                self.emit(PeerEvent::Hashes { request, hashes }).await?
+            }
+            Message::HashReject(request) => self.emit(PeerEvent::HashReject(request)).await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_command<S>(
+        &mut self,
+        framed: &mut Connection<S>,
+        command: PeerCommand,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match command {
+            PeerCommand::Request(blocks) => {
+                self.queue.extend(blocks);
+                self.fill_pipeline(framed).await?;
+            }
+            PeerCommand::Cancel(block_info) => {
+                self.queue.retain(|queued| *queued != block_info);
+                if let Some(position) = self.in_flight.iter().position(|b| *b == block_info) {
+                    self.in_flight.swap_remove(position);
+                    framed.send(Message::Cancel(block_info)).await?;
+                }
+            }
+            PeerCommand::Have(index) => framed.send(Message::Have(index)).await?,
+            PeerCommand::Bitfield(bitfield) => framed.send(Message::Bitfield(bitfield)).await?,
+            PeerCommand::Choke => self.set_choke(framed, ChokeStatus::Chocked).await?,
+            PeerCommand::Unchoke => self.set_choke(framed, ChokeStatus::NotChocked).await?,
+            PeerCommand::Interested => self.set_interest(framed, IntrestStatus::Interested).await?,
+            PeerCommand::NotInterested => {
+                self.set_interest(framed, IntrestStatus::NotInterested)
+                    .await?
+            }
+            PeerCommand::Piece(block) => {
+                if self.state.am_choking == ChokeStatus::NotChocked {
+                    framed.send(Message::Piece(block)).await?;
+                }
+            }
+            PeerCommand::Metadata(message) => {
+                if let Some(message) = self.extensions.message(UT_METADATA, message.to_bytes()?) {
+                    framed.send(message).await?;
+                }
+            }
+            PeerCommand::HashRequest(request) => {
+                if self.peer_v2 {
+                    framed.send(Message::HashRequest(request)).await?;
+                } else {
+                    self.emit(PeerEvent::HashReject(request)).await?;
+                }
+            }
+            PeerCommand::Hashes { request, hashes } => {
+                framed.send(Message::Hashes { request, hashes }).await?
+            }
+            PeerCommand::HashReject(request) => framed.send(Message::HashReject(request)).await?,
+            PeerCommand::Shutdown => unreachable!("handled by the connection loop"),
+        }
+        Ok(())
+    }
+
+    async fn set_choke<S>(&mut self, framed: &mut Connection<S>, choke: ChokeStatus) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.state.am_choking == choke {
+            return Ok(());
+        }
+        self.state.am_choking = choke;
+        let message = match choke {
+            ChokeStatus::Chocked => Message::Choke,
+            ChokeStatus::NotChocked => Message::Unchoke,
+        };
+        framed.send(message).await?;
+        self.emit(PeerEvent::StateChanged(self.state)).await
+    }
+
+    async fn set_interest<S>(
+        &mut self,
+        framed: &mut Connection<S>,
+        interest: IntrestStatus,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.state.am_interested == interest {
+            return Ok(());
+        }
+        self.state.am_interested = interest;
+        let message = match interest {
+            IntrestStatus::Interested => Message::Interested,
+            IntrestStatus::NotInterested => Message::NotInterested,
+        };
+        framed.send(message).await?;
+        self.emit(PeerEvent::StateChanged(self.state)).await?;
+        self.fill_pipeline(framed).await
+    }
+
+    /// Requests queued blocks until the pipeline is full, as long as the peer lets us.
+    async fn fill_pipeline<S>(&mut self, framed: &mut Connection<S>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut requested = false;
+
+        while self.state.can_download() && self.in_flight.len() < self.pipeline {
+            let Some(block_info) = self.queue.pop_front() else {
+                break;
+            };
+            framed.feed(Message::Request(block_info)).await?;
+            self.in_flight.push(block_info);
+            requested = true;
+        }
+
+        if requested {
+            framed.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn emit(&self, event: PeerEvent) -> Result<()> {
+        self.events
+            .send((self.peer_info.addr, event))
+            .await
+            .map_err(|_| anyhow::anyhow!("Engine stopped listening to peer events"))
+    }
+}
+
+/// Extensions every connection speaks, their messages are reported as [PeerEvent]s.
+fn extensions() -> ExtensionRegistry<PeerEvent> {
+    let mut extensions = ExtensionRegistry::new();
+    extensions.register(UT_METADATA, |payload: &[u8]| {
+        let message = MetadataMessage::from_bytes(payload)?;
+        Ok(Some(PeerEvent::Metadata(message)))
+    });
+    extensions
+}
@@ -1,24 +1,457 @@
-use crate::Peer;
-use std::collections::HashSet;
-use tokio::task::JoinHandle;
-use torrus_core::{metainfo::Metainfo, prelude::ID};
-use torrus_tracker::Tracker;
+use crate::{
+    connection::HANDSHAKE_TIMEOUT,
+    metadata::MetadataTask,
+    torrent::{Incoming, Torrent, TorrentCommand, TorrentConfig, TorrentStatus},
+    PickStrategy,
+};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_util::codec::Framed;
+use torrus_core::{
+    prelude::{MagnetLink, MetaVersion, Metainfo, Sha1Hash, ID},
+    store::Store,
+};
+use torrus_storage::merkle::TorrentHashes;
+use torrus_wire::HandshakeCodec;
 
-pub type DefCmd<T, U> = Box<dyn Fn(T) -> U>;
+const DEFAULT_PORT: u16 = 6881;
+const DEFAULT_MAX_PEERS: usize = 50;
+const DEFAULT_UPLOAD_SLOTS: usize = 4;
 
-impl<T, Args> Command<Args, T> for DefCmd<Args, T> {}
+/// Pause after failing to accept a connection, errors like running out of file descriptors
+/// persist for a while.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
-pub trait Command<Args, T>: Fn(Args) -> T {}
+type Reply<T> = oneshot::Sender<Result<T>>;
 
-pub struct Engine {
-    commands: Vec<Box<dyn Command<(), (), Output = ()>>>,
-    torrents: HashSet<ID, TorrentEntry>,
-    engine_thread: Option<JoinHandle<()>>,
+pub(crate) enum Command {
+    AddTorrent(Box<Metainfo>, Reply<ID>),
+    AddMagnet(Box<MagnetLink>, Reply<ID>),
+    AddPeers(ID, Vec<SocketAddr>, Reply<()>),
+    Remove(ID, Reply<()>),
+    Pause(ID, Reply<()>),
+    Resume(ID, Reply<()>),
+    SetStrategy(ID, PickStrategy, Reply<()>),
+    SetDeadlines(ID, Vec<(usize, Duration)>, Reply<()>),
+    List(oneshot::Sender<Vec<TorrentStatus>>),
+    Watch(ID, Reply<watch::Receiver<TorrentStatus>>),
+    ListenAddr(Reply<SocketAddr>),
+    Shutdown(oneshot::Sender<()>),
 }
 
-pub struct TorrentEntry {
-    metainfo: Metainfo,
-    info_hash: ID,
-    peers: HashSet<ID, Peer>,
-    trackers: Vec<Tracker>,
+/// Cloneable handle to a running [Engine].
+#[derive(Clone)]
+pub struct EngineHandle {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl EngineHandle {
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(command(tx))
+            .map_err(|_| anyhow::anyhow!("Engine is not running"))?;
+        rx.await.context("Engine is not running")?
+    }
+
+    /// Adds a torrent and starts downloading it, returns its info hash.
+    pub async fn add_torrent(&self, metainfo: Metainfo) -> Result<ID> {
+        self.request(|tx| Command::AddTorrent(Box::new(metainfo), tx))
+            .await
+    }
+
+    /// Adds a torrent from a magnet link, returns its info hash. The torrent is in the
+    /// [FetchingMetadata](crate::TorrentState::FetchingMetadata) state until the info
+    /// dictionary is downloaded from peers, then it starts like any other torrent.
+    pub async fn add_magnet(&self, magnet: MagnetLink) -> Result<ID> {
+        self.request(|tx| Command::AddMagnet(Box::new(magnet), tx))
+            .await
+    }
+
+    /// Connects to peers not learned from a tracker.
+    pub async fn add_peers(&self, id: ID, addrs: Vec<SocketAddr>) -> Result<()> {
+        self.request(|tx| Command::AddPeers(id, addrs, tx)).await
+    }
+
+    /// Stops the torrent and forgets about it, downloaded data stays in the store.
+    pub async fn remove(&self, id: ID) -> Result<()> {
+        self.request(|tx| Command::Remove(id, tx)).await
+    }
+
+    /// Disconnects all peers and stops announcing until resumed.
+    pub async fn pause(&self, id: ID) -> Result<()> {
+        self.request(|tx| Command::Pause(id, tx)).await
+    }
+
+    /// Restarts a paused torrent, or retries one which stopped because of an error.
+    pub async fn resume(&self, id: ID) -> Result<()> {
+        self.request(|tx| Command::Resume(id, tx)).await
+    }
+
+    /// Changes the order in which the remaining pieces of the torrent are downloaded.
+    pub async fn set_strategy(&self, id: ID, strategy: PickStrategy) -> Result<()> {
+        self.request(|tx| Command::SetStrategy(id, strategy, tx))
+            .await
+    }
+
+    /// Pieces to download before any other, each with the time it is needed in, such as the
+    /// pieces following a playback position. Pieces which fall behind are requested from the
+    /// fastest peers as well. Replaces earlier deadlines, an empty list clears them.
+    pub async fn set_deadlines(&self, id: ID, deadlines: Vec<(usize, Duration)>) -> Result<()> {
+        self.request(|tx| Command::SetDeadlines(id, deadlines, tx))
+            .await
+    }
+
+    pub async fn list(&self) -> Result<Vec<TorrentStatus>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(Command::List(tx))
+            .map_err(|_| anyhow::anyhow!("Engine is not running"))?;
+        rx.await.context("Engine is not running")
+    }
+
+    pub async fn status(&self, id: ID) -> Result<TorrentStatus> {
+        let status = self.watch(id).await?;
+        let status = status.borrow().clone();
+        Ok(status)
+    }
+
+    /// Receiver which is updated on every change of the torrent's status.
+    pub async fn watch(&self, id: ID) -> Result<watch::Receiver<TorrentStatus>> {
+        self.request(|tx| Command::Watch(id, tx)).await
+    }
+
+    /// Address incoming peer connections are accepted on.
+    pub async fn listen_addr(&self) -> Result<SocketAddr> {
+        self.request(Command::ListenAddr).await
+    }
+
+    /// Stops every torrent and the engine itself.
+    pub async fn shutdown(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.commands.send(Command::Shutdown(tx)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+/// Per torrent bookkeeping of the [Engine].
+struct TorrentEntry {
+    commands: mpsc::UnboundedSender<TorrentCommand>,
+    status: watch::Receiver<TorrentStatus>,
+    task: JoinHandle<()>,
+}
+
+impl TorrentEntry {
+    fn send(&self, command: TorrentCommand) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("Torrent task stopped"))
+    }
+}
+
+/// Runs every torrent of the client, each in its own task.
+///
+/// Controlled through an [EngineHandle], blocks of all torrents are written to the same store.
+/// Peers connecting to the engine are handed to the torrent matching their handshake.
+pub struct Engine<S> {
+    config: TorrentConfig,
+    store: Arc<Mutex<S>>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    torrents: HashMap<ID, TorrentEntry>,
+    /// Bound address of the listener, or why binding failed.
+    listen_addr: Result<SocketAddr, String>,
+}
+
+impl<S> Engine<S>
+where
+    S: Store + Send + 'static,
+    S::Err: Send + Sync + 'static,
+{
+    /// `peer_id` identifies the client to trackers and peers.
+    pub fn new(peer_id: ID, store: S) -> (Self, EngineHandle) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let engine = Self {
+            config: TorrentConfig {
+                peer_id,
+                port: DEFAULT_PORT,
+                max_peers: DEFAULT_MAX_PEERS,
+                upload_slots: DEFAULT_UPLOAD_SLOTS,
+            },
+            store: Arc::new(Mutex::new(store)),
+            commands: rx,
+            torrents: HashMap::new(),
+            listen_addr: Err("Engine is not listening yet".into()),
+        };
+
+        (engine, EngineHandle { commands: tx })
+    }
+
+    /// Port incoming peer connections are accepted on and announced to trackers, 0 picks a
+    /// free one.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Maximum number of connected peers per torrent.
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.config.max_peers = max_peers;
+        self
+    }
+
+    /// Number of peers per torrent allowed to download from us at once, including the
+    /// optimistic unchoke.
+    pub fn with_upload_slots(mut self, upload_slots: usize) -> Self {
+        self.config.upload_slots = upload_slots;
+        self
+    }
+
+    /// Runs until [EngineHandle::shutdown] is called or every handle is dropped.
+    pub async fn run(mut self) {
+        let listeners = self.listen().await;
+        let (incoming_tx, mut incoming) = mpsc::channel(16);
+
+        loop {
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(Command::Shutdown(reply)) => {
+                        self.shutdown().await;
+                        let _ = reply.send(());
+                        return;
+                    }
+                    Some(command) => self.handle(command).await,
+                    None => break,
+                },
+                (stream, addr) = accept(&listeners) => {
+                    tokio::spawn(read_handshake(stream, addr, incoming_tx.clone()));
+                }
+                Some(peer) = incoming.recv() => {
+                    // Peers for torrents we do not know are dropped.
+                    if let Some(entry) = self.torrents.get(&peer.handshake.info_hash) {
+                        let _ = entry.send(TorrentCommand::Incoming(Box::new(peer)));
+                    }
+                }
+            }
+        }
+        self.shutdown().await;
+    }
+
+    /// Binds the peer port on IPv6 and IPv4.
+    ///
+    /// Where the IPv6 socket is dual-stack it takes IPv4 connections as well and binding IPv4
+    /// fails, only one of both has to succeed.
+    async fn listen(&mut self) -> Vec<TcpListener> {
+        let mut listeners = Vec::new();
+        let mut error = None;
+        for ip in [
+            IpAddr::from(Ipv6Addr::UNSPECIFIED),
+            Ipv4Addr::UNSPECIFIED.into(),
+        ] {
+            match TcpListener::bind((ip, self.config.port)).await {
+                Ok(listener) => {
+                    // The second listener takes the port picked for the first.
+                    if let Ok(addr) = listener.local_addr() {
+                        self.config.port = addr.port();
+                    }
+                    listeners.push(listener);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        let addr = match listeners.first() {
+            Some(listener) => listener.local_addr(),
+            None => Err(error.unwrap()),
+        };
+        self.listen_addr = addr.map_err(|e| format!("Could not listen for peers: {e}"));
+        listeners
+    }
+
+    async fn handle(&mut self, command: Command) {
+        match command {
+            Command::AddTorrent(metainfo, reply) => {
+                let _ = reply.send(self.add_torrent(*metainfo));
+            }
+            Command::AddMagnet(magnet, reply) => {
+                let _ = reply.send(self.add_magnet(*magnet));
+            }
+            Command::AddPeers(id, addrs, reply) => {
+                let result = self
+                    .torrent(id)
+                    .and_then(|entry| entry.send(TorrentCommand::AddPeers(addrs)));
+                let _ = reply.send(result);
+            }
+            Command::Remove(id, reply) => {
+                let _ = reply.send(self.remove(id).await);
+            }
+            Command::Pause(id, reply) => {
+                let result = self
+                    .torrent(id)
+                    .and_then(|entry| entry.send(TorrentCommand::Pause));
+                let _ = reply.send(result);
+            }
+            Command::Resume(id, reply) => {
+                let result = self
+                    .torrent(id)
+                    .and_then(|entry| entry.send(TorrentCommand::Resume));
+                let _ = reply.send(result);
+            }
+            Command::SetStrategy(id, strategy, reply) => {
+                let result = self
+                    .torrent(id)
+                    .and_then(|entry| entry.send(TorrentCommand::SetStrategy(strategy)));
+                let _ = reply.send(result);
+            }
+            Command::SetDeadlines(id, deadlines, reply) => {
+                let result = self
+                    .torrent(id)
+                    .and_then(|entry| entry.send(TorrentCommand::SetDeadlines(deadlines)));
+                let _ = reply.send(result);
+            }
+            Command::List(reply) => {
+                let list = self
+                    .torrents
+                    .values()
+                    .map(|entry| entry.status.borrow().clone())
+                    .collect();
+                let _ = reply.send(list);
+            }
+            Command::Watch(id, reply) => {
+                let result = self.torrent(id).map(|entry| entry.status.clone());
+                let _ = reply.send(result);
+            }
+            Command::ListenAddr(reply) => {
+                let result = self.listen_addr.clone().map_err(anyhow::Error::msg);
+                let _ = reply.send(result);
+            }
+            Command::Shutdown(_) => unreachable!("handled by the engine loop"),
+        }
+    }
+
+    fn add_torrent(&mut self, metainfo: Metainfo) -> Result<ID> {
+        if metainfo.info.version() == MetaVersion::V2 {
+            anyhow::bail!("v2 only torrents are not supported, their pieces can not be verified");
+        }
+        if metainfo.info.version() == MetaVersion::Hybrid {
+            TorrentHashes::new(&metainfo).context("Invalid v2 hashes of a hybrid torrent")?;
+        }
+        let id = metainfo.as_sha1();
+        if self.torrents.contains_key(&id) {
+            anyhow::bail!("Torrent {} was already added", id);
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (torrent, status) = Torrent::new(metainfo, self.config, self.store.clone(), rx);
+        let task = tokio::spawn(torrent.run());
+
+        let entry = TorrentEntry {
+            commands: tx,
+            status,
+            task,
+        };
+        self.torrents.insert(id, entry);
+        Ok(id)
+    }
+
+    fn add_magnet(&mut self, magnet: MagnetLink) -> Result<ID> {
+        let Some(id) = magnet.info_hash else {
+            anyhow::bail!("Magnet link has no v1 info hash, v2 only torrents are not supported");
+        };
+        if self.torrents.contains_key(&id) {
+            anyhow::bail!("Torrent {} was already added", id);
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (task, status) = MetadataTask::new(magnet, self.config, rx);
+        let config = self.config;
+        let store = self.store.clone();
+        let task = tokio::spawn(async move {
+            if let Some(resolved) = task.run().await {
+                Torrent::from_magnet(resolved, config, store).run().await;
+            }
+        });
+
+        let entry = TorrentEntry {
+            commands: tx,
+            status,
+            task,
+        };
+        self.torrents.insert(id, entry);
+        Ok(id)
+    }
+
+    fn torrent(&self, id: ID) -> Result<&TorrentEntry> {
+        self.torrents
+            .get(&id)
+            .with_context(|| format!("Unknown torrent {}", id))
+    }
+
+    async fn remove(&mut self, id: ID) -> Result<()> {
+        let entry = self
+            .torrents
+            .remove(&id)
+            .with_context(|| format!("Unknown torrent {}", id))?;
+        let _ = entry.send(TorrentCommand::Shutdown);
+        let _ = entry.task.await;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) {
+        for (_, entry) in self.torrents.drain() {
+            let _ = entry.send(TorrentCommand::Shutdown);
+            let _ = entry.task.await;
+        }
+    }
+}
+
+/// Waits for the next incoming connection on any listener, pending forever without one.
+///
+/// IPv4 peers reaching a dual-stack listener get their plain IPv4 address.
+async fn accept(listeners: &[TcpListener]) -> (TcpStream, SocketAddr) {
+    if listeners.is_empty() {
+        return std::future::pending().await;
+    }
+    loop {
+        let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+        match futures::future::select_all(accepts).await.0 {
+            Ok((stream, addr)) => {
+                return (
+                    stream,
+                    SocketAddr::new(addr.ip().to_canonical(), addr.port()),
+                );
+            }
+            Err(e) => {
+                log::warn!("Could not accept a peer connection: {e}");
+                sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Reads the handshake of an incoming connection to find out which torrent it is for.
+async fn read_handshake(stream: TcpStream, addr: SocketAddr, incoming: mpsc::Sender<Incoming>) {
+    let mut framed = Framed::new(stream, HandshakeCodec);
+    if let Ok(Some(Ok(handshake))) = timeout(HANDSHAKE_TIMEOUT, framed.next()).await {
+        let _ = incoming
+            .send(Incoming {
+                addr,
+                framed,
+                handshake,
+            })
+            .await;
+    }
 }
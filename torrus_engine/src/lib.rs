@@ -1,5 +1,14 @@
+mod choker;
+mod connection;
 mod engine;
+mod metadata;
 mod peer;
+mod picker;
+mod torrent;
 pub(crate) use peer::Peer;
 
-pub use engine::{Command, Engine, TorrentEntry};
+pub use choker::{Choker, ChokerPeer, CHOKE_INTERVAL};
+pub use connection::{PeerCommand, PeerConnection, PeerEvent, PeerHandle};
+pub use engine::{Engine, EngineHandle};
+pub use picker::{PickStrategy, PiecePicker};
+pub use torrent::{TorrentState, TorrentStatus};
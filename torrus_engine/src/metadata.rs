@@ -0,0 +1,535 @@
+use crate::{
+    torrent::{TorrentCommand, TorrentConfig, TorrentState, TorrentStatus},
+    PeerCommand, PeerConnection, PeerEvent, PeerHandle, PickStrategy,
+};
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::{interval, Instant},
+};
+use torrus_core::prelude::{MagnetLink, Metainfo, PeerInfo, ID};
+use torrus_storage::piece::BLOCK_SIZE;
+use torrus_tracker::{AnnounceStats, Announcer, AnnouncerHandle, TrackerList, TrackerRequest};
+use torrus_wire::{ExtendedHandshake, MetadataMessage, METADATA_PIECE_SIZE, UT_METADATA};
+
+/// The size is unknown until the metadata arrives, trackers only need to know we are not
+/// seeding.
+const UNKNOWN_LEFT: u64 = BLOCK_SIZE;
+
+/// Info dictionaries larger than this are not fetched, they are most likely bogus.
+const MAX_METADATA_SIZE: u64 = 1 << 24;
+
+/// Pieces of the info dictionary requested from a peer at once.
+const MAX_REQUESTS: usize = 4;
+
+/// Peers which did not announce ut_metadata this long after we started connecting are
+/// dropped to make room for others.
+const EXTENSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything a torrent task needs to take over once the info dictionary is known.
+pub(crate) struct Resolved {
+    pub info_hash: ID,
+    pub metainfo: Metainfo,
+    pub commands: mpsc::UnboundedReceiver<TorrentCommand>,
+    pub status: watch::Sender<TorrentStatus>,
+    /// Peers to download the pieces from.
+    pub peers: Vec<SocketAddr>,
+    pub strategy: PickStrategy,
+    pub deadlines: Vec<(usize, Duration)>,
+}
+
+struct MetadataPeer {
+    handle: PeerHandle,
+    connected_at: Instant,
+    /// Set once the peer announced ut_metadata.
+    supported: bool,
+    /// Pieces requested from the peer which it did not answer yet.
+    requested: Vec<u32>,
+}
+
+/// Info dictionary put together from the pieces peers send.
+struct Metadata {
+    size: usize,
+    /// Received pieces and the peer each one came from.
+    pieces: Vec<Option<(SocketAddr, Vec<u8>)>>,
+    /// Set once a dictionary did not match the info hash. Every piece then comes from a
+    /// single peer, so the peer to blame for a mismatch is known.
+    single_source: bool,
+    /// The peer pieces are requested from while `single_source` is set.
+    source: Option<SocketAddr>,
+}
+
+impl Metadata {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            pieces: vec![None; size.div_ceil(METADATA_PIECE_SIZE)],
+            single_source: false,
+            source: None,
+        }
+    }
+
+    /// Drops every received piece to start over from a single peer.
+    fn restart(&mut self) {
+        *self = Self {
+            single_source: true,
+            ..Self::new(self.size)
+        };
+    }
+
+    fn piece_size(&self, piece: usize) -> usize {
+        METADATA_PIECE_SIZE.min(self.size - piece * METADATA_PIECE_SIZE)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+}
+
+/// Task running a torrent added from a magnet link until its info dictionary is known.
+///
+/// Connects to the peers of the magnet link and its trackers and downloads the dictionary
+/// over ut_metadata, see BEP 9. Once it matches the info hash the torrent is handed over to
+/// a regular torrent task.
+pub(crate) struct MetadataTask {
+    magnet: MagnetLink,
+    info_hash: ID,
+    config: TorrentConfig,
+    commands: mpsc::UnboundedReceiver<TorrentCommand>,
+    status: watch::Sender<TorrentStatus>,
+    stats: watch::Sender<AnnounceStats>,
+    announcer: Option<(AnnouncerHandle, mpsc::Receiver<Vec<SocketAddr>>)>,
+    events_tx: mpsc::Sender<(SocketAddr, PeerEvent)>,
+    events: mpsc::Receiver<(SocketAddr, PeerEvent)>,
+    peers: HashMap<SocketAddr, MetadataPeer>,
+    /// Peers to fetch the metadata from once a connection slot frees up.
+    candidates: VecDeque<SocketAddr>,
+    metadata: Option<Metadata>,
+    /// Peers which alone sent a dictionary that did not match the info hash.
+    banned: HashSet<IpAddr>,
+    paused: bool,
+    strategy: PickStrategy,
+    deadlines: Vec<(usize, Duration)>,
+}
+
+impl MetadataTask {
+    /// The magnet link must have a v1 info hash.
+    pub(crate) fn new(
+        magnet: MagnetLink,
+        config: TorrentConfig,
+        commands: mpsc::UnboundedReceiver<TorrentCommand>,
+    ) -> (Self, watch::Receiver<TorrentStatus>) {
+        let info_hash = magnet.info_hash.expect("magnet link without v1 info hash");
+        let (status, rx) = watch::channel(TorrentStatus {
+            info_hash,
+            name: magnet.name.clone().unwrap_or_else(|| info_hash.to_string()),
+            state: TorrentState::FetchingMetadata,
+            num_pieces: 0,
+            pieces_done: 0,
+            downloaded: 0,
+            uploaded: 0,
+            left: 0,
+            peers: 0,
+            hash_failures: 0,
+            endgame: false,
+            wasted: 0,
+        });
+        let (stats, _) = watch::channel(AnnounceStats {
+            left: UNKNOWN_LEFT,
+            ..Default::default()
+        });
+        let (events_tx, events) = mpsc::channel(64);
+
+        let task = Self {
+            magnet,
+            info_hash,
+            config,
+            commands,
+            status,
+            stats,
+            announcer: None,
+            events_tx,
+            events,
+            peers: HashMap::new(),
+            candidates: VecDeque::new(),
+            metadata: None,
+            banned: HashSet::new(),
+            paused: false,
+            strategy: PickStrategy::default(),
+            deadlines: Vec::new(),
+        };
+
+        (task, rx)
+    }
+
+    /// Runs until the info dictionary is downloaded, returns `None` if the torrent was shut
+    /// down before.
+    pub(crate) async fn run(mut self) -> Option<Resolved> {
+        self.start();
+        let mut tick = interval(TICK_INTERVAL);
+
+        let metainfo = loop {
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(TorrentCommand::Pause) => {
+                        self.paused = true;
+                        self.stop();
+                        self.set_state(TorrentState::Paused);
+                    }
+                    Some(TorrentCommand::Resume) => {
+                        self.paused = false;
+                        if self.announcer.is_none() && self.peers.is_empty() {
+                            self.start();
+                        }
+                    }
+                    Some(TorrentCommand::AddPeers(addrs)) => self.add_peers(addrs),
+                    // Both apply once the torrent is running.
+                    Some(TorrentCommand::SetStrategy(strategy)) => self.strategy = strategy,
+                    Some(TorrentCommand::SetDeadlines(deadlines)) => self.deadlines = deadlines,
+                    // Peers can not download anything from us yet.
+                    Some(TorrentCommand::Incoming(_)) => {}
+                    Some(TorrentCommand::Shutdown) | None => {
+                        self.stop();
+                        return None;
+                    }
+                },
+                addrs = next_peers(self.announcer.as_mut()) => self.add_peers(addrs),
+                Some((addr, event)) = self.events.recv() => {
+                    let Some(metadata) = self.handle_event(addr, event) else {
+                        continue;
+                    };
+                    match self.verify(metadata) {
+                        Ok(Some(resolved)) => break resolved,
+                        Ok(None) => {}
+                        Err(e) => {
+                            self.stop();
+                            self.set_state(TorrentState::Error(e.to_string()));
+                        }
+                    }
+                }
+                _ = tick.tick() => self.drop_unsupported(),
+            }
+        };
+
+        Some(self.into_resolved(metainfo))
+    }
+
+    /// Starts announcing to the trackers of the magnet link and connecting to its peers.
+    fn start(&mut self) {
+        self.set_state(TorrentState::FetchingMetadata);
+        // Hostnames are not resolved, peers in magnet links are almost always ip addresses.
+        let peers = self
+            .magnet
+            .peers
+            .iter()
+            .filter_map(|peer| peer.parse().ok());
+        self.add_peers(peers.collect());
+        if self.magnet.trackers.is_empty() {
+            return;
+        }
+
+        let request = TrackerRequest::builder()
+            .info_hash(self.info_hash)
+            .set_peer_id(self.config.peer_id)
+            .set_port(self.config.port);
+        let (addrs_tx, addrs) = mpsc::channel(8);
+        let (announcer, handle) = Announcer::new(
+            TrackerList::new(vec![self.magnet.trackers.clone()]),
+            request,
+            self.stats.subscribe(),
+            addrs_tx,
+        );
+        tokio::spawn(announcer.run());
+        self.announcer = Some((handle, addrs));
+    }
+
+    /// Stops announcing and disconnects every peer, received pieces are kept.
+    fn stop(&mut self) {
+        if let Some((announcer, _)) = self.announcer.take() {
+            announcer.stop();
+        }
+        for (_, peer) in self.peers.drain() {
+            peer.handle.send(PeerCommand::Shutdown);
+        }
+        self.candidates.clear();
+        self.publish();
+    }
+
+    fn add_peers(&mut self, addrs: Vec<SocketAddr>) {
+        if self.paused {
+            return;
+        }
+        for addr in addrs {
+            if !self.peers.contains_key(&addr)
+                && !self.candidates.contains(&addr)
+                && !self.banned.contains(&addr.ip())
+            {
+                self.candidates.push_back(addr);
+            }
+        }
+        self.connect_peers();
+    }
+
+    fn connect_peers(&mut self) {
+        while self.peers.len() < self.config.max_peers {
+            let Some(addr) = self.candidates.pop_front() else {
+                break;
+            };
+            let (connection, handle) = PeerConnection::new(
+                PeerInfo::new(addr),
+                self.info_hash,
+                self.config.peer_id,
+                self.events_tx.clone(),
+            );
+            let connection = connection
+                .interested_on_connect(false)
+                .with_listen_port(self.config.port);
+            tokio::spawn(connection.run());
+            let peer = MetadataPeer {
+                handle,
+                connected_at: Instant::now(),
+                supported: false,
+                requested: Vec::new(),
+            };
+            self.peers.insert(addr, peer);
+        }
+        self.publish();
+    }
+
+    /// Handles an event of a peer, returns the info dictionary once every piece arrived.
+    fn handle_event(&mut self, addr: SocketAddr, event: PeerEvent) -> Option<Metadata> {
+        match event {
+            PeerEvent::Disconnected => {
+                self.peers.remove(&addr);
+                // The source left before sending every piece, the next one starts over.
+                if let Some(metadata) = &mut self.metadata {
+                    if metadata.source == Some(addr) {
+                        metadata.restart();
+                    }
+                }
+                self.connect_peers();
+                // Pieces requested from the peer are up for grabs again.
+                self.request_from_all();
+            }
+            PeerEvent::ExtendedHandshake(handshake) => self.handle_handshake(addr, handshake),
+            PeerEvent::Metadata(MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            }) => return self.handle_piece(addr, piece, total_size, data),
+            PeerEvent::Metadata(MetadataMessage::Request(piece)) => {
+                if let Some(peer) = self.peers.get(&addr) {
+                    let reject = MetadataMessage::Reject(piece);
+                    peer.handle.send(PeerCommand::Metadata(reject));
+                }
+            }
+            // The peer does not hand out the dictionary, somebody else might.
+            PeerEvent::Metadata(MetadataMessage::Reject(_)) => self.disconnect(addr),
+            _ => {}
+        }
+        None
+    }
+
+    fn handle_handshake(&mut self, addr: SocketAddr, handshake: ExtendedHandshake) {
+        let size = handshake
+            .metadata_size
+            .filter(|size| (1..=MAX_METADATA_SIZE).contains(size));
+        let (Some(_), Some(size)) = (handshake.extension_id(UT_METADATA), size) else {
+            self.disconnect(addr);
+            return;
+        };
+        let metadata = self
+            .metadata
+            .get_or_insert_with(|| Metadata::new(size as usize));
+        // Peers disagreeing on the size have a different dictionary, it might be the right
+        // one but there is no way to tell before downloading it.
+        if metadata.size != size as usize {
+            self.disconnect(addr);
+            return;
+        }
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.supported = true;
+        }
+        self.request_pieces(addr);
+    }
+
+    fn handle_piece(
+        &mut self,
+        addr: SocketAddr,
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    ) -> Option<Metadata> {
+        let peer = self.peers.get_mut(&addr)?;
+        let metadata = self.metadata.as_mut()?;
+        // Pieces we did not ask for are dropped.
+        let position = peer.requested.iter().position(|p| *p == piece)?;
+        peer.requested.swap_remove(position);
+
+        let index = piece as usize;
+        if total_size != metadata.size as u64 || data.len() != metadata.piece_size(index) {
+            self.disconnect(addr);
+            return None;
+        }
+        if metadata.pieces[index].is_none() {
+            metadata.pieces[index] = Some((addr, data));
+        }
+
+        if metadata.is_complete() {
+            return self.metadata.take();
+        }
+        self.request_pieces(addr);
+        None
+    }
+
+    fn request_from_all(&mut self) {
+        let addrs: Vec<_> = self.peers.keys().copied().collect();
+        for addr in addrs {
+            self.request_pieces(addr);
+        }
+    }
+
+    /// Requests missing pieces until the peer has enough outstanding. Pieces nobody was
+    /// asked for come first, then pieces other peers are slow to send.
+    fn request_pieces(&mut self, addr: SocketAddr) {
+        let (Some(peer), Some(metadata)) = (self.peers.get(&addr), self.metadata.as_mut()) else {
+            return;
+        };
+        if !peer.supported {
+            return;
+        }
+        if metadata.single_source {
+            match metadata.source {
+                Some(source) if source != addr => return,
+                _ => metadata.source = Some(addr),
+            }
+        }
+        let requested: HashSet<u32> = self
+            .peers
+            .values()
+            .flat_map(|peer| peer.requested.iter().copied())
+            .collect();
+        let mut missing: Vec<u32> = (0..metadata.pieces.len())
+            .filter(|index| metadata.pieces[*index].is_none())
+            .map(|index| index as u32)
+            .filter(|piece| !peer.requested.contains(piece))
+            .collect();
+        missing.sort_by_key(|piece| requested.contains(piece));
+
+        let free = MAX_REQUESTS.saturating_sub(peer.requested.len());
+        let pieces: Vec<_> = missing.into_iter().take(free).collect();
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        for piece in pieces {
+            peer.requested.push(piece);
+            let request = MetadataMessage::Request(piece);
+            peer.handle.send(PeerCommand::Metadata(request));
+        }
+    }
+
+    /// Checks the assembled dictionary against the info hash. On a mismatch fetching starts
+    /// over from a single peer, which is banned if its dictionary does not match either.
+    fn verify(&mut self, mut metadata: Metadata) -> Result<Option<Metainfo>> {
+        let mut sources = HashSet::new();
+        let mut bytes = Vec::with_capacity(metadata.size);
+        for (addr, data) in metadata.pieces.iter().flatten() {
+            sources.insert(*addr);
+            bytes.extend_from_slice(data);
+        }
+
+        if ID::from(Sha1::digest(&bytes).to_vec()) != self.info_hash {
+            // With pieces from several peers there is no telling which of them lied.
+            if sources.len() == 1 {
+                let addr = sources.into_iter().next().unwrap();
+                self.banned.insert(addr.ip());
+                self.disconnect(addr);
+            }
+            metadata.restart();
+            self.metadata = Some(metadata);
+            // Late answers to requests of the old dictionary are dropped.
+            for peer in self.peers.values_mut() {
+                peer.requested.clear();
+            }
+            self.request_from_all();
+            return Ok(None);
+        }
+
+        let mut metainfo =
+            Metainfo::from_info_bytes(bytes).context("Metadata is not a valid info dictionary")?;
+        let trackers = self.magnet.trackers.clone();
+        metainfo.announce = trackers.first().cloned();
+        metainfo.announce_list = (!trackers.is_empty()).then(|| vec![trackers]);
+        Ok(Some(metainfo))
+    }
+
+    /// Disconnects every peer and hands the torrent over, the peers are connected again by
+    /// the torrent task.
+    fn into_resolved(mut self, metainfo: Metainfo) -> Resolved {
+        let mut peers: Vec<_> = self.peers.keys().copied().collect();
+        peers.extend(self.candidates.drain(..));
+        self.stop();
+
+        Resolved {
+            info_hash: self.info_hash,
+            metainfo,
+            commands: self.commands,
+            status: self.status,
+            peers,
+            strategy: self.strategy,
+            deadlines: self.deadlines,
+        }
+    }
+
+    /// Drops peers which do not speak ut_metadata.
+    fn drop_unsupported(&mut self) {
+        let addrs: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| !peer.supported && peer.connected_at.elapsed() > EXTENSION_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in addrs {
+            self.disconnect(addr);
+        }
+    }
+
+    /// Closes the connection, the slot is freed once it reports back.
+    fn disconnect(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.supported = false;
+            peer.requested.clear();
+            peer.handle.send(PeerCommand::Shutdown);
+        }
+    }
+
+    fn set_state(&self, state: TorrentState) {
+        self.status.send_modify(|status| status.state = state);
+    }
+
+    fn publish(&self) {
+        let peers = self.peers.len();
+        self.status.send_modify(|status| status.peers = peers);
+    }
+}
+
+/// Waits for peers from the trackers, pending forever while not announcing.
+async fn next_peers(
+    announcer: Option<&mut (AnnouncerHandle, mpsc::Receiver<Vec<SocketAddr>>)>,
+) -> Vec<SocketAddr> {
+    match announcer {
+        Some((_, addrs)) => match addrs.recv().await {
+            Some(addrs) => addrs,
+            None => std::future::pending().await,
+        },
+        None => std::future::pending().await,
+    }
+}
@@ -1,15 +1,110 @@
-use torrus_core::prelude::{ChokeStatus, IntrestStatus, PeerInfo, PeerState, Sha1Hash, ID};
+use crate::{PeerCommand, PeerHandle};
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+use torrus_core::prelude::{Blockinfo, ClientInfo, PeerInfo, PeerState, Sha1Hash, ID};
 
+/// Peers which unchoke us but send none of the requested blocks for this long are snubbing us.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Engine side view of a connected peer.
 pub struct Peer {
     pub(crate) peer_info: PeerInfo,
     pub(crate) state: PeerState,
+    pub(crate) client: ClientInfo,
+    pub(crate) handle: PeerHandle,
+    /// Pieces the peer announced through `bitfield` and `have` messages.
+    pub(crate) pieces: Vec<bool>,
+    /// Piece currently downloaded from this peer.
+    pub(crate) piece: Option<usize>,
+    /// Pieces other peers are downloading which were requested from this peer as well in endgame.
+    pub(crate) duplicates: Vec<usize>,
+    /// Bytes per second received from the peer, averaged over the last ticks.
+    pub(crate) download_rate: u64,
+    /// Bytes received since the last tick.
+    pub(crate) received: u64,
+    /// Bytes per second uploaded to the peer, averaged over the last ticks.
+    pub(crate) upload_rate: u64,
+    /// Bytes sent since the last tick.
+    pub(crate) sent: u64,
+    pub(crate) connected_at: Instant,
+    /// When the peer last sent a block, or was asked for blocks while none were outstanding.
+    pub(crate) last_block: Instant,
+    /// Blocks the peer requested which were not sent yet.
+    pub(crate) uploads: VecDeque<Blockinfo>,
+    /// Whether a block for the peer is read from the store right now.
+    pub(crate) reading: bool,
 }
 
 impl Peer {
-    pub fn new(peer_info: PeerInfo) -> Self {
+    pub fn new(peer_info: PeerInfo, handle: PeerHandle, num_pieces: usize) -> Self {
         Peer {
             peer_info,
             state: PeerState::default(),
+            client: ClientInfo::default(),
+            handle,
+            pieces: vec![false; num_pieces],
+            piece: None,
+            duplicates: Vec::new(),
+            download_rate: 0,
+            received: 0,
+            upload_rate: 0,
+            sent: 0,
+            connected_at: Instant::now(),
+            last_block: Instant::now(),
+            uploads: VecDeque::new(),
+            reading: false,
+        }
+    }
+
+    pub fn send(&self, command: PeerCommand) -> bool {
+        self.handle.send(command)
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.pieces.get(index).copied().unwrap_or(false)
+    }
+
+    pub fn set_have(&mut self, index: usize) {
+        if let Some(have) = self.pieces.get_mut(index) {
+            *have = true;
+        }
+    }
+
+    /// Whether blocks of the piece were requested from the peer.
+    pub fn is_requesting(&self, index: usize) -> bool {
+        self.piece == Some(index) || self.duplicates.contains(&index)
+    }
+
+    /// Folds the bytes exchanged since the last tick into the rates, called every second.
+    pub fn update_rate(&mut self) {
+        self.download_rate = (self.download_rate + self.received) / 2;
+        self.received = 0;
+        self.upload_rate = (self.upload_rate + self.sent) / 2;
+        self.sent = 0;
+    }
+
+    /// Whether the peer unchokes us but did not send any of the requested blocks for a while.
+    pub fn is_snubbed(&self, now: Instant) -> bool {
+        let requested = self.piece.is_some() || !self.duplicates.is_empty();
+        self.state.can_download()
+            && requested
+            && now.saturating_duration_since(self.last_block) >= SNUB_TIMEOUT
+    }
+
+    /// Sends block requests, restarting the snub timer if none were outstanding.
+    pub fn request(&mut self, blocks: Vec<Blockinfo>) {
+        if self.piece.is_none() && self.duplicates.is_empty() {
+            self.last_block = Instant::now();
+        }
+        self.send(PeerCommand::Request(blocks));
+    }
+
+    /// Replaces the known pieces with a `bitfield` message, spare bits are ignored.
+    pub fn set_bitfield(&mut self, bitfield: &[u8]) {
+        for (index, have) in self.pieces.iter_mut().enumerate() {
+            *have = bitfield
+                .get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0);
         }
     }
 }
@@ -19,3 +114,12 @@ impl Sha1Hash for Peer {
         self.peer_info.id
     }
 }
+
+/// Packs pieces into the bitfield message format, the first piece is the high bit.
+pub(crate) fn to_bitfield(pieces: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0; pieces.len().div_ceil(8)];
+    for (index, _) in pieces.iter().enumerate().filter(|(_, have)| **have) {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    bitfield
+}
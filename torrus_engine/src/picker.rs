@@ -0,0 +1,278 @@
+use rand::{seq::SliceRandom, Rng};
+
+/// Order in which pieces are picked once urgent and partially downloaded pieces are taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PickStrategy {
+    #[default]
+    RarestFirst,
+    /// Lowest index first, for playing media while it downloads.
+    Sequential,
+}
+
+/// Chooses which piece to download next, rarest first.
+///
+/// Pieces which are not downloaded yet are kept in `order`, sorted by how many peers have them.
+/// `starts[n]` is the position of the first piece in `order` which at least `n` peers have, so
+/// updating the availability of a piece only swaps it to the edge of its bucket.
+pub struct PiecePicker {
+    /// Number of connected peers which have each piece.
+    availability: Vec<u32>,
+    order: Vec<usize>,
+    /// Position of each piece in `order`, `None` once the piece is downloaded.
+    positions: Vec<Option<usize>>,
+    starts: Vec<usize>,
+    /// Pieces which are downloaded or verified right now and should not be picked again.
+    busy: Vec<bool>,
+    num_busy: usize,
+    /// Pieces with some blocks downloaded but nobody downloading the rest.
+    partial: Vec<usize>,
+    /// Pieces picked before any other, in order.
+    urgent: Vec<usize>,
+    strategy: PickStrategy,
+    /// Every piece before this one is downloaded, where sequential picking starts looking.
+    first_wanted: usize,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize) -> Self {
+        let mut order: Vec<usize> = (0..num_pieces).collect();
+        // Pieces are shuffled once, ties between equally rare pieces are random from there on.
+        order.shuffle(&mut rand::thread_rng());
+        let mut positions = vec![None; num_pieces];
+        for (position, index) in order.iter().enumerate() {
+            positions[*index] = Some(position);
+        }
+
+        Self {
+            availability: vec![0; num_pieces],
+            order,
+            positions,
+            starts: vec![0],
+            busy: vec![false; num_pieces],
+            num_busy: 0,
+            partial: Vec::new(),
+            urgent: Vec::new(),
+            strategy: PickStrategy::default(),
+            first_wanted: 0,
+        }
+    }
+
+    pub fn strategy(&self) -> PickStrategy {
+        self.strategy
+    }
+
+    pub fn set_strategy(&mut self, strategy: PickStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Replaces the pieces which are picked before any other, the first one is the most urgent.
+    pub fn set_urgent(&mut self, pieces: Vec<usize>) {
+        self.urgent = pieces;
+        self.urgent
+            .retain(|index| self.positions.get(*index).is_some_and(Option::is_some));
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.availability.len()
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    /// Whether the piece still needs to be downloaded.
+    pub fn wants(&self, index: usize) -> bool {
+        self.positions.get(index).is_some_and(Option::is_some)
+    }
+
+    /// Whether every piece which still needs to be downloaded is picked.
+    pub fn all_picked(&self) -> bool {
+        self.num_busy == self.order.len()
+    }
+
+    /// Counts the pieces of a peer, usually from its `bitfield` message.
+    pub fn add_peer(&mut self, pieces: &[bool]) {
+        for (index, _) in pieces.iter().enumerate().filter(|(_, have)| **have) {
+            self.increment(index);
+        }
+    }
+
+    /// Forgets the pieces of a disconnected peer.
+    pub fn remove_peer(&mut self, pieces: &[bool]) {
+        for (index, _) in pieces.iter().enumerate().filter(|(_, have)| **have) {
+            self.decrement(index);
+        }
+    }
+
+    /// Counts a piece a peer announced with a `have` message.
+    pub fn add_have(&mut self, index: usize) {
+        if index < self.num_pieces() {
+            self.increment(index);
+        }
+    }
+
+    /// Marks a piece as downloaded, it is never picked again.
+    pub fn set_have(&mut self, index: usize) {
+        let Some(mut at) = self.positions.get(index).copied().flatten() else {
+            return;
+        };
+        self.set_busy(index, false);
+        self.partial.retain(|partial| *partial != index);
+        self.urgent.retain(|urgent| *urgent != index);
+
+        // Moves the piece into every bucket above its own until it is the last one in `order`.
+        let availability = self.availability[index] as usize;
+        for bucket in availability + 1..self.starts.len() {
+            let last = self.starts[bucket] - 1;
+            self.swap(at, last);
+            self.starts[bucket] = last;
+            at = last;
+        }
+        let last = self.order.len() - 1;
+        self.swap(at, last);
+        self.order.pop();
+        self.positions[index] = None;
+    }
+
+    /// Picks a piece the peer has. Urgent pieces come first, then pieces which were already
+    /// started, then the rest following the [PickStrategy].
+    ///
+    /// The picked piece is not handed out again until it is [released](Self::release) or
+    /// [downloaded](Self::set_have).
+    pub fn pick(&mut self, peer: &[bool]) -> Option<usize> {
+        let has = |index: usize| peer.get(index).copied().unwrap_or(false);
+
+        let urgent = self
+            .urgent
+            .iter()
+            .copied()
+            .find(|index| !self.busy[*index] && has(*index));
+        if let Some(index) = urgent {
+            self.partial.retain(|partial| *partial != index);
+            self.set_busy(index, true);
+            return Some(index);
+        }
+
+        let partial = self
+            .partial
+            .iter()
+            .copied()
+            .filter(|index| has(*index))
+            .min_by_key(|index| self.availability[*index]);
+        if let Some(index) = partial {
+            self.partial.retain(|partial| *partial != index);
+            self.set_busy(index, true);
+            return Some(index);
+        }
+
+        let picked = match self.strategy {
+            PickStrategy::RarestFirst => self.rarest(has),
+            PickStrategy::Sequential => self.sequential(has),
+        };
+        if let Some(index) = picked {
+            self.set_busy(index, true);
+        }
+        picked
+    }
+
+    fn rarest(&self, has: impl Fn(usize) -> bool) -> Option<usize> {
+        // Pieces nobody has are skipped, the peer has at least one copy of what it can give.
+        let mut rng = rand::thread_rng();
+        for bucket in 1..self.starts.len() {
+            let start = self.starts[bucket];
+            let end = self
+                .starts
+                .get(bucket + 1)
+                .copied()
+                .unwrap_or(self.order.len());
+            if start == end {
+                continue;
+            }
+            // Starts at a random position so peers with the same pieces spread out.
+            let offset = rng.gen_range(0..end - start);
+            let found = (0..end - start)
+                .map(|n| self.order[start + (offset + n) % (end - start)])
+                .find(|index| !self.busy[*index] && has(*index));
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    fn sequential(&mut self, has: impl Fn(usize) -> bool) -> Option<usize> {
+        while self.first_wanted < self.num_pieces() && !self.wants(self.first_wanted) {
+            self.first_wanted += 1;
+        }
+        (self.first_wanted..self.num_pieces())
+            .find(|index| self.wants(*index) && !self.busy[*index] && has(*index))
+    }
+
+    /// Picks a specific piece, returns false if it is downloaded or already picked.
+    pub fn pick_piece(&mut self, index: usize) -> bool {
+        if !self.wants(index) || self.busy[index] {
+            return false;
+        }
+        self.partial.retain(|partial| *partial != index);
+        self.set_busy(index, true);
+        true
+    }
+
+    /// Makes a picked piece available again, `partial` pieces are picked before any other.
+    pub fn release(&mut self, index: usize, partial: bool) {
+        if !self.wants(index) {
+            return;
+        }
+        self.set_busy(index, false);
+        if partial && !self.partial.contains(&index) {
+            self.partial.push(index);
+        }
+    }
+
+    fn set_busy(&mut self, index: usize, busy: bool) {
+        if self.busy[index] != busy {
+            self.busy[index] = busy;
+            if busy {
+                self.num_busy += 1;
+            } else {
+                self.num_busy -= 1;
+            }
+        }
+    }
+
+    fn increment(&mut self, index: usize) {
+        let availability = self.availability[index] as usize;
+        self.availability[index] += 1;
+        if self.starts.len() == availability + 1 {
+            self.starts.push(self.order.len());
+        }
+        let Some(at) = self.positions[index] else {
+            return;
+        };
+        // The last piece of the bucket becomes the first one of the next bucket.
+        let last = self.starts[availability + 1] - 1;
+        self.swap(at, last);
+        self.starts[availability + 1] = last;
+    }
+
+    fn decrement(&mut self, index: usize) {
+        let availability = self.availability[index] as usize;
+        if availability == 0 {
+            return;
+        }
+        self.availability[index] -= 1;
+        let Some(at) = self.positions[index] else {
+            return;
+        };
+        // The first piece of the bucket becomes the last one of the previous bucket.
+        let first = self.starts[availability];
+        self.swap(at, first);
+        self.starts[availability] = first + 1;
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.order.swap(a, b);
+        self.positions[self.order[a]] = Some(a);
+        self.positions[self.order[b]] = Some(b);
+    }
+}
@@ -0,0 +1,1401 @@
+use crate::{
+    choker::{Choker, ChokerPeer, CHOKE_INTERVAL},
+    metadata::Resolved,
+    peer::to_bitfield,
+    Peer, PeerCommand, PeerConnection, PeerEvent, PickStrategy, PiecePicker,
+};
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{interval, Instant},
+};
+use tokio_util::codec::Framed;
+use torrus_core::{
+    prelude::{
+        Block, Blockinfo, ChokeStatus, ClientInfo, HashRequest, IntrestStatus, MetaVersion,
+        Metainfo, PeerInfo, Sha1Hash, ID,
+    },
+    store::Store,
+};
+use torrus_storage::{
+    merkle::{Hash, TorrentHashes},
+    piece::{self, Piece, PieceInfo, BLOCK_SIZE},
+};
+use torrus_tracker::{AnnounceStats, Announcer, AnnouncerHandle, TrackerList, TrackerRequest};
+use torrus_wire::{Handshake, HandshakeCodec, MetadataMessage, METADATA_PIECE_SIZE};
+
+/// Peers which sent this many corrupt pieces are banned.
+const MAX_HASH_FAILURES: usize = 2;
+
+/// Requests a peer may have queued with us, further requests are ignored.
+pub(crate) const MAX_REQUEST_QUEUE: usize = 256;
+
+/// Interval at which peer rates are updated and piece deadlines are checked.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lifecycle state of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Added from a magnet link, the info dictionary is downloaded from peers first.
+    FetchingMetadata,
+    /// Preparing storage and checking which pieces are already on disk.
+    Checking,
+    Downloading,
+    /// Every piece is downloaded, the torrent only uploads.
+    Seeding,
+    Paused,
+    /// The torrent stopped because of an error, resuming tries again.
+    Error(String),
+}
+
+/// Snapshot of a torrent published by its task.
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: ID,
+    pub name: String,
+    pub state: TorrentState,
+    pub num_pieces: usize,
+    pub pieces_done: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub left: u64,
+    pub peers: usize,
+    /// Downloaded pieces which did not match their hash.
+    pub hash_failures: usize,
+    /// Whether the last blocks are requested from several peers at once.
+    pub endgame: bool,
+    /// Bytes of blocks which arrived more than once.
+    pub wasted: u64,
+}
+
+pub(crate) enum TorrentCommand {
+    Pause,
+    Resume,
+    AddPeers(Vec<SocketAddr>),
+    SetStrategy(PickStrategy),
+    SetDeadlines(Vec<(usize, Duration)>),
+    Incoming(Box<Incoming>),
+    Shutdown,
+}
+
+/// Connection a peer opened to us, its handshake is already read.
+pub(crate) struct Incoming {
+    pub addr: SocketAddr,
+    pub framed: Framed<TcpStream, HandshakeCodec>,
+    pub handshake: Handshake,
+}
+
+/// Settings shared by every torrent of an engine.
+#[derive(Clone, Copy)]
+pub(crate) struct TorrentConfig {
+    pub peer_id: ID,
+    pub port: u16,
+    pub max_peers: usize,
+    pub upload_slots: usize,
+}
+
+/// Everything that only exists while the torrent is running, dropped on pause.
+struct Session {
+    announcer: AnnouncerHandle,
+    addrs: mpsc::Receiver<Vec<SocketAddr>>,
+    events_tx: mpsc::Sender<(SocketAddr, PeerEvent)>,
+    events: mpsc::Receiver<(SocketAddr, PeerEvent)>,
+    peers: HashMap<SocketAddr, Peer>,
+}
+
+/// A piece being downloaded, its blocks may come from several peers.
+struct Download {
+    piece: Piece,
+    /// Peer which sent each written block, by offset.
+    senders: BTreeMap<u64, SocketAddr>,
+}
+
+/// Outcome of hashing a downloaded piece on the blocking pool.
+struct Verified {
+    index: usize,
+    /// Peer which sent each block of the piece, by offset.
+    senders: BTreeMap<u64, SocketAddr>,
+    /// Digest of every block, left empty for a valid piece which never failed before.
+    digests: Vec<ID>,
+    /// Data of a corrupt piece whose block hashes can point out the bad blocks.
+    data: Option<Vec<u8>>,
+    /// Whether the piece matched its hash and was written to the store.
+    result: Result<bool>,
+}
+
+/// Piece which failed the merkle check, kept while the hashes of its blocks are requested.
+struct Suspect {
+    data: Vec<u8>,
+    senders: BTreeMap<u64, SocketAddr>,
+}
+
+/// Block of a piece which failed the hash check, kept until the piece is valid to find out whether
+/// this block was the corrupt one.
+struct FailedBlock {
+    offset: u64,
+    digest: ID,
+    sender: SocketAddr,
+}
+
+/// Block read from the store for a peer which requested it.
+struct Served {
+    addr: SocketAddr,
+    block: Option<Block>,
+}
+
+/// Task running a single torrent, connects its trackers, peers and storage.
+pub(crate) struct Torrent<S> {
+    metainfo: Arc<Metainfo>,
+    /// Merkle hashes of hybrid torrents, pieces must match them as well.
+    hashes: Option<Arc<TorrentHashes>>,
+    /// Hash requests sent to peers and not answered yet, with the peer asked.
+    hash_requests: HashMap<HashRequest, SocketAddr>,
+    /// Hash requests peers rejected, they are not asked for the same hashes again.
+    hash_rejects: HashSet<(SocketAddr, HashRequest)>,
+    /// Pieces which failed the merkle check, waiting for the hashes of their blocks.
+    suspects: HashMap<usize, Suspect>,
+    info_hash: ID,
+    config: TorrentConfig,
+    store: Arc<Mutex<S>>,
+    commands: mpsc::UnboundedReceiver<TorrentCommand>,
+    status: watch::Sender<TorrentStatus>,
+    stats: watch::Sender<AnnounceStats>,
+    session: Option<Session>,
+    /// Peers to connect to once a connection slot frees up.
+    candidates: VecDeque<SocketAddr>,
+    /// Set by the user, a paused torrent does not start after checking.
+    paused: bool,
+    checked: bool,
+    checking: Option<JoinHandle<Result<Vec<bool>>>>,
+    verified_tx: mpsc::UnboundedSender<Verified>,
+    verified: mpsc::UnboundedReceiver<Verified>,
+    served_tx: mpsc::UnboundedSender<Served>,
+    served: mpsc::UnboundedReceiver<Served>,
+    /// Pieces which are downloaded and written to the store.
+    have: Vec<bool>,
+    /// Pieces currently downloaded, at most one per peer, and pieces left partially downloaded.
+    pieces: HashMap<usize, Download>,
+    picker: PiecePicker,
+    /// Set once every missing piece is downloaded by some peer.
+    endgame: bool,
+    /// Pieces needed by a certain time, ordered by their deadline.
+    deadlines: Vec<(usize, Instant)>,
+    choker: Choker,
+    last_rechoke: Instant,
+    /// Blocks of pieces which failed the hash check with blocks from several peers.
+    failed: HashMap<usize, Vec<FailedBlock>>,
+    /// Corrupt pieces each address sent, counted across connections.
+    strikes: HashMap<IpAddr, usize>,
+    /// Peers which repeatedly sent corrupt data.
+    banned: HashSet<IpAddr>,
+}
+
+impl<S> Torrent<S>
+where
+    S: Store + Send + 'static,
+    S::Err: Send + Sync + 'static,
+{
+    pub(crate) fn new(
+        metainfo: Metainfo,
+        config: TorrentConfig,
+        store: Arc<Mutex<S>>,
+        commands: mpsc::UnboundedReceiver<TorrentCommand>,
+    ) -> (Self, watch::Receiver<TorrentStatus>) {
+        let info_hash = metainfo.as_sha1();
+        let (status, rx) = watch::channel(initial_status(&metainfo, info_hash));
+        let torrent = Self::with_status(metainfo, info_hash, config, store, commands, status);
+        (torrent, rx)
+    }
+
+    /// Takes over a torrent added from a magnet link once its info dictionary is known.
+    pub(crate) fn from_magnet(
+        resolved: Resolved,
+        config: TorrentConfig,
+        store: Arc<Mutex<S>>,
+    ) -> Self {
+        let info_hash = resolved.info_hash;
+        resolved
+            .status
+            .send_replace(initial_status(&resolved.metainfo, info_hash));
+        let mut torrent = Self::with_status(
+            resolved.metainfo,
+            info_hash,
+            config,
+            store,
+            resolved.commands,
+            resolved.status,
+        );
+        // Peers which had the metadata most likely have pieces as well.
+        torrent.candidates.extend(resolved.peers);
+        torrent.picker.set_strategy(resolved.strategy);
+        torrent.set_deadlines(resolved.deadlines);
+        torrent
+    }
+
+    fn with_status(
+        metainfo: Metainfo,
+        info_hash: ID,
+        config: TorrentConfig,
+        store: Arc<Mutex<S>>,
+        commands: mpsc::UnboundedReceiver<TorrentCommand>,
+        status: watch::Sender<TorrentStatus>,
+    ) -> Self {
+        let num_pieces = metainfo.info.num_pieces();
+        let total_length = metainfo.info.total_length();
+        let (stats, _) = watch::channel(AnnounceStats {
+            left: total_length,
+            ..Default::default()
+        });
+        let (verified_tx, verified) = mpsc::unbounded_channel();
+        let (served_tx, served) = mpsc::unbounded_channel();
+        // Invalid hashes are rejected when adding the torrent, those of a torrent from a magnet
+        // link only lack the piece layers.
+        let hashes = match metainfo.info.version() {
+            MetaVersion::Hybrid => TorrentHashes::new(&metainfo).ok().map(Arc::new),
+            _ => None,
+        };
+
+        Self {
+            metainfo: Arc::new(metainfo),
+            hashes,
+            hash_requests: HashMap::new(),
+            hash_rejects: HashSet::new(),
+            suspects: HashMap::new(),
+            info_hash,
+            config,
+            store,
+            commands,
+            status,
+            stats,
+            session: None,
+            candidates: VecDeque::new(),
+            paused: false,
+            checked: false,
+            checking: None,
+            verified_tx,
+            verified,
+            served_tx,
+            served,
+            have: vec![false; num_pieces],
+            pieces: HashMap::new(),
+            picker: PiecePicker::new(num_pieces),
+            endgame: false,
+            deadlines: Vec::new(),
+            choker: Choker::new(config.upload_slots),
+            last_rechoke: Instant::now(),
+            failed: HashMap::new(),
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+        }
+    }
+
+    pub(crate) async fn run(mut self) {
+        self.start();
+        let mut tick = interval(TICK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(TorrentCommand::Pause) => {
+                        self.paused = true;
+                        self.stop();
+                        self.set_state(TorrentState::Paused);
+                    }
+                    Some(TorrentCommand::Resume) => {
+                        self.paused = false;
+                        if self.session.is_none() && self.checking.is_none() {
+                            self.start();
+                        }
+                    }
+                    Some(TorrentCommand::AddPeers(addrs)) => self.add_peers(addrs),
+                    Some(TorrentCommand::SetStrategy(strategy)) => {
+                        self.picker.set_strategy(strategy);
+                    }
+                    Some(TorrentCommand::SetDeadlines(deadlines)) => self.set_deadlines(deadlines),
+                    Some(TorrentCommand::Incoming(incoming)) => self.accept_peer(*incoming),
+                    Some(TorrentCommand::Shutdown) | None => {
+                        self.stop();
+                        if let Some(checking) = self.checking.take() {
+                            checking.abort();
+                        }
+                        return;
+                    }
+                },
+                checked = wait_checked(self.checking.as_mut()) => {
+                    self.checking = None;
+                    match checked {
+                        Ok(have) => {
+                            self.checked = true;
+                            self.set_have(have);
+                            if self.paused {
+                                self.set_state(TorrentState::Paused);
+                            } else {
+                                self.start_session();
+                            }
+                        }
+                        Err(e) => self.set_state(TorrentState::Error(e.to_string())),
+                    }
+                }
+                Some(served) = self.served.recv() => self.handle_served(served),
+                Some(verified) = self.verified.recv() => {
+                    if let Err(e) = self.handle_verified(verified) {
+                        self.stop();
+                        self.set_state(TorrentState::Error(e.to_string()));
+                    }
+                }
+                event = next_event(self.session.as_mut()) => match event {
+                    SessionEvent::Peers(addrs) => self.add_peers(addrs),
+                    SessionEvent::Peer(addr, event) => self.handle_event(addr, event),
+                },
+                _ = tick.tick() => self.tick(),
+            }
+        }
+    }
+
+    /// Checks the pieces already in the store the first time, then starts the session.
+    fn start(&mut self) {
+        if self.checked {
+            self.start_session();
+            return;
+        }
+
+        self.set_state(TorrentState::Checking);
+        let store = self.store.clone();
+        let metainfo = self.metainfo.clone();
+        let id = self.info_hash;
+        self.checking = Some(tokio::task::spawn_blocking(move || {
+            check(&store, &metainfo, id)
+        }));
+    }
+
+    /// Starts announcing and connecting to peers.
+    fn start_session(&mut self) {
+        let request = TrackerRequest::builder()
+            .info_hash(self.info_hash)
+            .set_peer_id(self.config.peer_id)
+            .set_port(self.config.port);
+        let (addrs_tx, addrs) = mpsc::channel(8);
+        let (announcer, handle) = Announcer::new(
+            TrackerList::from_metainfo(&self.metainfo),
+            request,
+            self.stats.subscribe(),
+            addrs_tx,
+        );
+        tokio::spawn(announcer.run());
+
+        let (events_tx, events) = mpsc::channel(256);
+        self.session = Some(Session {
+            announcer: handle,
+            addrs,
+            events_tx,
+            events,
+            peers: HashMap::new(),
+        });
+        self.connect_peers();
+
+        if self.is_complete() {
+            self.set_state(TorrentState::Seeding);
+        } else {
+            self.set_state(TorrentState::Downloading);
+        }
+    }
+
+    /// Disconnects every peer and sends `stopped` to the trackers.
+    fn stop(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.announcer.stop();
+            for peer in session.peers.values() {
+                peer.send(PeerCommand::Shutdown);
+                self.picker.remove_peer(&peer.pieces);
+            }
+        }
+        // Partially downloaded pieces are lost along with their peers.
+        let suspects = self.suspects.drain().map(|(index, _)| index);
+        for index in self.pieces.drain().map(|(index, _)| index).chain(suspects) {
+            self.picker.release(index, false);
+        }
+        self.hash_requests.clear();
+        self.hash_rejects.clear();
+        self.update_endgame();
+        self.candidates.clear();
+        self.publish();
+    }
+
+    fn set_have(&mut self, have: Vec<bool>) {
+        let left = (0..have.len())
+            .filter(|index| !have[*index])
+            .map(|index| self.metainfo.info.piece_size(index))
+            .sum();
+        for (index, _) in have.iter().enumerate().filter(|(_, have)| **have) {
+            self.picker.set_have(index);
+        }
+        self.have = have;
+        self.stats.send_modify(|stats| stats.left = left);
+    }
+
+    /// Queues peers to connect to, peers added while checking are connected afterwards.
+    fn add_peers(&mut self, addrs: Vec<SocketAddr>) {
+        if self.paused {
+            return;
+        }
+        for addr in addrs {
+            let connected = self
+                .session
+                .as_ref()
+                .is_some_and(|s| s.peers.contains_key(&addr));
+            if !connected && !self.candidates.contains(&addr) && !self.banned.contains(&addr.ip()) {
+                self.candidates.push_back(addr);
+            }
+        }
+        self.connect_peers();
+    }
+
+    /// Connects to candidates until the peer limit is reached.
+    fn connect_peers(&mut self) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        while session.peers.len() < self.config.max_peers {
+            let Some(addr) = self.candidates.pop_front() else {
+                break;
+            };
+            let peer_info = PeerInfo::new(addr);
+            let (connection, handle) = PeerConnection::new(
+                peer_info,
+                self.info_hash,
+                self.config.peer_id,
+                session.events_tx.clone(),
+            );
+            let mut connection = connection
+                .with_metadata_size(self.metainfo.info_bytes().len() as u64)
+                .with_listen_port(self.config.port);
+            if self.hashes.is_some() {
+                connection = connection.with_v2();
+            }
+            tokio::spawn(connection.run());
+            session
+                .peers
+                .insert(addr, Peer::new(peer_info, handle, self.have.len()));
+        }
+        self.publish();
+    }
+
+    /// Takes over a connection a peer opened, unless the torrent is stopped or full.
+    fn accept_peer(&mut self, incoming: Incoming) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        let addr = incoming.addr;
+        if session.peers.len() >= self.config.max_peers
+            || session.peers.contains_key(&addr)
+            || self.banned.contains(&addr.ip())
+        {
+            return;
+        }
+
+        let mut peer_info = PeerInfo::new(addr);
+        peer_info.id = incoming.handshake.peer_id;
+        let (connection, handle) = PeerConnection::new(
+            peer_info,
+            self.info_hash,
+            self.config.peer_id,
+            session.events_tx.clone(),
+        );
+        let mut connection = connection
+            .with_metadata_size(self.metainfo.info_bytes().len() as u64)
+            .with_listen_port(self.config.port);
+        if self.hashes.is_some() {
+            connection = connection.with_v2();
+        }
+        tokio::spawn(connection.run_accepted(incoming.framed, incoming.handshake));
+        session
+            .peers
+            .insert(addr, Peer::new(peer_info, handle, self.have.len()));
+        self.publish();
+    }
+
+    fn handle_event(&mut self, addr: SocketAddr, event: PeerEvent) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+
+        if let PeerEvent::Disconnected = event {
+            if let Some(peer) = session.peers.remove(&addr) {
+                self.picker.remove_peer(&peer.pieces);
+                for index in peer.piece.into_iter().chain(peer.duplicates) {
+                    self.release_piece(index);
+                }
+            }
+            // Hashes the peer was asked for are asked from another one.
+            self.hash_rejects.retain(|(rejecter, _)| *rejecter != addr);
+            let unanswered: Vec<_> = self
+                .hash_requests
+                .iter()
+                .filter(|(_, asked)| **asked == addr)
+                .map(|(request, _)| *request)
+                .collect();
+            for request in unanswered {
+                self.hash_requests.remove(&request);
+                self.retry_hashes(request);
+            }
+            self.update_endgame();
+            self.connect_peers();
+            self.assign_pieces();
+            return;
+        }
+
+        let Some(peer) = session.peers.get_mut(&addr) else {
+            return;
+        };
+
+        match event {
+            PeerEvent::Connected(id) => {
+                peer.peer_info.id = id;
+                if self.have.iter().any(|have| *have) {
+                    peer.send(PeerCommand::Bitfield(to_bitfield(&self.have)));
+                }
+                self.request_piece_layers();
+            }
+            PeerEvent::StateChanged(state) => {
+                peer.state = state;
+                // Choking a peer discards its requests.
+                if state.am_choking == ChokeStatus::Chocked {
+                    peer.uploads.clear();
+                }
+                self.fill_free_slot(addr);
+            }
+            PeerEvent::Have(index) => {
+                let index = index as usize;
+                if !peer.has(index) {
+                    peer.set_have(index);
+                    self.picker.add_have(index);
+                }
+            }
+            PeerEvent::Bitfield(bitfield) => {
+                self.picker.remove_peer(&peer.pieces);
+                peer.set_bitfield(&bitfield);
+                self.picker.add_peer(&peer.pieces);
+            }
+            PeerEvent::Block(block) => {
+                peer.last_block = Instant::now();
+                self.write_block(addr, block);
+            }
+            PeerEvent::Request(block_info) => self.queue_upload(addr, block_info),
+            PeerEvent::Cancel(block_info) => peer.uploads.retain(|queued| *queued != block_info),
+            PeerEvent::Metadata(MetadataMessage::Request(piece)) => {
+                self.serve_metadata(addr, piece)
+            }
+            PeerEvent::ExtendedHandshake(handshake) => {
+                peer.client = ClientInfo {
+                    name: handshake.client,
+                    request_queue: handshake.request_queue.map(|queue| queue as usize),
+                    listen_port: handshake.port,
+                };
+            }
+            PeerEvent::Metadata(_) => {}
+            PeerEvent::HashRequest(request) => {
+                let answer = self
+                    .hashes
+                    .as_ref()
+                    .and_then(|hashes| hashes.answer(&request));
+                peer.send(match answer {
+                    Some(hashes) => PeerCommand::Hashes { request, hashes },
+                    None => PeerCommand::HashReject(request),
+                });
+            }
+            PeerEvent::Hashes { request, hashes } => self.add_hashes(addr, request, hashes),
+            PeerEvent::HashReject(request) => self.reject_hashes(addr, request),
+            PeerEvent::Disconnected => unreachable!(),
+        }
+
+        self.assign_piece(addr);
+    }
+
+    fn assign_pieces(&mut self) {
+        let addrs: Vec<_> = match self.session.as_ref() {
+            Some(session) => session.peers.keys().copied().collect(),
+            None => return,
+        };
+        for addr in addrs {
+            self.assign_piece(addr);
+        }
+    }
+
+    /// Starts downloading a piece from the peer if it has none assigned, in endgame the peer
+    /// helps with the pieces other peers are downloading instead.
+    fn assign_piece(&mut self, addr: SocketAddr) {
+        let Some(session) = self.session.as_ref() else {
+            return;
+        };
+        let Some(peer) = session.peers.get(&addr) else {
+            return;
+        };
+        if peer.piece.is_some() || !peer.state.can_download() {
+            return;
+        }
+
+        let picked = match self.hashes.as_deref() {
+            // Pieces are not picked before their hash is known.
+            Some(hashes) if !hashes.has_piece_layers() => {
+                let pieces: Vec<_> = (0..peer.pieces.len())
+                    .map(|index| peer.has(index) && hashes.can_verify(index))
+                    .collect();
+                self.picker.pick(&pieces)
+            }
+            _ => self.picker.pick(&peer.pieces),
+        };
+        let Some(index) = picked else {
+            if self.endgame {
+                self.request_duplicates(addr);
+            }
+            return;
+        };
+
+        let blocks = self.piece(index).missing_blocks().collect();
+
+        if let Some(peer) = self.session.as_mut().and_then(|s| s.peers.get_mut(&addr)) {
+            peer.request(blocks);
+            peer.piece = Some(index);
+        }
+        self.update_endgame();
+    }
+
+    /// Requests the missing blocks of every piece in progress the peer has and was not asked for.
+    fn request_duplicates(&mut self, addr: SocketAddr) {
+        let indices: Vec<_> = self.pieces.keys().copied().collect();
+        for index in indices {
+            self.request_duplicate(addr, index);
+        }
+    }
+
+    /// Requests the missing blocks of a piece from a peer next to the ones already asked.
+    fn request_duplicate(&mut self, addr: SocketAddr, index: usize) {
+        let Some(peer) = self.session.as_mut().and_then(|s| s.peers.get_mut(&addr)) else {
+            return;
+        };
+        let Some(download) = self.pieces.get(&index) else {
+            return;
+        };
+        if peer.has(index) && !peer.is_requesting(index) {
+            peer.request(download.piece.missing_blocks().collect());
+            peer.duplicates.push(index);
+        }
+    }
+
+    /// Hands a piece back to the picker once no peer downloads it anymore.
+    fn release_piece(&mut self, index: usize) {
+        let requested = self
+            .session
+            .as_ref()
+            .is_some_and(|session| session.peers.values().any(|peer| peer.is_requesting(index)));
+        if requested {
+            return;
+        }
+        // Blocks already downloaded are kept for the next peer picking the piece, along with
+        // the peers which sent them.
+        let partial = self
+            .pieces
+            .get(&index)
+            .is_some_and(|download| download.piece.size() > 0);
+        if !partial {
+            self.pieces.remove(&index);
+        }
+        self.picker.release(index, partial);
+    }
+
+    /// Enters endgame once every missing piece is picked and leaves it when a piece frees up.
+    fn update_endgame(&mut self) {
+        let endgame = self.session.is_some() && !self.is_complete() && self.picker.all_picked();
+        if endgame == self.endgame {
+            return;
+        }
+        self.endgame = endgame;
+        self.status.send_modify(|status| status.endgame = endgame);
+        if endgame {
+            self.assign_pieces();
+        }
+    }
+
+    fn forget_duplicates(&mut self, index: usize) {
+        if let Some(session) = self.session.as_mut() {
+            for peer in session.peers.values_mut() {
+                peer.duplicates.retain(|duplicate| *duplicate != index);
+            }
+        }
+    }
+
+    /// The piece in progress at `index`, started if it was not yet.
+    fn piece(&mut self, index: usize) -> &mut Piece {
+        let info = &self.metainfo.info;
+        let download = self.pieces.entry(index).or_insert_with(|| {
+            let hash = info.piece_hash(index).unwrap();
+            Download {
+                piece: Piece::new(PieceInfo::new(index, info.piece_size(index), hash)),
+                senders: BTreeMap::new(),
+            }
+        });
+        &mut download.piece
+    }
+
+    fn write_block(&mut self, addr: SocketAddr, block: Block) {
+        let block_info = block.block_info;
+        let index = block_info.index;
+        let length = block.len() as u64;
+
+        // Blocks of finished pieces, duplicates and blocks which do not fit are dropped.
+        let written = self.pieces.get_mut(&index).is_some_and(|download| {
+            let written = download.piece.write(block).is_ok();
+            if written {
+                download.senders.insert(block_info.offset, addr);
+            }
+            written
+        });
+        if !written {
+            self.status.send_modify(|status| status.wasted += length);
+            return;
+        }
+        self.stats.send_modify(|stats| stats.downloaded += length);
+        if let Some(peer) = self.session.as_mut().and_then(|s| s.peers.get_mut(&addr)) {
+            peer.received += length;
+        }
+        self.cancel_duplicates(addr, block_info);
+
+        if self.pieces[&index].piece.is_full() {
+            let download = self.pieces.remove(&index).unwrap();
+            self.forget_duplicates(index);
+
+            // Whoever downloaded the piece is free to pick another one.
+            let owners: Vec<_> = self
+                .session
+                .iter_mut()
+                .flat_map(|session| session.peers.iter_mut())
+                .filter(|(_, peer)| peer.piece == Some(index))
+                .map(|(owner, peer)| {
+                    peer.piece = None;
+                    *owner
+                })
+                .collect();
+            self.verify(index, download);
+            for owner in owners.into_iter().filter(|owner| *owner != addr) {
+                self.assign_piece(owner);
+            }
+        }
+        self.publish();
+    }
+
+    /// Cancels a block which arrived at every other peer it was requested from.
+    fn cancel_duplicates(&self, addr: SocketAddr, block_info: Blockinfo) {
+        let Some(session) = self.session.as_ref() else {
+            return;
+        };
+        let index = block_info.index;
+        for (other, peer) in &session.peers {
+            if *other != addr && peer.is_requesting(index) {
+                peer.send(PeerCommand::Cancel(block_info));
+            }
+        }
+    }
+
+    /// Replaces the piece deadlines, urgent pieces are picked before any other.
+    fn set_deadlines(&mut self, deadlines: Vec<(usize, Duration)>) {
+        let now = Instant::now();
+        self.deadlines = deadlines
+            .into_iter()
+            .filter(|(index, _)| !self.have.get(*index).copied().unwrap_or(true))
+            .map(|(index, budget)| (index, now + budget))
+            .collect();
+        self.deadlines.sort_by_key(|(_, deadline)| *deadline);
+        self.picker
+            .set_urgent(self.deadlines.iter().map(|(index, _)| *index).collect());
+        self.assign_pieces();
+    }
+
+    fn tick(&mut self) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        for peer in session.peers.values_mut() {
+            peer.update_rate();
+        }
+        let now = Instant::now();
+        self.check_deadlines(now);
+        if now.duration_since(self.last_rechoke) >= CHOKE_INTERVAL {
+            self.last_rechoke = now;
+            self.rechoke(now);
+        }
+    }
+
+    /// Unchokes an interested peer right away while upload slots are free, instead of waiting
+    /// for the next round.
+    fn fill_free_slot(&self, addr: SocketAddr) {
+        let Some(session) = self.session.as_ref() else {
+            return;
+        };
+        let Some(peer) = session.peers.get(&addr) else {
+            return;
+        };
+        let unchoked = session
+            .peers
+            .values()
+            .filter(|peer| peer.state.am_choking == ChokeStatus::NotChocked)
+            .count();
+        if unchoked < self.choker.upload_slots()
+            && peer.state.peer_interested == IntrestStatus::Interested
+            && peer.state.am_choking == ChokeStatus::Chocked
+        {
+            peer.send(PeerCommand::Unchoke);
+        }
+    }
+
+    /// Queues a block the peer requested if we have its piece and the peer's queue has room.
+    fn queue_upload(&mut self, addr: SocketAddr, block_info: Blockinfo) {
+        let index = block_info.index;
+        let valid = self.have.get(index).copied().unwrap_or(false)
+            && block_info.length > 0
+            && block_info.length <= BLOCK_SIZE
+            && block_info.offset + block_info.length <= self.metainfo.info.piece_size(index);
+        let Some(peer) = self.session.as_mut().and_then(|s| s.peers.get_mut(&addr)) else {
+            return;
+        };
+        if valid && peer.uploads.len() < MAX_REQUEST_QUEUE {
+            peer.uploads.push_back(block_info);
+            self.serve(addr);
+        }
+    }
+
+    /// Reads the next block the peer requested on the blocking pool, one block at a time.
+    fn serve(&mut self, addr: SocketAddr) {
+        let Some(peer) = self.session.as_mut().and_then(|s| s.peers.get_mut(&addr)) else {
+            return;
+        };
+        if peer.reading {
+            return;
+        }
+        let Some(block_info) = peer.uploads.pop_front() else {
+            return;
+        };
+        peer.reading = true;
+
+        let store = self.store.clone();
+        let id = self.info_hash;
+        let served_tx = self.served_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let block = store.lock().unwrap().get_block(id, block_info);
+            let _ = served_tx.send(Served { addr, block });
+        });
+    }
+
+    /// Sends a piece of the info dictionary, private torrents only hand it out through
+    /// their torrent file.
+    fn serve_metadata(&self, addr: SocketAddr, piece: u32) {
+        let Some(peer) = self.session.as_ref().and_then(|s| s.peers.get(&addr)) else {
+            return;
+        };
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        let metadata = self.metainfo.info_bytes();
+        let message = match metadata.get(start..) {
+            Some(data) if !data.is_empty() && !self.metainfo.info.is_private() => {
+                MetadataMessage::Data {
+                    piece,
+                    total_size: metadata.len() as u64,
+                    data: data[..data.len().min(METADATA_PIECE_SIZE)].to_vec(),
+                }
+            }
+            _ => MetadataMessage::Reject(piece),
+        };
+        peer.send(PeerCommand::Metadata(message));
+    }
+
+    fn handle_served(&mut self, served: Served) {
+        let addr = served.addr;
+        let Some(peer) = self.session.as_mut().and_then(|s| s.peers.get_mut(&addr)) else {
+            return;
+        };
+        peer.reading = false;
+
+        if let Some(block) = served.block {
+            let length = block.len() as u64;
+            let unchoked = peer.state.am_choking == ChokeStatus::NotChocked;
+            if unchoked && peer.send(PeerCommand::Piece(block)) {
+                peer.sent += length;
+                self.stats.send_modify(|stats| stats.uploaded += length);
+                self.publish();
+            }
+        }
+        self.serve(addr);
+    }
+
+    /// Unchokes the peers chosen by the choker and chokes every other peer.
+    fn rechoke(&mut self, now: Instant) {
+        let Some(session) = self.session.as_ref() else {
+            return;
+        };
+        let peers: Vec<_> = session
+            .peers
+            .iter()
+            .map(|(addr, peer)| ChokerPeer {
+                addr: *addr,
+                download_rate: peer.download_rate,
+                upload_rate: peer.upload_rate,
+                interested: peer.state.peer_interested == IntrestStatus::Interested,
+                snubbed: peer.is_snubbed(now),
+                connected_at: peer.connected_at,
+            })
+            .collect();
+        let unchoked = self.choker.rechoke(&peers, self.is_complete(), now);
+
+        for (addr, peer) in &session.peers {
+            let choking = peer.state.am_choking == ChokeStatus::Chocked;
+            let unchoke = unchoked.contains(addr);
+            if unchoke && choking {
+                peer.send(PeerCommand::Unchoke);
+            } else if !unchoke && !choking {
+                peer.send(PeerCommand::Choke);
+            }
+        }
+    }
+
+    /// Requests pieces which would miss their deadline at the current rate from the fastest peer
+    /// not downloading them yet.
+    fn check_deadlines(&mut self, now: Instant) {
+        let Some(session) = self.session.as_ref() else {
+            return;
+        };
+
+        let mut requests = Vec::new();
+        for (index, deadline) in &self.deadlines {
+            let index = *index;
+            let downloaders = session.peers.values().filter(|p| p.is_requesting(index));
+            let rate: u64 = downloaders.map(|peer| peer.download_rate).sum();
+            let missing = self.metainfo.info.piece_size(index)
+                - self
+                    .pieces
+                    .get(&index)
+                    .map_or(0, |download| download.piece.size());
+            let budget = deadline.saturating_duration_since(now).as_secs_f64();
+            if (missing as f64) <= rate as f64 * budget {
+                continue;
+            }
+
+            let fastest = session
+                .peers
+                .iter()
+                .filter(|(_, peer)| {
+                    peer.state.can_download() && peer.has(index) && !peer.is_requesting(index)
+                })
+                .max_by_key(|(_, peer)| peer.download_rate);
+            if let Some((addr, _)) = fastest {
+                requests.push((*addr, index));
+            }
+        }
+
+        for (addr, index) in requests {
+            if !self.can_verify(index) {
+                continue;
+            }
+            // Pieces nobody downloads are taken from the picker, pieces being verified are skipped.
+            let picked = self.picker.pick_piece(index);
+            if !picked && !self.pieces.contains_key(&index) {
+                continue;
+            }
+            self.piece(index);
+            self.request_duplicate(addr, index);
+        }
+    }
+
+    /// Hashes a complete piece on the blocking pool and writes it to the store if it is valid.
+    fn verify(&mut self, index: usize, download: Download) {
+        let store = self.store.clone();
+        let id = self.info_hash;
+        let verified_tx = self.verified_tx.clone();
+        let Download { piece, senders } = download;
+        let failed_before = self.failed.contains_key(&index);
+        let hashes = self.hashes.clone();
+        tokio::task::spawn_blocking(move || {
+            let block_info = Blockinfo {
+                offset: 0,
+                length: piece.size(),
+                index,
+            };
+            let valid = piece.check_integrity();
+            let data = piece.get_raw_data();
+            let valid = valid
+                && hashes
+                    .as_ref()
+                    .is_none_or(|hashes| hashes.verify_piece(index, &data));
+            // Digests are only needed to compare blocks against another download of the piece.
+            let digests = if valid && !failed_before {
+                Vec::new()
+            } else {
+                piece::block_digests(&data)
+            };
+            let result = if valid {
+                let block = Block::new(&data, block_info);
+                let mut store = store.lock().unwrap();
+                store.put_block(id, block).map(|_| true).map_err(Into::into)
+            } else {
+                Ok(false)
+            };
+            let _ = verified_tx.send(Verified {
+                index,
+                senders,
+                digests,
+                data: hashes
+                    .is_some_and(|hashes| !valid && hashes.block_request(index).is_some())
+                    .then_some(data),
+                result,
+            });
+        });
+    }
+
+    fn handle_verified(&mut self, verified: Verified) -> Result<()> {
+        let index = verified.index;
+        let valid = verified
+            .result
+            .inspect_err(|_| self.picker.release(index, false))?;
+
+        if valid {
+            self.have[index] = true;
+            self.picker.set_have(index);
+            // Blocks which differ from the valid piece were the corrupt ones.
+            let corrupt: HashSet<_> = self
+                .failed
+                .remove(&index)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|block| {
+                    let number = (block.offset / BLOCK_SIZE) as usize;
+                    verified.digests.get(number) != Some(&block.digest)
+                })
+                .map(|block| block.sender)
+                .collect();
+            for addr in corrupt {
+                self.penalize(addr);
+            }
+            self.deadlines.retain(|(deadline, _)| *deadline != index);
+            let length = self.metainfo.info.piece_size(index);
+            self.stats.send_modify(|stats| stats.left -= length);
+
+            if let Some(session) = self.session.as_ref() {
+                for peer in session.peers.values() {
+                    peer.send(PeerCommand::Have(index as u32));
+                }
+                if self.is_complete() {
+                    session.announcer.completed();
+                    self.set_state(TorrentState::Seeding);
+                }
+            }
+            self.update_endgame();
+        } else {
+            self.status.send_modify(|status| status.hash_failures += 1);
+            match verified.data {
+                // The hashes of the blocks point out the bad ones, the others are kept.
+                Some(data) if self.request_block_hashes(index) => {
+                    let senders = verified.senders;
+                    self.suspects.insert(index, Suspect { data, senders });
+                }
+                _ => self.retry_piece(index, verified.senders, verified.digests),
+            }
+        }
+
+        self.publish();
+        Ok(())
+    }
+
+    /// Downloads a piece which failed the hash check again.
+    fn retry_piece(&mut self, index: usize, senders: BTreeMap<u64, SocketAddr>, digests: Vec<ID>) {
+        self.picker.release(index, false);
+        self.update_endgame();
+        // With blocks from several peers there is no telling yet who sent the corrupt ones,
+        // the blocks are compared against the piece once it is valid.
+        let unique: HashSet<_> = senders.values().copied().collect();
+        if unique.len() == 1 {
+            let addr = unique.into_iter().next().unwrap();
+            self.penalize(addr);
+        } else {
+            let blocks = senders.into_iter().map(|(offset, sender)| {
+                let digest = digests[(offset / BLOCK_SIZE) as usize];
+                FailedBlock {
+                    offset,
+                    digest,
+                    sender,
+                }
+            });
+            self.failed.entry(index).or_default().extend(blocks);
+        }
+        // The piece is up for grabs again.
+        self.assign_pieces();
+    }
+
+    /// Keeps the good blocks of a piece which failed the merkle check and downloads only the
+    /// bad ones again, whose senders are penalized.
+    fn repair_piece(&mut self, index: usize, block_hashes: &[Hash]) {
+        let Suspect { data, senders } = self.suspects.remove(&index).unwrap();
+        let bad = match self.hashes.as_ref() {
+            Some(hashes) => hashes.bad_blocks(index, &data, block_hashes),
+            None => Vec::new(),
+        };
+        // Blocks matching their hashes while the piece does not leave nothing to go on.
+        if bad.is_empty() {
+            let digests = piece::block_digests(&data);
+            self.retry_piece(index, senders, digests);
+            return;
+        }
+
+        let is_bad = |offset: u64| bad.contains(&((offset / BLOCK_SIZE) as usize));
+        let corrupt: HashSet<_> = senders
+            .iter()
+            .filter(|(offset, _)| is_bad(**offset))
+            .map(|(_, sender)| *sender)
+            .collect();
+        for addr in corrupt {
+            self.penalize(addr);
+        }
+
+        self.piece(index);
+        let download = self.pieces.get_mut(&index).unwrap();
+        for (offset, sender) in senders.into_iter().filter(|(offset, _)| !is_bad(*offset)) {
+            let end = data.len().min((offset + BLOCK_SIZE) as usize);
+            let block_info = Blockinfo {
+                offset,
+                length: end as u64 - offset,
+                index,
+            };
+            if download
+                .piece
+                .write(Block::new(&data[offset as usize..end], block_info))
+                .is_ok()
+            {
+                download.senders.insert(offset, sender);
+            }
+        }
+        self.picker.release(index, true);
+        self.update_endgame();
+        self.assign_pieces();
+    }
+
+    /// Whether a downloaded piece can be verified, hybrid torrents need its merkle hash.
+    fn can_verify(&self, index: usize) -> bool {
+        self.hashes
+            .as_ref()
+            .is_none_or(|hashes| hashes.can_verify(index))
+    }
+
+    /// Asks peers for the parts of the piece layers we lack, until then their pieces are not
+    /// downloaded.
+    fn request_piece_layers(&mut self) {
+        let Some(hashes) = self.hashes.as_ref() else {
+            return;
+        };
+        for request in hashes.piece_layer_requests() {
+            if !self.hash_requests.contains_key(&request) {
+                self.request_hashes(request, |_| true);
+            }
+        }
+    }
+
+    /// Asks a peer with the piece at `index` for the hashes of its blocks. Returns false if no
+    /// peer is left to ask.
+    fn request_block_hashes(&mut self, index: usize) -> bool {
+        let Some(request) = self.hashes.as_ref().and_then(|h| h.block_request(index)) else {
+            return false;
+        };
+        self.request_hashes(request, |peer| peer.has(index))
+    }
+
+    /// Sends `request` to one of the `eligible` peers which did not reject it before. Returns
+    /// false if there is none.
+    fn request_hashes(&mut self, request: HashRequest, eligible: impl Fn(&Peer) -> bool) -> bool {
+        let Some(session) = self.session.as_ref() else {
+            return false;
+        };
+        let asked = session
+            .peers
+            .iter()
+            .find(|(addr, peer)| eligible(peer) && !self.hash_rejects.contains(&(**addr, request)));
+        let Some((addr, peer)) = asked else {
+            return false;
+        };
+        peer.send(PeerCommand::HashRequest(request));
+        self.hash_requests.insert(request, *addr);
+        true
+    }
+
+    /// The piece waiting for the block hashes `request` asks for.
+    fn suspect_of(&self, request: &HashRequest) -> Option<usize> {
+        let hashes = self.hashes.as_ref()?;
+        self.suspects
+            .keys()
+            .copied()
+            .find(|index| hashes.block_request(*index) == Some(*request))
+    }
+
+    /// Takes the hashes a peer answered our request with.
+    fn add_hashes(&mut self, addr: SocketAddr, request: HashRequest, hashes: Vec<Hash>) {
+        if self.hash_requests.get(&request) != Some(&addr) {
+            return;
+        }
+        let Some(torrent_hashes) = self.hashes.as_mut() else {
+            return;
+        };
+        // Pieces being verified keep the hashes they started with.
+        match Arc::make_mut(torrent_hashes).add_hashes(&request, &hashes) {
+            Ok(base) => {
+                self.hash_requests.remove(&request);
+                match self.suspect_of(&request) {
+                    Some(index) => self.repair_piece(index, &base),
+                    // Pieces whose hash is known now can be downloaded.
+                    None => self.assign_pieces(),
+                }
+            }
+            // Hashes which do not match the tree count as a rejection.
+            Err(_) => self.reject_hashes(addr, request),
+        }
+    }
+
+    /// Asks another peer for hashes the peer did not hand out.
+    fn reject_hashes(&mut self, addr: SocketAddr, request: HashRequest) {
+        if self.hash_requests.get(&request) != Some(&addr) {
+            return;
+        }
+        self.hash_requests.remove(&request);
+        self.hash_rejects.insert((addr, request));
+        self.retry_hashes(request);
+    }
+
+    fn retry_hashes(&mut self, request: HashRequest) {
+        let Some(index) = self.suspect_of(&request) else {
+            self.request_piece_layers();
+            return;
+        };
+        if !self.request_hashes(request, |peer| peer.has(index)) {
+            // Nobody hands out the block hashes, the whole piece is downloaded again.
+            let Suspect { data, senders } = self.suspects.remove(&index).unwrap();
+            self.retry_piece(index, senders, piece::block_digests(&data));
+            self.publish();
+        }
+    }
+
+    /// Counts a corrupt piece against a peer's address, banning it once it reaches the limit.
+    ///
+    /// The peer may have disconnected already, the ban still keeps it from coming back.
+    fn penalize(&mut self, addr: SocketAddr) {
+        let ip = addr.ip();
+        let strikes = self.strikes.entry(ip).or_default();
+        *strikes += 1;
+        if *strikes < MAX_HASH_FAILURES {
+            return;
+        }
+        self.banned.insert(ip);
+        let Some(session) = self.session.as_ref() else {
+            return;
+        };
+        for addr in session.peers.keys().filter(|addr| addr.ip() == ip) {
+            self.disconnect(*addr);
+        }
+    }
+
+    fn disconnect(&self, addr: SocketAddr) {
+        if let Some(peer) = self.session.as_ref().and_then(|s| s.peers.get(&addr)) {
+            peer.send(PeerCommand::Shutdown);
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.have.iter().all(|have| *have)
+    }
+
+    fn set_state(&mut self, state: TorrentState) {
+        self.status.send_modify(|status| status.state = state);
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let stats = *self.stats.borrow();
+        let peers = self.session.as_ref().map_or(0, |s| s.peers.len());
+        let pieces_done = self.have.iter().filter(|have| **have).count();
+        self.status.send_modify(|status| {
+            status.downloaded = stats.downloaded;
+            status.uploaded = stats.uploaded;
+            status.left = stats.left;
+            status.peers = peers;
+            status.pieces_done = pieces_done;
+        });
+    }
+}
+
+fn initial_status(metainfo: &Metainfo, info_hash: ID) -> TorrentStatus {
+    TorrentStatus {
+        info_hash,
+        name: metainfo.info.name.clone(),
+        state: TorrentState::Checking,
+        num_pieces: metainfo.info.num_pieces(),
+        pieces_done: 0,
+        downloaded: 0,
+        uploaded: 0,
+        left: metainfo.info.total_length(),
+        peers: 0,
+        hash_failures: 0,
+        endgame: false,
+        wasted: 0,
+    }
+}
+
+/// Prepares the store and hashes every piece already in it.
+fn check<S>(store: &Mutex<S>, metainfo: &Metainfo, id: ID) -> Result<Vec<bool>>
+where
+    S: Store,
+    S::Err: Send + Sync + 'static,
+{
+    let info = &metainfo.info;
+    store.lock().unwrap().new_store(id, info)?;
+
+    let have = (0..info.num_pieces())
+        .map(|index| {
+            let block_info = Blockinfo {
+                offset: 0,
+                length: info.piece_size(index),
+                index,
+            };
+            // Locked per piece so other torrents can keep writing meanwhile.
+            let block = store.lock().unwrap().get_block(id, block_info);
+            match (block, info.piece_hash(index)) {
+                (Some(block), Some(hash)) => piece::verify(&block, &hash),
+                _ => false,
+            }
+        })
+        .collect();
+    Ok(have)
+}
+
+async fn wait_checked(checking: Option<&mut JoinHandle<Result<Vec<bool>>>>) -> Result<Vec<bool>> {
+    match checking {
+        Some(checking) => checking.await?,
+        None => std::future::pending().await,
+    }
+}
+
+enum SessionEvent {
+    Peers(Vec<SocketAddr>),
+    Peer(SocketAddr, PeerEvent),
+}
+
+/// Waits for new peers or peer events of the running session, pending forever while stopped.
+async fn next_event(session: Option<&mut Session>) -> SessionEvent {
+    let Some(session) = session else {
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        Some(addrs) = session.addrs.recv() => SessionEvent::Peers(addrs),
+        Some((addr, event)) = session.events.recv() => SessionEvent::Peer(addr, event),
+        else => std::future::pending().await,
+    }
+}
@@ -0,0 +1,129 @@
+use std::{net::SocketAddr, time::Duration};
+use tokio::time::Instant;
+use torrus_engine::{Choker, ChokerPeer};
+
+fn addr(n: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], n))
+}
+
+/// Interested peers connected long ago, with the given download and upload rates.
+fn table(rates: &[(u64, u64)], now: Instant) -> Vec<ChokerPeer> {
+    rates
+        .iter()
+        .enumerate()
+        .map(|(n, (download_rate, upload_rate))| ChokerPeer {
+            addr: addr(n as u16),
+            download_rate: *download_rate,
+            upload_rate: *upload_rate,
+            interested: true,
+            snubbed: false,
+            connected_at: now - Duration::from_secs(600),
+        })
+        .collect()
+}
+
+fn regular(unchoked: &[SocketAddr], choker: &Choker) -> Vec<SocketAddr> {
+    let mut regular: Vec<_> = unchoked
+        .iter()
+        .copied()
+        .filter(|addr| Some(*addr) != choker.optimistic())
+        .collect();
+    regular.sort();
+    regular
+}
+
+#[test]
+fn test_choker_unchokes_fastest_peers() {
+    let now = Instant::now();
+    let peers = table(
+        &[(10, 500), (50, 400), (30, 300), (40, 200), (20, 100)],
+        now,
+    );
+    let mut choker = Choker::new(3);
+
+    let unchoked = choker.rechoke(&peers, false, now);
+    assert_eq!(unchoked.len(), 3);
+    assert_eq!(regular(&unchoked, &choker), vec![addr(1), addr(3)]);
+    let optimistic = choker.optimistic().unwrap();
+    assert!(![addr(1), addr(3)].contains(&optimistic));
+
+    // Seeding ranks by upload rate.
+    let unchoked = choker.rechoke(&peers, true, now);
+    assert_eq!(regular(&unchoked, &choker), vec![addr(0), addr(1)]);
+}
+
+#[test]
+fn test_choker_skips_uninterested_and_snubbed_peers() {
+    let now = Instant::now();
+    let mut peers = table(&[(100, 0), (90, 0), (80, 0), (70, 0)], now);
+    peers[0].interested = false;
+    peers[1].snubbed = true;
+    let mut choker = Choker::new(3);
+
+    // Snubbed peers only ever get the optimistic slot.
+    for _ in 0..10 {
+        let unchoked = choker.rechoke(&peers, false, now);
+        assert_eq!(regular(&unchoked, &choker), vec![addr(2), addr(3)]);
+        assert_eq!(choker.optimistic(), Some(addr(1)));
+    }
+
+    // Nobody is unchoked without slots.
+    let mut choker = Choker::new(0);
+    assert!(choker.rechoke(&peers, false, now).is_empty());
+}
+
+#[test]
+fn test_choker_rotates_optimistic_unchoke() {
+    let now = Instant::now();
+    let peers = table(&[(100, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0)], now);
+    let mut choker = Choker::new(2);
+
+    let mut optimistic = Vec::new();
+    for _ in 0..10 {
+        choker.rechoke(&peers, false, now);
+        let first = choker.optimistic().unwrap();
+        assert_ne!(first, addr(0));
+        // Kept for three rounds.
+        choker.rechoke(&peers, false, now);
+        choker.rechoke(&peers, false, now);
+        assert_eq!(choker.optimistic(), Some(first));
+        optimistic.push(first);
+    }
+    optimistic.sort();
+    optimistic.dedup();
+    assert!(optimistic.len() > 1);
+
+    // A peer losing interest loses the slot right away.
+    let mut peers = peers;
+    choker.rechoke(&peers, false, now);
+    let first = choker.optimistic().unwrap();
+    peers
+        .iter_mut()
+        .find(|p| p.addr == first)
+        .unwrap()
+        .interested = false;
+    choker.rechoke(&peers, false, now);
+    assert_ne!(choker.optimistic(), Some(first));
+}
+
+#[test]
+fn test_choker_favors_new_peers() {
+    let now = Instant::now();
+    let mut peers = table(&[(0, 0), (0, 0), (0, 0), (0, 0)], now);
+    peers[3].connected_at = now;
+    let mut choker = Choker::new(1);
+
+    // Weighted three to one against each of the three old peers, the new peer gets about half
+    // of the rotations instead of a quarter.
+    let mut new = 0;
+    for _ in 0..400 {
+        let unchoked = choker.rechoke(&peers, false, now);
+        assert_eq!(unchoked.len(), 1);
+        if unchoked[0] == addr(3) {
+            new += 1;
+        }
+        choker.rechoke(&peers, false, now);
+        choker.rechoke(&peers, false, now);
+    }
+    assert!(new > 140, "{new}");
+}
@@ -0,0 +1,457 @@
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::mpsc, time::sleep};
+use tokio_util::codec::Framed;
+use torrus_core::{
+    merkle::{MerkleTree, LEAF_SIZE},
+    prelude::{Block, Blockinfo, HashRequest, Info, Metainfo, ID},
+    store::Store,
+};
+use torrus_wire::{
+    ExtendedHandshake, Handshake, HandshakeCodec, Message, MessageCodec, MetadataMessage,
+    METADATA_PIECE_SIZE, UT_METADATA,
+};
+
+/// Pieces of a torrent by index.
+type Pieces = BTreeMap<usize, Vec<u8>>;
+
+/// Store keeping whole pieces in memory, clones share the same data.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    torrents: Arc<Mutex<HashMap<ID, Pieces>>>,
+}
+
+impl MemoryStore {
+    /// Concatenated pieces of a torrent.
+    pub fn data(&self, id: ID) -> Vec<u8> {
+        let torrents = self.torrents.lock().unwrap();
+        torrents[&id].values().flatten().copied().collect()
+    }
+
+    /// Flips the first byte of a piece.
+    pub fn corrupt(&self, id: ID, index: usize) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let piece = torrents.get_mut(&id).unwrap().get_mut(&index).unwrap();
+        piece[0] = !piece[0];
+    }
+}
+
+impl Store for MemoryStore {
+    type Err = std::io::Error;
+
+    fn new_store(&mut self, id: ID, _info: &Info) -> Result<(), Self::Err> {
+        self.torrents.lock().unwrap().entry(id).or_default();
+        Ok(())
+    }
+
+    fn put_block(&mut self, id: ID, block: Block) -> Result<(), Self::Err> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let pieces = torrents.get_mut(&id).ok_or(std::io::ErrorKind::NotFound)?;
+        pieces.insert(block.block_info.index, block.to_vec());
+        Ok(())
+    }
+
+    fn get_block(&self, id: ID, block_info: Blockinfo) -> Option<Block> {
+        let torrents = self.torrents.lock().unwrap();
+        let piece = torrents.get(&id)?.get(&block_info.index)?;
+        let start = block_info.offset as usize;
+        let data = piece.get(start..start + block_info.length as usize)?;
+        Some(Block::new(data, block_info))
+    }
+}
+
+/// Single file torrent without trackers for `data`.
+pub fn metainfo(name: &str, data: &[u8], piece_length: usize) -> Metainfo {
+    let pieces: Vec<u8> = data
+        .chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+
+    let mut info = format!(
+        "d6:lengthi{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+        data.len(),
+        name.len(),
+        name,
+        piece_length,
+        pieces.len()
+    )
+    .into_bytes();
+    info.extend_from_slice(&pieces);
+    info.push(b'e');
+
+    let mut torrent = b"d4:info".to_vec();
+    torrent.extend_from_slice(&info);
+    torrent.push(b'e');
+    Metainfo::new(&torrent).unwrap()
+}
+
+/// Single file hybrid torrent of `data`, whose merkle tree is the one of `tree_data`.
+pub fn hybrid_metainfo(name: &str, data: &[u8], piece_length: usize, tree_data: &[u8]) -> Metainfo {
+    let pieces: Vec<u8> = data
+        .chunks(piece_length)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    let tree = MerkleTree::from_data(tree_data);
+    let piece_layer = (piece_length / LEAF_SIZE as usize).trailing_zeros();
+    let layer = tree.layer(piece_layer).unwrap()[..pieces.len() / 20].concat();
+
+    let mut info = format!(
+        "d9:file treed{}:{}d0:d6:lengthi{}e11:pieces root32:",
+        name.len(),
+        name,
+        data.len()
+    )
+    .into_bytes();
+    info.extend_from_slice(&tree.root());
+    info.extend_from_slice(
+        format!(
+            "eee6:lengthi{}e12:meta versioni2e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+            data.len(),
+            name.len(),
+            name,
+            piece_length,
+            pieces.len()
+        )
+        .as_bytes(),
+    );
+    info.extend_from_slice(&pieces);
+    info.push(b'e');
+
+    let mut torrent = b"d4:info".to_vec();
+    torrent.extend_from_slice(&info);
+    torrent.extend_from_slice(b"12:piece layersd32:");
+    torrent.extend_from_slice(&tree.root());
+    torrent.extend_from_slice(format!("{}:", layer.len()).as_bytes());
+    torrent.extend_from_slice(&layer);
+    torrent.extend_from_slice(b"ee");
+    Metainfo::new(&torrent).unwrap()
+}
+
+/// Peer which has every piece of `data`, unchokes everyone interested and answers all requests.
+pub async fn spawn_seeder(info_hash: ID, data: Vec<u8>, piece_length: usize) -> SocketAddr {
+    spawn_seeder_on("127.0.0.1:0", info_hash, data, piece_length).await
+}
+
+pub async fn spawn_seeder_on(
+    addr: &str,
+    info_hash: ID,
+    data: Vec<u8>,
+    piece_length: usize,
+) -> SocketAddr {
+    let num_pieces = data.len().div_ceil(piece_length);
+    let have = (0..num_pieces).collect();
+    spawn_peer(addr, info_hash, data, piece_length, have)
+        .await
+        .0
+}
+
+/// Peer which only announces the pieces in `have`, but otherwise behaves like [spawn_seeder].
+/// Returns the blocks it was requested.
+pub async fn spawn_peer(
+    addr: &str,
+    info_hash: ID,
+    data: Vec<u8>,
+    piece_length: usize,
+    have: Vec<usize>,
+) -> (SocketAddr, mpsc::UnboundedReceiver<Blockinfo>) {
+    let serving = Serving {
+        have,
+        ..Default::default()
+    };
+    serve(addr, info_hash, data, piece_length, serving).await
+}
+
+/// Peer which only announces the pieces in `have`, answers the first `blocks` requests of a
+/// connection and disconnects. Returns the blocks it answered.
+pub async fn spawn_leaver(
+    addr: &str,
+    info_hash: ID,
+    data: Vec<u8>,
+    piece_length: usize,
+    have: Vec<usize>,
+    blocks: usize,
+) -> (SocketAddr, mpsc::UnboundedReceiver<Blockinfo>) {
+    let serving = Serving {
+        have,
+        limit: Some(blocks),
+        leave: true,
+        ..Default::default()
+    };
+    serve(addr, info_hash, data, piece_length, serving).await
+}
+
+/// Like [spawn_leaver], but stays connected and ignores the requests after the first `blocks`.
+pub async fn spawn_hoarder(
+    addr: &str,
+    info_hash: ID,
+    data: Vec<u8>,
+    piece_length: usize,
+    have: Vec<usize>,
+    blocks: usize,
+) -> (SocketAddr, mpsc::UnboundedReceiver<Blockinfo>) {
+    let serving = Serving {
+        have,
+        limit: Some(blocks),
+        ..Default::default()
+    };
+    serve(addr, info_hash, data, piece_length, serving).await
+}
+
+/// Seeder of a single file hybrid torrent which announces v2 support and answers hash requests
+/// from the merkle tree of `data`. The block at `corrupt` is sent corrupt the first time it is
+/// requested. Returns the blocks it was requested.
+pub async fn spawn_v2_seeder(
+    info_hash: ID,
+    data: Vec<u8>,
+    piece_length: usize,
+    corrupt: Option<Blockinfo>,
+) -> (SocketAddr, mpsc::UnboundedReceiver<Blockinfo>) {
+    let serving = Serving {
+        have: (0..data.len().div_ceil(piece_length)).collect(),
+        tree: Some(Arc::new(MerkleTree::from_data(&data))),
+        corrupt: Arc::new(Mutex::new(corrupt)),
+        ..Default::default()
+    };
+    serve("127.0.0.1:0", info_hash, data, piece_length, serving).await
+}
+
+/// How a peer spawned by [serve] behaves.
+#[derive(Clone, Default)]
+struct Serving {
+    /// Pieces announced in the bitfield.
+    have: Vec<usize>,
+    /// Requests answered per connection, later ones are ignored.
+    limit: Option<usize>,
+    /// Whether to disconnect once `limit` requests are answered.
+    leave: bool,
+    /// Tree answering hash requests, v2 support is announced with it.
+    tree: Option<Arc<MerkleTree>>,
+    /// Block sent corrupt once, shared by every connection.
+    corrupt: Arc<Mutex<Option<Blockinfo>>>,
+}
+
+async fn serve(
+    addr: &str,
+    info_hash: ID,
+    data: Vec<u8>,
+    piece_length: usize,
+    serving: Serving,
+) -> (SocketAddr, mpsc::UnboundedReceiver<Blockinfo>) {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let data = Arc::new(data);
+    let (requests_tx, requests) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let data = data.clone();
+            let serving = serving.clone();
+            let requests_tx = requests_tx.clone();
+
+            tokio::spawn(async move {
+                let mut framed = Framed::new(stream, HandshakeCodec);
+                let Some(Ok(handshake)) = framed.next().await else {
+                    return;
+                };
+                assert_eq!(handshake.info_hash, info_hash);
+                let mut ours = Handshake::new(info_hash, ID::from(vec![7; 20]));
+                if serving.tree.is_some() {
+                    ours = ours.with_v2();
+                }
+                framed.send(ours).await.unwrap();
+
+                let mut framed = framed.map_codec(|_| MessageCodec::new());
+                let num_pieces = data.len().div_ceil(piece_length);
+                let mut bitfield = vec![0u8; num_pieces.div_ceil(8)];
+                for index in serving.have {
+                    bitfield[index / 8] |= 0x80 >> (index % 8);
+                }
+                framed.send(Message::Bitfield(bitfield)).await.unwrap();
+
+                let mut answered = 0;
+                while let Some(Ok(message)) = framed.next().await {
+                    let reply = match message {
+                        Message::Interested => Message::Unchoke,
+                        Message::Request(_) if serving.limit == Some(answered) => continue,
+                        Message::Request(block_info) => {
+                            answered += 1;
+                            let _ = requests_tx.send(block_info);
+                            let start =
+                                block_info.index * piece_length + block_info.offset as usize;
+                            let end = start + block_info.length as usize;
+                            let mut block = data[start..end].to_vec();
+                            let mut corrupt = serving.corrupt.lock().unwrap();
+                            if *corrupt == Some(block_info) {
+                                *corrupt = None;
+                                block.iter_mut().for_each(|byte| *byte = !*byte);
+                            }
+                            Message::Piece(Block::new(&block, block_info))
+                        }
+                        Message::HashRequest(request) => {
+                            match serving
+                                .tree
+                                .as_ref()
+                                .and_then(|tree| answer(tree, &request))
+                            {
+                                Some(hashes) => Message::Hashes { request, hashes },
+                                None => Message::HashReject(request),
+                            }
+                        }
+                        _ => continue,
+                    };
+                    let left = serving.leave && serving.limit == Some(answered);
+                    if framed.send(reply).await.is_err() || left {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    (addr, requests)
+}
+
+/// Hashes of `tree` answering `request`, followed by the uncle hashes proving them.
+fn answer(tree: &MerkleTree, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+    let length = request.length as usize;
+    let index = request.index as usize;
+    let hashes = tree.layer(request.base_layer)?.get(index..index + length)?;
+    let uncles = tree.proof(request.base_layer + length.trailing_zeros(), index / length);
+    Some([hashes, uncles.get(..request.proof_layers as usize)?].concat())
+}
+
+/// Peer which claims to have all `num_pieces` and unchokes everyone, but never answers a request.
+/// Returns the blocks it was asked to cancel.
+pub async fn spawn_staller(
+    info_hash: ID,
+    num_pieces: usize,
+) -> (SocketAddr, mpsc::UnboundedReceiver<Blockinfo>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (cancels_tx, cancels) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        let Some(Ok(_)) = framed.next().await else {
+            return;
+        };
+        framed
+            .send(Handshake::new(info_hash, ID::from(vec![8; 20])))
+            .await
+            .unwrap();
+
+        let mut framed = framed.map_codec(|_| MessageCodec::new());
+        let mut bitfield = vec![0u8; num_pieces.div_ceil(8)];
+        for index in 0..num_pieces {
+            bitfield[index / 8] |= 0x80 >> (index % 8);
+        }
+        framed.send(Message::Bitfield(bitfield)).await.unwrap();
+
+        while let Some(Ok(message)) = framed.next().await {
+            match message {
+                Message::Interested => framed.send(Message::Unchoke).await.unwrap(),
+                Message::Cancel(block_info) => {
+                    let _ = cancels_tx.send(block_info);
+                }
+                _ => {}
+            }
+        }
+    });
+
+    (addr, cancels)
+}
+
+/// Peer which only hands out the info dictionary `info` over ut_metadata. The piece at
+/// `corrupt` is sent inverted and the piece at `delayed` only after a while.
+pub async fn spawn_metadata_peer(
+    addr: &str,
+    info: Vec<u8>,
+    corrupt: Option<u32>,
+    delayed: u32,
+) -> SocketAddr {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = ID::from(Sha1::digest(&info).to_vec());
+    let info = Arc::new(info);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let info = info.clone();
+
+            tokio::spawn(async move {
+                let mut framed = Framed::new(stream, HandshakeCodec);
+                let Some(Ok(_)) = framed.next().await else {
+                    return;
+                };
+                let handshake = Handshake::new(info_hash, ID::from(vec![9; 20])).with_extensions();
+                framed.send(handshake).await.unwrap();
+
+                let framed = framed.map_codec(|_| MessageCodec::new());
+                let (mut sink, mut stream) = framed.split();
+                let (replies_tx, mut replies) = mpsc::unbounded_channel();
+                tokio::spawn(async move {
+                    while let Some(reply) = replies.recv().await {
+                        if sink.send(reply).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                let ours = ExtendedHandshake {
+                    m: [(UT_METADATA.to_string(), 3)].into(),
+                    metadata_size: Some(info.len() as u64),
+                    ..Default::default()
+                };
+                let payload = ours.to_bytes().unwrap();
+                let _ = replies_tx.send(Message::Extended { id: 0, payload });
+
+                let mut theirs = None;
+                while let Some(Ok(message)) = stream.next().await {
+                    match message {
+                        Message::Extended { id: 0, payload } => {
+                            let handshake = ExtendedHandshake::from_bytes(&payload).unwrap();
+                            theirs = handshake.extension_id(UT_METADATA);
+                        }
+                        Message::Extended { id: 3, payload } => {
+                            let (Some(id), Ok(MetadataMessage::Request(piece))) =
+                                (theirs, MetadataMessage::from_bytes(&payload))
+                            else {
+                                continue;
+                            };
+                            let start = piece as usize * METADATA_PIECE_SIZE;
+                            let end = info.len().min(start + METADATA_PIECE_SIZE);
+                            let mut data = info[start..end].to_vec();
+                            if corrupt == Some(piece) {
+                                data.iter_mut().for_each(|byte| *byte = !*byte);
+                            }
+                            let data = MetadataMessage::Data {
+                                piece,
+                                total_size: info.len() as u64,
+                                data,
+                            };
+                            let payload = data.to_bytes().unwrap();
+                            let replies_tx = replies_tx.clone();
+                            tokio::spawn(async move {
+                                if piece == delayed {
+                                    sleep(Duration::from_millis(300)).await;
+                                }
+                                let _ = replies_tx.send(Message::Extended { id, payload });
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+    });
+
+    addr
+}
@@ -0,0 +1,927 @@
+mod common;
+
+use common::{
+    hybrid_metainfo, metainfo, spawn_hoarder, spawn_leaver, spawn_metadata_peer, spawn_peer,
+    spawn_seeder, spawn_seeder_on, spawn_staller, spawn_v2_seeder, MemoryStore,
+};
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use std::{net::Ipv6Addr, time::Duration};
+use tokio::{
+    net::TcpStream,
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tokio_util::codec::Framed;
+use torrus_core::{
+    merkle::MerkleTree,
+    prelude::{Blockinfo, HashRequest, MagnetLink, Metainfo, Sha1Hash, ID},
+};
+use torrus_engine::{Engine, EngineHandle, PickStrategy, TorrentState, TorrentStatus};
+use torrus_storage::merkle::FileHashes;
+use torrus_wire::{
+    ExtendedHandshake, Handshake, HandshakeCodec, Message, MessageCodec, MetadataMessage,
+    UT_METADATA,
+};
+
+const PIECE_LENGTH: usize = 1 << 15;
+
+fn test_data() -> Vec<u8> {
+    (0..PIECE_LENGTH * 3 + 100)
+        .map(|n| (n % 251) as u8)
+        .collect()
+}
+
+fn spawn_engine(store: MemoryStore) -> EngineHandle {
+    let (engine, handle) = Engine::new(ID::from(vec![5; 20]), store);
+    let engine = engine.with_port(0);
+    tokio::spawn(engine.run());
+    handle
+}
+
+async fn wait_for_state(status: &mut watch::Receiver<TorrentStatus>, state: TorrentState) {
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.state == state),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_engine_lifecycle() {
+    let handle = spawn_engine(MemoryStore::default());
+
+    let id = handle
+        .add_torrent(metainfo("a", &test_data(), PIECE_LENGTH))
+        .await
+        .unwrap();
+    assert!(handle
+        .add_torrent(metainfo("a", &test_data(), PIECE_LENGTH))
+        .await
+        .is_err());
+    handle
+        .add_torrent(metainfo("b", &test_data(), PIECE_LENGTH))
+        .await
+        .unwrap();
+
+    // Without v1 piece hashes there is nothing to verify pieces against yet.
+    let v2_only = Metainfo::from_info_bytes(
+        [
+            &b"d9:file treed1:ad0:d6:lengthi1e11:pieces root32:"[..],
+            &[1; 32],
+            b"eee12:meta versioni2e4:name1:a12:piece lengthi16384ee",
+        ]
+        .concat(),
+    )
+    .unwrap();
+    assert!(handle.add_torrent(v2_only).await.is_err());
+
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.name, "a");
+    assert_eq!(current.num_pieces, 4);
+    assert_eq!(current.left, test_data().len() as u64);
+
+    handle.pause(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Paused).await;
+    handle.resume(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    assert_eq!(handle.list().await.unwrap().len(), 2);
+    handle.remove(id).await.unwrap();
+    let list = handle.list().await.unwrap();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].name, "b");
+
+    assert!(handle.pause(id).await.is_err());
+    assert!(handle.status(id).await.is_err());
+
+    handle.shutdown().await;
+    assert!(handle.list().await.is_err());
+}
+
+#[tokio::test]
+async fn test_engine_download() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    let data = test_data();
+    let metainfo = metainfo("download", &data, PIECE_LENGTH);
+    let id = handle.add_torrent(metainfo).await.unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+
+    let seeder = spawn_seeder(id, data.clone(), PIECE_LENGTH).await;
+    // Peers which can not be reached must not stall the download.
+    let unreachable = "127.0.0.1:1".parse().unwrap();
+    handle
+        .add_peers(id, vec![unreachable, seeder])
+        .await
+        .unwrap();
+
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.pieces_done, 4);
+    assert_eq!(current.left, 0);
+    assert_eq!(current.downloaded, data.len() as u64);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_pause_drops_peers() {
+    let handle = spawn_engine(MemoryStore::default());
+
+    let data = test_data();
+    let id = handle
+        .add_torrent(metainfo("pause", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    handle.pause(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Paused).await;
+
+    // Peers added while paused are ignored.
+    let seeder = spawn_seeder(id, data, PIECE_LENGTH).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.state, TorrentState::Paused);
+    assert_eq!(current.peers, 0);
+
+    handle.resume(id).await.unwrap();
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_bans_corrupt_peers() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    let data = test_data();
+    let id = handle
+        .add_torrent(metainfo("corrupt", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    // Bound to another loopback address as peers are banned by ip.
+    let corrupt: Vec<u8> = data.iter().map(|byte| !byte).collect();
+    let liar = spawn_seeder_on("127.0.0.2:0", id, corrupt, PIECE_LENGTH).await;
+    handle.add_peers(id, vec![liar]).await.unwrap();
+
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.hash_failures == 2 && status.peers == 0),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(handle.status(id).await.unwrap().pieces_done, 0);
+
+    // Banned peers are not connected again.
+    handle.add_peers(id, vec![liar]).await.unwrap();
+    assert_eq!(handle.status(id).await.unwrap().peers, 0);
+
+    let seeder = spawn_seeder(id, data.clone(), PIECE_LENGTH).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_blames_corrupt_sender() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    // Two pieces of four blocks.
+    let piece_length = 1 << 16;
+    let data: Vec<u8> = (0..piece_length * 2).map(|n| (n % 251) as u8).collect();
+    let id = handle
+        .add_torrent(metainfo("senders", &data, piece_length))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    // Each liar sends two corrupt blocks of its piece and leaves it partially downloaded.
+    let corrupt: Vec<u8> = data.iter().map(|byte| !byte).collect();
+    let mut liars = Vec::new();
+    for index in 0..2 {
+        let (liar, mut answered) = spawn_leaver(
+            "127.0.0.2:0",
+            id,
+            corrupt.clone(),
+            piece_length,
+            vec![index],
+            2,
+        )
+        .await;
+        handle.add_peers(id, vec![liar]).await.unwrap();
+        for _ in 0..2 {
+            timeout(Duration::from_secs(10), answered.recv())
+                .await
+                .unwrap();
+        }
+        liars.push(liar);
+    }
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.peers == 0),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    // The seeder completing both pieces is not blamed for their corrupt blocks.
+    let seeder = spawn_seeder(id, data.clone(), piece_length).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.hash_failures, 2);
+    assert_eq!(current.peers, 1);
+
+    // The liars' blocks differ from the valid pieces, their address is banned although they left.
+    handle.add_peers(id, liars).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(handle.status(id).await.unwrap().peers, 1);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_blames_corrupt_sender_in_endgame() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    // Two pieces of four blocks.
+    let piece_length = 1 << 16;
+    let data: Vec<u8> = (0..piece_length * 2).map(|n| (n % 251) as u8).collect();
+    let id = handle
+        .add_torrent(metainfo("endgame senders", &data, piece_length))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    // Each liar sends two corrupt blocks of its piece and withholds the rest.
+    let corrupt: Vec<u8> = data.iter().map(|byte| !byte).collect();
+    for index in 0..2 {
+        let (liar, mut answered) = spawn_hoarder(
+            "127.0.0.2:0",
+            id,
+            corrupt.clone(),
+            piece_length,
+            vec![index],
+            2,
+        )
+        .await;
+        handle.add_peers(id, vec![liar]).await.unwrap();
+        for _ in 0..2 {
+            timeout(Duration::from_secs(10), answered.recv())
+                .await
+                .unwrap();
+        }
+    }
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.endgame),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    // The seeder duplicates the missing blocks, the liars are found out once it sends the pieces
+    // on its own.
+    let seeder = spawn_seeder(id, data.clone(), piece_length).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.peers == 1),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(handle.status(id).await.unwrap().hash_failures, 2);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_verifies_hybrid_pieces() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+    let data = test_data();
+
+    // The SHA-1 hashes match, but the merkle tree is the one of other data. More pieces may fail
+    // while the seeder is asked for block hashes it does not have.
+    let other: Vec<u8> = data.iter().map(|byte| !byte).collect();
+    let id = handle
+        .add_torrent(hybrid_metainfo("other", &data, PIECE_LENGTH, &other))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    let seeder = spawn_seeder_on("127.0.0.2:0", id, data.clone(), PIECE_LENGTH).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.hash_failures >= 2 && status.peers == 0),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(handle.status(id).await.unwrap().pieces_done, 0);
+
+    let id = handle
+        .add_torrent(hybrid_metainfo("hybrid", &data, PIECE_LENGTH, &data))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    let seeder = spawn_seeder(id, data.clone(), PIECE_LENGTH).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_fetches_piece_layers() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+    let data = test_data();
+
+    // Like a torrent from a magnet link, the piece layers come from peers.
+    let mut metainfo = hybrid_metainfo("layers", &data, PIECE_LENGTH, &data);
+    metainfo.piece_layers = None;
+    let id = handle.add_torrent(metainfo).await.unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    // A peer without v2 support can not hand out the layers, no piece can be verified yet.
+    let all = (0..4).collect();
+    let (plain, mut requests) =
+        spawn_peer("127.0.0.2:0", id, data.clone(), PIECE_LENGTH, all).await;
+    handle.add_peers(id, vec![plain]).await.unwrap();
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.peers == 1),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert!(requests.try_recv().is_err());
+
+    let (seeder, _) = spawn_v2_seeder(id, data.clone(), PIECE_LENGTH, None).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+    assert_eq!(handle.status(id).await.unwrap().hash_failures, 0);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_downloads_bad_blocks_again() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    // A single piece of four blocks, the second one arrives corrupt once.
+    let piece_length = 1 << 16;
+    let data: Vec<u8> = (0..piece_length).map(|n| (n % 251) as u8).collect();
+    let id = handle
+        .add_torrent(hybrid_metainfo("blocks", &data, piece_length, &data))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    let corrupt = Blockinfo {
+        offset: 1 << 14,
+        length: 1 << 14,
+        index: 0,
+    };
+    let (seeder, mut requests) =
+        spawn_v2_seeder(id, data.clone(), piece_length, Some(corrupt)).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+
+    // The block hashes point out the corrupt block, only that one is requested again.
+    let mut offsets = Vec::new();
+    while let Ok(block_info) = requests.try_recv() {
+        offsets.push(block_info.offset);
+    }
+    offsets.sort();
+    assert_eq!(offsets, [0, 1 << 14, 1 << 14, 2 << 14, 3 << 14]);
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.hash_failures, 1);
+    assert_eq!(current.peers, 1);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_serves_hashes() {
+    let data = test_data();
+    let handle = spawn_engine(MemoryStore::default());
+    let id = handle
+        .add_torrent(hybrid_metainfo("hashes", &data, PIECE_LENGTH, &data))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+    let port = handle.listen_addr().await.unwrap().port();
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut framed = Framed::new(stream, HandshakeCodec);
+    let handshake = Handshake::new(id, ID::from(vec![6; 20])).with_v2();
+    framed.send(handshake).await.unwrap();
+    assert!(framed.next().await.unwrap().unwrap().supports_v2());
+    let mut peer = framed.map_codec(|_| MessageCodec::new());
+
+    // The piece layer is handed out and leads to the pieces root.
+    let tree = MerkleTree::from_data(&data);
+    let mut hashes = FileHashes::new(tree.root(), data.len() as u64, PIECE_LENGTH as u64).unwrap();
+    let layer = hashes.piece_layer_requests()[0];
+    // Block hashes are not kept.
+    let blocks = HashRequest {
+        base_layer: 0,
+        length: 2,
+        ..layer
+    };
+    for request in [layer, blocks] {
+        peer.send(Message::HashRequest(request)).await.unwrap();
+    }
+
+    let mut answered = 0;
+    while answered < 2 {
+        let message = timeout(Duration::from_secs(10), peer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match message {
+            Message::Hashes {
+                request,
+                hashes: answer,
+            } => {
+                assert_eq!(request, layer);
+                hashes.add_hashes(&request, &answer).unwrap();
+                answered += 1;
+            }
+            Message::HashReject(request) => {
+                assert_eq!(request, blocks);
+                answered += 1;
+            }
+            _ => {}
+        }
+    }
+    assert!(hashes.has_piece_layer());
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_checks_existing_data() {
+    let store = MemoryStore::default();
+    let data = test_data();
+
+    let handle = spawn_engine(store.clone());
+    let id = handle
+        .add_torrent(metainfo("check", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    let seeder = spawn_seeder(id, data.clone(), PIECE_LENGTH).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    handle.shutdown().await;
+
+    // A new engine finds every piece in the store.
+    let handle = spawn_engine(store.clone());
+    handle
+        .add_torrent(metainfo("check", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.pieces_done, 4);
+    assert_eq!(current.left, 0);
+    handle.shutdown().await;
+
+    // Corrupt pieces are downloaded again.
+    store.corrupt(id, 2);
+    let handle = spawn_engine(store.clone());
+    handle
+        .add_torrent(metainfo("check", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.pieces_done, 3);
+    assert_eq!(current.left, PIECE_LENGTH as u64);
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_endgame() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    // A single piece of two blocks.
+    let data = test_data()[..PIECE_LENGTH].to_vec();
+    let id = handle
+        .add_torrent(metainfo("endgame", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+
+    // The staller picks the only piece and never sends it.
+    let (staller, mut cancels) = spawn_staller(id, 1).await;
+    handle.add_peers(id, vec![staller]).await.unwrap();
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.endgame),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    let seeder = spawn_seeder(id, data.clone(), PIECE_LENGTH).await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+
+    let current = handle.status(id).await.unwrap();
+    assert!(!current.endgame);
+    assert_eq!(current.downloaded, data.len() as u64);
+
+    // Both blocks are cancelled at the staller once the seeder sent them.
+    let mut cancelled = Vec::new();
+    for _ in 0..2 {
+        let block_info = timeout(Duration::from_secs(10), cancels.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        cancelled.push(block_info.offset);
+    }
+    cancelled.sort();
+    assert_eq!(cancelled, vec![0, 1 << 14]);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_sequential() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    let data = test_data();
+    let id = handle
+        .add_torrent(metainfo("sequential", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    handle
+        .set_strategy(id, PickStrategy::Sequential)
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+
+    let (seeder, mut requests) = spawn_peer(
+        "127.0.0.1:0",
+        id,
+        data.clone(),
+        PIECE_LENGTH,
+        vec![0, 1, 2, 3],
+    )
+    .await;
+    handle.add_peers(id, vec![seeder]).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+
+    // Blocks are requested strictly in order.
+    let mut requested = Vec::new();
+    while let Ok(block_info) = requests.try_recv() {
+        requested.push((block_info.index, block_info.offset));
+    }
+    let mut sorted = requested.clone();
+    sorted.sort();
+    assert_eq!(requested.len(), 7);
+    assert_eq!(requested, sorted);
+
+    assert!(handle
+        .set_strategy(ID::from(vec![9; 20]), PickStrategy::Sequential)
+        .await
+        .is_err());
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_deadlines() {
+    let store = MemoryStore::default();
+    let handle = spawn_engine(store.clone());
+
+    let data = test_data();
+    let id = handle
+        .add_torrent(metainfo("deadlines", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+    handle
+        .set_deadlines(id, vec![(2, Duration::ZERO)])
+        .await
+        .unwrap();
+
+    // The staller picks the urgent piece first and never sends it.
+    let (staller, mut cancels) = spawn_staller(id, 4).await;
+    handle.add_peers(id, vec![staller]).await.unwrap();
+    timeout(Duration::from_millis(200), cancels.recv())
+        .await
+        .expect_err("nothing was downloaded yet");
+
+    // The peer only has the urgent piece, which is re-requested from it as the deadline passed.
+    let (peer, _) = spawn_peer("127.0.0.1:0", id, data.clone(), PIECE_LENGTH, vec![2]).await;
+    handle.add_peers(id, vec![peer]).await.unwrap();
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| status.pieces_done == 1),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        store.data(id),
+        data[2 * PIECE_LENGTH..3 * PIECE_LENGTH].to_vec()
+    );
+    assert!(timeout(Duration::from_secs(10), cancels.recv())
+        .await
+        .unwrap()
+        .is_some());
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_seeds() {
+    let data = test_data();
+
+    let seeder_store = MemoryStore::default();
+    let seeder = spawn_engine(seeder_store.clone());
+    let id = seeder
+        .add_torrent(metainfo("seed", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut seeder_status = seeder.watch(id).await.unwrap();
+    let origin = spawn_seeder(id, data.clone(), PIECE_LENGTH).await;
+    seeder.add_peers(id, vec![origin]).await.unwrap();
+    wait_for_state(&mut seeder_status, TorrentState::Seeding).await;
+
+    // A second engine downloads everything from the first one.
+    let store = MemoryStore::default();
+    let leecher = spawn_engine(store.clone());
+    leecher
+        .add_torrent(metainfo("seed", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut status = leecher.watch(id).await.unwrap();
+    let port = seeder.listen_addr().await.unwrap().port();
+    let addr = ([127, 0, 0, 1], port).into();
+    leecher.add_peers(id, vec![addr]).await.unwrap();
+
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+
+    timeout(
+        Duration::from_secs(10),
+        seeder_status.wait_for(|status| status.uploaded == data.len() as u64),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    // Connections for unknown torrents are dropped.
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut framed = Framed::new(stream, HandshakeCodec);
+    let unknown = ID::from(vec![9; 20]);
+    framed
+        .send(Handshake::new(unknown, ID::from(vec![6; 20])))
+        .await
+        .unwrap();
+    let answer = timeout(Duration::from_secs(10), framed.next())
+        .await
+        .unwrap();
+    assert!(answer.is_none());
+
+    seeder.shutdown().await;
+    leecher.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_add_magnet() {
+    let handle = spawn_engine(MemoryStore::default());
+    let info_hash = metainfo("a", &test_data(), PIECE_LENGTH).as_sha1();
+
+    let magnet: MagnetLink = format!("magnet:?xt=urn:btih:{info_hash}&dn=Magnet&x.pe=127.0.0.1:1")
+        .parse()
+        .unwrap();
+    let id = handle.add_magnet(magnet.clone()).await.unwrap();
+    assert_eq!(id, info_hash);
+    assert!(handle.add_magnet(magnet).await.is_err());
+    assert!(handle
+        .add_torrent(metainfo("a", &test_data(), PIECE_LENGTH))
+        .await
+        .is_err());
+
+    let mut status = handle.watch(id).await.unwrap();
+    let current = handle.status(id).await.unwrap();
+    assert_eq!(current.state, TorrentState::FetchingMetadata);
+    assert_eq!(current.name, "Magnet");
+
+    handle.pause(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Paused).await;
+    handle.resume(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::FetchingMetadata).await;
+
+    let v2_only = MagnetLink {
+        info_hash_v2: Some([1; 32].into()),
+        ..Default::default()
+    };
+    assert!(handle.add_magnet(v2_only).await.is_err());
+
+    handle.remove(id).await.unwrap();
+    assert!(handle.list().await.unwrap().is_empty());
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_resolves_magnet() {
+    let data = test_data();
+
+    let seeder = spawn_engine(MemoryStore::default());
+    let id = seeder
+        .add_torrent(metainfo("seed", &data, PIECE_LENGTH))
+        .await
+        .unwrap();
+    let mut seeder_status = seeder.watch(id).await.unwrap();
+    let origin = spawn_seeder(id, data.clone(), PIECE_LENGTH).await;
+    seeder.add_peers(id, vec![origin]).await.unwrap();
+    wait_for_state(&mut seeder_status, TorrentState::Seeding).await;
+
+    // The info dictionary comes from the first engine, the pieces from both.
+    let port = seeder.listen_addr().await.unwrap().port();
+    let mut magnet = MagnetLink::new(id);
+    magnet.peers.push(format!("127.0.0.1:{port}"));
+    let store = MemoryStore::default();
+    let leecher = spawn_engine(store.clone());
+    leecher.add_magnet(magnet).await.unwrap();
+    let mut status = leecher.watch(id).await.unwrap();
+
+    wait_for_state(&mut status, TorrentState::Seeding).await;
+    assert_eq!(store.data(id), data);
+    let current = status.borrow().clone();
+    assert_eq!(current.name, "seed");
+    assert_eq!(current.num_pieces, 4);
+
+    seeder.shutdown().await;
+    leecher.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_engine_metadata_mismatch() {
+    // An info dictionary of two ut_metadata pieces.
+    let num_pieces = 1000;
+    let mut info = format!(
+        "d6:lengthi{}e4:name4:meta12:piece lengthi{PIECE_LENGTH}e6:pieces{}:",
+        num_pieces * PIECE_LENGTH,
+        num_pieces * 20
+    )
+    .into_bytes();
+    info.extend(vec![1; num_pieces * 20]);
+    info.push(b'e');
+    let id = ID::from(Sha1::digest(&info).to_vec());
+
+    // Each peer is the first to send one of the pieces, one of which is corrupt. Neither is
+    // banned for the mixed dictionary, only the liar once its own dictionary is wrong too.
+    let liar = spawn_metadata_peer("127.0.0.2:0", info.clone(), Some(1), 0).await;
+    let honest = spawn_metadata_peer("127.0.0.3:0", info, None, 1).await;
+    let mut magnet = MagnetLink::new(id);
+    magnet.peers = vec![liar.to_string(), honest.to_string()];
+
+    let handle = spawn_engine(MemoryStore::default());
+    handle.add_magnet(magnet).await.unwrap();
+    let mut status = handle.watch(id).await.unwrap();
+    wait_for_state(&mut status, TorrentState::Downloading).await;
+    assert_eq!(status.borrow().num_pieces, num_pieces);
+
+    handle.shutdown().await;
+}
+
+/// Connects to the engine with the extension protocol and requests the first piece of the
+/// info dictionary.
+async fn request_metadata(addr: std::net::SocketAddr, id: ID) -> MetadataMessage {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut framed = Framed::new(stream, HandshakeCodec);
+    let handshake = Handshake::new(id, ID::from(vec![6; 20])).with_extensions();
+    framed.send(handshake).await.unwrap();
+    assert!(framed.next().await.unwrap().unwrap().supports_extensions());
+    let mut peer = framed.map_codec(|_| MessageCodec::new());
+
+    let ours = ExtendedHandshake {
+        m: [(UT_METADATA.to_string(), 3)].into(),
+        ..Default::default()
+    };
+    peer.send(Message::Extended {
+        id: 0,
+        payload: ours.to_bytes().unwrap(),
+    })
+    .await
+    .unwrap();
+
+    loop {
+        let message = timeout(Duration::from_secs(10), peer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match message {
+            Message::Extended { id: 0, payload } => {
+                let theirs = ExtendedHandshake::from_bytes(&payload).unwrap();
+                assert!(theirs.metadata_size.is_some());
+                let request = MetadataMessage::Request(0);
+                peer.send(Message::Extended {
+                    id: theirs.extension_id(UT_METADATA).unwrap(),
+                    payload: request.to_bytes().unwrap(),
+                })
+                .await
+                .unwrap();
+            }
+            Message::Extended { id: 3, payload } => {
+                return MetadataMessage::from_bytes(&payload).unwrap();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_engine_serves_metadata() {
+    let data = test_data();
+    let handle = spawn_engine(MemoryStore::default());
+
+    let public = metainfo("public", &data, PIECE_LENGTH);
+    let mut private = metainfo("private", &data, PIECE_LENGTH);
+    private.info.private = Some(1);
+    let public = handle.add_torrent(public).await.unwrap();
+    let private = handle.add_torrent(private).await.unwrap();
+    for id in [public, private] {
+        let mut status = handle.watch(id).await.unwrap();
+        wait_for_state(&mut status, TorrentState::Downloading).await;
+    }
+    let port = handle.listen_addr().await.unwrap().port();
+    let addr = ([127, 0, 0, 1], port).into();
+
+    let MetadataMessage::Data {
+        piece: 0,
+        total_size,
+        data,
+    } = request_metadata(addr, public).await
+    else {
+        panic!("Expected the first piece of the info dictionary");
+    };
+    assert_eq!(total_size, data.len() as u64);
+    assert_eq!(ID::from(Sha1::digest(&data).to_vec()), public);
+
+    assert_eq!(
+        request_metadata(addr, private).await,
+        MetadataMessage::Reject(0)
+    );
+
+    // Peers connecting over IPv6 are accepted as well.
+    let addr = (Ipv6Addr::LOCALHOST, port).into();
+    assert!(matches!(
+        request_metadata(addr, public).await,
+        MetadataMessage::Data { piece: 0, .. }
+    ));
+
+    handle.shutdown().await;
+}
@@ -0,0 +1,274 @@
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use tokio::{
+    io::{duplex, AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_util::codec::Framed;
+use torrus_core::prelude::{Block, Blockinfo, ChokeStatus, IntrestStatus, PeerInfo, PeerState, ID};
+use torrus_engine::{PeerCommand, PeerConnection, PeerEvent};
+use torrus_wire::{
+    ExtendedHandshake, Handshake, HandshakeCodec, Message, MessageCodec, MetadataMessage,
+    UT_METADATA,
+};
+
+fn info_hash() -> ID {
+    ID::from(vec![1; 20])
+}
+
+fn remote_id() -> ID {
+    ID::from(vec![9; 20])
+}
+
+fn block_info(offset: u64) -> Blockinfo {
+    Blockinfo {
+        offset,
+        length: 16384,
+        index: 0,
+    }
+}
+
+/// Plays the remote end of the handshake and hands back the message stream.
+async fn fake_peer<S>(stream: S, info_hash: ID) -> Framed<S, MessageCodec>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, HandshakeCodec);
+    let handshake = framed.next().await.unwrap().unwrap();
+    assert_eq!(handshake.info_hash, self::info_hash());
+    framed
+        .send(Handshake::new(info_hash, remote_id()))
+        .await
+        .unwrap();
+    framed.map_codec(|_| MessageCodec::new())
+}
+
+async fn next_message<S>(framed: &mut Framed<S, MessageCodec>) -> Message
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    framed.next().await.unwrap().unwrap()
+}
+
+async fn next_event(events: &mut mpsc::Receiver<(SocketAddr, PeerEvent)>) -> PeerEvent {
+    events.recv().await.unwrap().1
+}
+
+fn addr() -> SocketAddr {
+    "10.0.0.1:6881".parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_peer_connection_download() {
+    let (local, remote) = duplex(1 << 16);
+    let (events_tx, mut events) = mpsc::channel(64);
+    let (connection, handle) =
+        PeerConnection::new(PeerInfo::new(addr()), info_hash(), ID::default(), events_tx);
+    let task = tokio::spawn(connection.with_pipeline(2).run_on(local));
+
+    let mut peer = fake_peer(remote, info_hash()).await;
+
+    assert!(matches!(next_event(&mut events).await, PeerEvent::Connected(id) if id == remote_id()));
+    // Interest is shown right after the handshake.
+    assert_eq!(next_message(&mut peer).await, Message::Interested);
+    match next_event(&mut events).await {
+        PeerEvent::StateChanged(state) => {
+            assert_eq!(state.am_interested, IntrestStatus::Interested);
+            assert_eq!(state.peer_choking, ChokeStatus::Chocked);
+        }
+        event => panic!("Unexpected event {event:?}"),
+    }
+
+    // Nothing is requested while the peer chokes us.
+    let blocks: Vec<_> = (0..4).map(|n| block_info(n * 16384)).collect();
+    assert!(handle.send(PeerCommand::Request(blocks.clone())));
+    peer.send(Message::Unchoke).await.unwrap();
+
+    assert!(
+        matches!(next_event(&mut events).await, PeerEvent::StateChanged(state) if state.can_download())
+    );
+    assert_eq!(next_message(&mut peer).await, Message::Request(blocks[0]));
+    assert_eq!(next_message(&mut peer).await, Message::Request(blocks[1]));
+
+    // Every received block makes room for the next request.
+    let block = Block::new(&[3; 16384], blocks[0]);
+    peer.send(Message::Piece(block.clone())).await.unwrap();
+    assert!(matches!(next_event(&mut events).await, PeerEvent::Block(b) if b == block));
+    assert_eq!(next_message(&mut peer).await, Message::Request(blocks[2]));
+
+    // Choking drops outstanding requests, they are sent again after the unchoke.
+    peer.send(Message::Choke).await.unwrap();
+    assert!(
+        matches!(next_event(&mut events).await, PeerEvent::StateChanged(state) if !state.can_download())
+    );
+    peer.send(Message::Unchoke).await.unwrap();
+    next_event(&mut events).await;
+    assert_eq!(next_message(&mut peer).await, Message::Request(blocks[1]));
+    assert_eq!(next_message(&mut peer).await, Message::Request(blocks[2]));
+
+    // Cancelling an outstanding block tells the peer.
+    assert!(handle.send(PeerCommand::Cancel(blocks[2])));
+    assert_eq!(next_message(&mut peer).await, Message::Cancel(blocks[2]));
+
+    peer.send(Message::Have(7)).await.unwrap();
+    assert!(matches!(next_event(&mut events).await, PeerEvent::Have(7)));
+
+    drop(peer);
+    assert!(matches!(
+        next_event(&mut events).await,
+        PeerEvent::Disconnected
+    ));
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_peer_connection_wrong_info_hash() {
+    let (local, remote) = duplex(1 << 16);
+    let (events_tx, mut events) = mpsc::channel(64);
+    let (connection, _handle) =
+        PeerConnection::new(PeerInfo::new(addr()), info_hash(), ID::default(), events_tx);
+    let task = tokio::spawn(connection.run_on(local));
+
+    let _peer = fake_peer(remote, ID::from(vec![2; 20])).await;
+
+    assert!(matches!(
+        next_event(&mut events).await,
+        PeerEvent::Disconnected
+    ));
+    assert!(task.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_peer_connection_upload_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer_info = PeerInfo::new(listener.local_addr().unwrap());
+    let (events_tx, mut events) = mpsc::channel(64);
+    let (connection, handle) =
+        PeerConnection::new(peer_info, info_hash(), ID::default(), events_tx);
+    let task = tokio::spawn(connection.interested_on_connect(false).run());
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut peer = fake_peer(stream, info_hash()).await;
+    assert!(matches!(
+        next_event(&mut events).await,
+        PeerEvent::Connected(_)
+    ));
+
+    // Requests of a choked peer never reach the engine.
+    peer.send(Message::Interested).await.unwrap();
+    peer.send(Message::Request(block_info(0))).await.unwrap();
+    let expected = PeerState {
+        peer_interested: IntrestStatus::Interested,
+        ..PeerState::default()
+    };
+    assert!(
+        matches!(next_event(&mut events).await, PeerEvent::StateChanged(state) if state == expected)
+    );
+
+    handle.send(PeerCommand::Unchoke);
+    assert_eq!(next_message(&mut peer).await, Message::Unchoke);
+    assert!(
+        matches!(next_event(&mut events).await, PeerEvent::StateChanged(state) if state.can_upload())
+    );
+
+    peer.send(Message::Request(block_info(16384)))
+        .await
+        .unwrap();
+    assert!(
+        matches!(next_event(&mut events).await, PeerEvent::Request(b) if b == block_info(16384))
+    );
+
+    let block = Block::new(&[5; 16384], block_info(16384));
+    handle.send(PeerCommand::Piece(block.clone()));
+    assert_eq!(next_message(&mut peer).await, Message::Piece(block));
+
+    handle.send(PeerCommand::Shutdown);
+    assert!(matches!(
+        next_event(&mut events).await,
+        PeerEvent::Disconnected
+    ));
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_peer_connection_metadata_extension() {
+    let (local, remote) = duplex(1 << 16);
+    let (events_tx, mut events) = mpsc::channel(64);
+    let (connection, handle) =
+        PeerConnection::new(PeerInfo::new(addr()), info_hash(), ID::default(), events_tx);
+    let connection = connection
+        .interested_on_connect(false)
+        .with_metadata_size(1234)
+        .with_listen_port(6000);
+    let task = tokio::spawn(connection.run_on(local));
+
+    let mut framed = Framed::new(remote, HandshakeCodec);
+    assert!(framed.next().await.unwrap().unwrap().supports_extensions());
+    let handshake = Handshake::new(info_hash(), remote_id()).with_extensions();
+    framed.send(handshake).await.unwrap();
+    let mut peer = framed.map_codec(|_| MessageCodec::new());
+    next_event(&mut events).await;
+
+    let Message::Extended { id: 0, payload } = next_message(&mut peer).await else {
+        panic!("Expected the extended handshake");
+    };
+    let ours = ExtendedHandshake::from_bytes(&payload).unwrap();
+    assert_eq!(ours.metadata_size, Some(1234));
+    assert_eq!(ours.port, Some(6000));
+    assert!(ours.client.as_ref().unwrap().starts_with("torrus"));
+    assert!(ours.request_queue.is_some());
+    assert_eq!(ours.your_ip(), Some(addr().ip()));
+    let our_id = ours.extension_id(UT_METADATA).unwrap();
+
+    let theirs = ExtendedHandshake {
+        m: [(UT_METADATA.to_string(), 7)].into(),
+        request_queue: Some(1),
+        client: Some("test".into()),
+        ..Default::default()
+    };
+    peer.send(Message::Extended {
+        id: 0,
+        payload: theirs.to_bytes().unwrap(),
+    })
+    .await
+    .unwrap();
+    assert!(
+        matches!(next_event(&mut events).await, PeerEvent::ExtendedHandshake(h) if h == theirs)
+    );
+
+    let request = MetadataMessage::Request(0);
+    peer.send(Message::Extended {
+        id: our_id,
+        payload: request.to_bytes().unwrap(),
+    })
+    .await
+    .unwrap();
+    assert!(matches!(next_event(&mut events).await, PeerEvent::Metadata(m) if m == request));
+
+    handle.send(PeerCommand::Metadata(MetadataMessage::Reject(0)));
+    assert_eq!(
+        next_message(&mut peer).await,
+        Message::Extended {
+            id: 7,
+            payload: MetadataMessage::Reject(0).to_bytes().unwrap()
+        }
+    );
+
+    // Only as many requests as the peer queues are kept in flight.
+    let blocks: Vec<_> = (0..3).map(|n| block_info(n * 16384)).collect();
+    handle.send(PeerCommand::Request(blocks.clone()));
+    handle.send(PeerCommand::Interested);
+    assert_eq!(next_message(&mut peer).await, Message::Interested);
+    peer.send(Message::Unchoke).await.unwrap();
+    assert_eq!(next_message(&mut peer).await, Message::Request(blocks[0]));
+    let nothing = tokio::time::timeout(std::time::Duration::from_millis(100), peer.next()).await;
+    assert!(nothing.is_err());
+    let block = Block::new(&[1; 16384], blocks[0]);
+    peer.send(Message::Piece(block)).await.unwrap();
+    assert_eq!(next_message(&mut peer).await, Message::Request(blocks[1]));
+
+    handle.send(PeerCommand::Shutdown);
+    while !matches!(next_event(&mut events).await, PeerEvent::Disconnected) {}
+    task.await.unwrap().unwrap();
+}
@@ -0,0 +1,189 @@
+use rand::Rng;
+use std::collections::HashSet;
+use torrus_engine::{PickStrategy, PiecePicker};
+
+fn pieces(num_pieces: usize, have: &[usize]) -> Vec<bool> {
+    (0..num_pieces).map(|index| have.contains(&index)).collect()
+}
+
+#[test]
+fn test_picker_rarest_first() {
+    let mut picker = PiecePicker::new(4);
+    picker.add_peer(&pieces(4, &[0, 1, 2, 3]));
+    picker.add_peer(&pieces(4, &[0, 1, 2]));
+    picker.add_peer(&pieces(4, &[0, 1]));
+    assert_eq!(picker.availability(0), 3);
+    assert_eq!(picker.availability(3), 1);
+
+    let seeder = pieces(4, &[0, 1, 2, 3]);
+    assert_eq!(picker.pick(&seeder), Some(3));
+    assert_eq!(picker.pick(&seeder), Some(2));
+    let picked: HashSet<_> = [picker.pick(&seeder), picker.pick(&seeder)].into();
+    assert_eq!(picked, [Some(0), Some(1)].into());
+    assert_eq!(picker.pick(&seeder), None);
+    assert!(picker.all_picked());
+
+    // Released pieces can be picked again, downloaded ones can not.
+    picker.release(1, false);
+    picker.set_have(0);
+    picker.release(0, false);
+    assert!(!picker.wants(0));
+    assert!(!picker.all_picked());
+    assert_eq!(picker.pick(&seeder), Some(1));
+}
+
+#[test]
+fn test_picker_respects_peer_pieces() {
+    let mut picker = PiecePicker::new(8);
+    let peer = pieces(8, &[5, 6]);
+    picker.add_peer(&peer);
+    picker.add_peer(&pieces(8, &[0, 1, 2, 3, 4]));
+
+    let mut picked = vec![picker.pick(&peer).unwrap(), picker.pick(&peer).unwrap()];
+    picked.sort();
+    assert_eq!(picked, vec![5, 6]);
+    assert_eq!(picker.pick(&peer), None);
+    assert_eq!(picker.pick(&pieces(8, &[7])), None);
+}
+
+#[test]
+fn test_picker_prefers_partial_pieces() {
+    let mut picker = PiecePicker::new(3);
+    picker.add_peer(&pieces(3, &[0, 1, 2]));
+    picker.add_peer(&pieces(3, &[0, 1]));
+    picker.add_have(0);
+
+    let seeder = pieces(3, &[0, 1, 2]);
+    assert_eq!(picker.pick(&seeder), Some(2));
+    picker.release(2, false);
+    assert_eq!(picker.pick(&seeder), Some(2));
+    assert_eq!(picker.pick(&seeder), Some(1));
+    // The most common piece is finished first once it was started.
+    picker.release(2, false);
+    picker.release(1, true);
+    assert_eq!(picker.pick(&seeder), Some(1));
+
+    // Partial pieces the peer does not have are left alone.
+    picker.release(1, true);
+    assert_eq!(picker.pick(&pieces(3, &[0, 2])), Some(2));
+}
+
+#[test]
+fn test_picker_updates_availability() {
+    let mut picker = PiecePicker::new(3);
+    let first = pieces(3, &[0, 1]);
+    picker.add_peer(&first);
+    picker.add_peer(&pieces(3, &[1, 2]));
+    picker.add_have(2);
+    picker.remove_peer(&first);
+    assert_eq!(
+        (0..3).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+        vec![0, 1, 2]
+    );
+
+    // Availability keeps being counted for downloaded pieces.
+    picker.set_have(1);
+    picker.add_have(1);
+    assert_eq!(picker.availability(1), 2);
+    assert_eq!(picker.pick(&pieces(3, &[0, 1, 2])), Some(2));
+}
+
+#[test]
+fn test_picker_random_ties() {
+    let seeder = vec![true; 100];
+    let picks: HashSet<_> = (0..20)
+        .map(|_| {
+            let mut picker = PiecePicker::new(100);
+            picker.add_peer(&seeder);
+            picker.pick(&seeder)
+        })
+        .collect();
+    assert!(picks.len() > 1);
+}
+
+#[test]
+fn test_picker_random_swarm() {
+    const NUM_PIECES: usize = 500;
+    let mut rng = rand::thread_rng();
+    let mut picker = PiecePicker::new(NUM_PIECES);
+    let mut peers: Vec<Vec<bool>> = Vec::new();
+
+    for round in 0..200 {
+        match rng.gen_range(0..4) {
+            0 | 1 => {
+                let peer: Vec<bool> = (0..NUM_PIECES).map(|_| rng.gen_bool(0.3)).collect();
+                picker.add_peer(&peer);
+                peers.push(peer);
+            }
+            2 if !peers.is_empty() => {
+                let peer = peers.swap_remove(rng.gen_range(0..peers.len()));
+                picker.remove_peer(&peer);
+            }
+            _ => picker.set_have(rng.gen_range(0..NUM_PIECES)),
+        }
+        if let Some(peer) = peers.last_mut() {
+            let index = rng.gen_range(0..NUM_PIECES);
+            if !peer[index] {
+                peer[index] = true;
+                picker.add_have(index);
+            }
+        }
+
+        for index in 0..NUM_PIECES {
+            let expected = peers.iter().filter(|peer| peer[index]).count() as u32;
+            assert_eq!(picker.availability(index), expected, "round {round}");
+        }
+    }
+
+    // A peer with every piece gets the wanted pieces from the rarest to the most common.
+    let seeder = vec![true; NUM_PIECES];
+    let mut last = 1;
+    while let Some(index) = picker.pick(&seeder) {
+        assert!(picker.wants(index));
+        let availability = picker.availability(index);
+        assert!(availability >= last);
+        last = availability;
+    }
+}
+
+#[test]
+fn test_picker_sequential() {
+    let mut picker = PiecePicker::new(5);
+    picker.set_strategy(PickStrategy::Sequential);
+    picker.add_peer(&pieces(5, &[0, 1, 2, 3, 4]));
+    picker.add_peer(&pieces(5, &[0, 1, 2, 3]));
+    picker.set_have(0);
+
+    let peer = pieces(5, &[0, 1, 3, 4]);
+    assert_eq!(picker.pick(&peer), Some(1));
+    assert_eq!(picker.pick(&peer), Some(3));
+    picker.set_have(1);
+    picker.release(3, false);
+    assert_eq!(picker.pick(&peer), Some(3));
+    assert_eq!(picker.pick(&peer), Some(4));
+    assert_eq!(picker.pick(&peer), None);
+
+    picker.set_strategy(PickStrategy::RarestFirst);
+    assert_eq!(picker.pick(&pieces(5, &[0, 1, 2, 3, 4])), Some(2));
+}
+
+#[test]
+fn test_picker_urgent_pieces() {
+    let mut picker = PiecePicker::new(6);
+    let seeder = pieces(6, &[0, 1, 2, 3, 4, 5]);
+    picker.add_peer(&seeder);
+    picker.add_peer(&pieces(6, &[0, 1, 2, 3, 4]));
+    picker.set_have(4);
+    picker.set_urgent(vec![4, 3, 1]);
+
+    assert_eq!(picker.pick(&pieces(6, &[1, 5])), Some(1));
+    assert_eq!(picker.pick(&seeder), Some(3));
+    assert_eq!(picker.pick(&seeder), Some(5));
+
+    // Specific pieces can be picked unless they are busy or downloaded.
+    assert!(picker.pick_piece(0));
+    assert!(!picker.pick_piece(0));
+    assert!(!picker.pick_piece(4));
+    assert_eq!(picker.pick(&seeder), Some(2));
+    assert!(picker.all_picked());
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+use torrus_core::{
+    prelude::{Block, Blockinfo, Info, ID},
+    store::Store,
+    validate::sanitize_name,
+};
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The torrent was never passed to [Store::new_store].
+    UnknownTorrent(ID),
+    /// The block reaches past the end of the torrent.
+    OutOfBounds(Blockinfo),
+    /// A file path of the torrent would end up outside of its directory.
+    InvalidPath(Vec<String>),
+    Io(io::Error),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::UnknownTorrent(id) => write!(f, "Unknown torrent {id}"),
+            StoreError::OutOfBounds(block_info) => {
+                write!(f, "Block {block_info:?} is out of the torrent's bounds")
+            }
+            StoreError::InvalidPath(path) => write!(f, "Invalid file path {path:?}"),
+            StoreError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(value: io::Error) -> Self {
+        StoreError::Io(value)
+    }
+}
+
+/// A file of a torrent and where it starts in the torrent's byte stream.
+#[derive(Debug)]
+struct FileEntry {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// Part of a block inside one file: the file, the offset in the file and the range of the block.
+type Chunk<'a> = (&'a FileEntry, u64, Range<usize>);
+
+/// Files of a torrent in the order their data is concatenated into pieces.
+#[derive(Debug)]
+struct Layout {
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    length: u64,
+}
+
+impl Layout {
+    fn new(root: &Path, info: &Info) -> Result<Self, StoreError> {
+        // Padding files are never written, blocks covering them read as zeros.
+        let files = info
+            .files()
+            .into_iter()
+            .filter(|file| !file.padding)
+            .map(|file| {
+                Ok(FileEntry {
+                    path: root.join(checked_path(&file.path)?),
+                    offset: file.offset,
+                    length: file.length,
+                })
+            })
+            .collect::<Result<_, StoreError>>()?;
+
+        Ok(Self {
+            files,
+            piece_length: info.piece_length,
+            length: info.total_length(),
+        })
+    }
+
+    /// Splits a block at file boundaries.
+    fn chunks(&self, block_info: &Blockinfo) -> Result<Vec<Chunk<'_>>, StoreError> {
+        let start = block_info.index as u64 * self.piece_length + block_info.offset;
+        let end = start + block_info.length;
+        if block_info.offset + block_info.length > self.piece_length || end > self.length {
+            return Err(StoreError::OutOfBounds(*block_info));
+        }
+
+        // First file which ends after the start of the block, empty files are skipped.
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= start);
+
+        let chunks = self.files[first..]
+            .iter()
+            .take_while(|file| file.offset < end)
+            .filter(|file| file.length > 0)
+            .map(|file| {
+                let from = start.max(file.offset);
+                let to = end.min(file.offset + file.length);
+                let range = (from - start) as usize..(to - start) as usize;
+                (file, from - file.offset, range)
+            })
+            .collect();
+        Ok(chunks)
+    }
+}
+
+/// Joins path components of a torrent, rejecting anything which could escape its directory.
+/// Names are sanitized so they can be created on any filesystem.
+fn checked_path(components: &[String]) -> Result<PathBuf, StoreError> {
+    let mut path = PathBuf::new();
+    for component in components {
+        let mut parsed = Path::new(component).components();
+        match (parsed.next(), parsed.next()) {
+            (Some(Component::Normal(_)), None) => path.push(sanitize_name(component)),
+            _ => return Err(StoreError::InvalidPath(components.to_vec())),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err(StoreError::InvalidPath(components.to_vec()));
+    }
+    Ok(path)
+}
+
+/// [Store] writing torrents to disk, laid out the same way as in their metainfo.
+///
+/// A single file torrent is stored as `root/name`, a multi file torrent as `root/name/path`.
+/// Files are created sparse on the first write.
+pub struct FileStore {
+    root: PathBuf,
+    torrents: HashMap<ID, Layout>,
+}
+
+impl FileStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            torrents: HashMap::new(),
+        }
+    }
+
+    fn layout(&self, id: ID) -> Result<&Layout, StoreError> {
+        self.torrents.get(&id).ok_or(StoreError::UnknownTorrent(id))
+    }
+
+    fn read_block(&self, id: ID, block_info: Blockinfo) -> Result<Block, StoreError> {
+        let layout = self.layout(id)?;
+        let mut data = vec![0; block_info.length as usize];
+        for (file, offset, range) in layout.chunks(&block_info)? {
+            let mut handle = File::open(&file.path)?;
+            handle.seek(SeekFrom::Start(offset))?;
+            handle.read_exact(&mut data[range])?;
+        }
+        Ok(Block::new(&data, block_info))
+    }
+}
+
+impl Store for FileStore {
+    type Err = StoreError;
+
+    fn new_store(&mut self, id: ID, info: &Info) -> Result<(), Self::Err> {
+        let layout = Layout::new(&self.root, info)?;
+        for file in &layout.files {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Empty files never receive a block.
+            if file.length == 0 {
+                File::create(&file.path)?;
+            }
+        }
+        self.torrents.insert(id, layout);
+        Ok(())
+    }
+
+    fn put_block(&mut self, id: ID, block: Block) -> Result<(), Self::Err> {
+        let layout = self.layout(id)?;
+        if block.len() as u64 != block.block_info.length {
+            return Err(StoreError::OutOfBounds(block.block_info));
+        }
+        for (file, offset, range) in layout.chunks(&block.block_info)? {
+            let mut handle = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)?;
+            handle.seek(SeekFrom::Start(offset))?;
+            handle.write_all(&block[range])?;
+        }
+        Ok(())
+    }
+
+    fn get_block(&self, id: ID, block_info: Blockinfo) -> Option<Block> {
+        self.read_block(id, block_info).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use torrus_core::prelude::Metainfo;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrus-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn block(index: usize, offset: u64, length: u64) -> Block {
+        let data: Vec<u8> = (0..length).map(|n| (n % 251) as u8).collect();
+        let block_info = Blockinfo {
+            offset,
+            length,
+            index,
+        };
+        Block::new(&data, block_info)
+    }
+
+    #[test]
+    fn test_file_store_multi_file() -> Result<()> {
+        let metainfo = Metainfo::new(&fs::read("../resources/multi.torrent")?)?;
+        let info = &metainfo.info;
+        let files = info.files.as_ref().unwrap();
+        let dir = test_dir("multi");
+        let id = ID::from(vec![1; 20]);
+
+        let mut store = FileStore::new(&dir);
+        store.new_store(id, info)?;
+
+        // The piece holding the end of the first file and the start of the second one.
+        let boundary = files[0].length;
+        let index = (boundary / info.piece_length) as usize;
+        let offset = boundary % info.piece_length - 100;
+        let written = block(index, offset, 300);
+        store.put_block(id, written.clone())?;
+
+        assert_eq!(
+            store.get_block(id, written.block_info),
+            Some(written.clone())
+        );
+
+        let first = dir.join(&info.name).join(&files[0].path[0]);
+        let second = dir.join(&info.name).join(&files[1].path[0]);
+        let first = fs::read(first)?;
+        let second = fs::read(second)?;
+        assert_eq!(first.len() as u64, files[0].length);
+        assert_eq!(&first[first.len() - 100..], &written[..100]);
+        assert_eq!(&second[..200], &written[100..]);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store_single_file() -> Result<()> {
+        let metainfo = Metainfo::new(&fs::read(
+            "../resources/ubuntu-22.10-desktop-amd64.iso.torrent",
+        )?)?;
+        let info = &metainfo.info;
+        let dir = test_dir("single");
+        let id = ID::from(vec![2; 20]);
+
+        let mut store = FileStore::new(&dir);
+        store.new_store(id, info)?;
+
+        // The last block of the torrent.
+        let last = (info.length - 1) / info.piece_length;
+        let length = info.length - last * info.piece_length;
+        let written = block(last as usize, 0, length);
+        store.put_block(id, written.clone())?;
+        assert_eq!(store.get_block(id, written.block_info), Some(written));
+        assert_eq!(fs::metadata(dir.join(&info.name))?.len(), info.length);
+
+        // One byte past the end.
+        let past_end = block(last as usize, 0, length + 1);
+        assert!(matches!(
+            store.put_block(id, past_end),
+            Err(StoreError::OutOfBounds(_))
+        ));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store_padding() -> Result<()> {
+        let torrent = format!(
+            "d4:infod5:filesld6:lengthi10e4:pathl1:aeed4:attr1:p6:lengthi6e4:pathl4:.pad1:6ee\
+                d6:lengthi4e4:pathl1:beee4:name3:pad12:piece lengthi16e6:pieces40:{}ee",
+            "x".repeat(40)
+        );
+        let metainfo = Metainfo::new(torrent.as_bytes())?;
+        let dir = test_dir("padding");
+        let id = ID::from(vec![4; 20]);
+
+        let mut store = FileStore::new(&dir);
+        store.new_store(id, &metainfo.info)?;
+
+        // The padding file is skipped, the second file starts with the second piece.
+        let first = block(0, 0, 16);
+        store.put_block(id, first.clone())?;
+        store.put_block(id, block(1, 0, 4))?;
+        assert_eq!(fs::read(dir.join("pad/a"))?, &first[..10]);
+        assert_eq!(fs::read(dir.join("pad/b"))?, &block(1, 0, 4)[..]);
+        assert!(!dir.join("pad/.pad").exists());
+
+        let read = store.get_block(id, first.block_info).unwrap();
+        assert_eq!(&read[..10], &first[..10]);
+        assert_eq!(&read[10..], &[0; 6]);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store_errors() {
+        let mut store = FileStore::new(test_dir("errors"));
+        let id = ID::from(vec![3; 20]);
+        assert!(matches!(
+            store.put_block(id, block(0, 0, 10)),
+            Err(StoreError::UnknownTorrent(_))
+        ));
+        assert_eq!(store.get_block(id, block(0, 0, 10).block_info), None);
+
+        assert!(checked_path(&["a".into(), "b".into()]).is_ok());
+        assert_eq!(
+            checked_path(&["a:b".into(), "aux".into()]).unwrap(),
+            Path::new("a_b").join("_aux")
+        );
+        for invalid in [
+            vec!["..".to_string()],
+            vec!["a".into(), "..".into()],
+            vec!["/etc".into()],
+            vec!["a/b".into()],
+            vec![".".into()],
+            vec![],
+        ] {
+            assert!(checked_path(&invalid).is_err(), "{invalid:?}");
+        }
+    }
+}
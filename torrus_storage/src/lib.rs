@@ -1,4 +1,7 @@
+mod file_store;
+pub mod merkle;
 pub mod piece;
 mod storage;
 
+pub use file_store::*;
 pub use storage::*;
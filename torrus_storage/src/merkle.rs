@@ -0,0 +1,517 @@
+//! Verification of v2 torrents against the merkle trees of their files, see BEP 52.
+//!
+//! The root of a file's tree is its `pieces root`, the layer whose hashes cover one piece
+//! each is its entry in the `piece layers` of the metainfo.
+
+use crate::piece::BLOCK_SIZE;
+use anyhow::{bail, Result};
+use torrus_core::prelude::{HashRequest, Metainfo};
+
+pub use torrus_core::merkle::{block_hash, hash_pair, pad_hash, root_from_proof, Hash, MerkleTree};
+
+/// Most hashes asked for in one hash request.
+pub const MAX_HASHES: usize = 512;
+
+/// Hashes known about one file of a v2 torrent: its pieces root and the piece layer, which
+/// comes with the torrent file or from peers through hash requests.
+#[derive(Debug, Clone)]
+pub struct FileHashes {
+    pieces_root: Hash,
+    /// Number of 16 KiB blocks of the file.
+    num_blocks: usize,
+    /// Number of blocks in a piece, a power of two.
+    piece_blocks: usize,
+    /// Verified hashes of the piece layer.
+    pieces: Vec<Option<Hash>>,
+}
+
+impl FileHashes {
+    pub fn new(pieces_root: Hash, length: u64, piece_length: u64) -> Result<Self> {
+        if piece_length < BLOCK_SIZE || !piece_length.is_power_of_two() {
+            bail!("Piece length {piece_length} is not a power of two of at least 16 KiB");
+        }
+        let mut pieces = vec![None; length.div_ceil(piece_length) as usize];
+        // The root of a file no larger than a piece is the hash of its only piece.
+        if pieces.len() == 1 {
+            pieces[0] = Some(pieces_root);
+        }
+        Ok(Self {
+            pieces_root,
+            num_blocks: length.div_ceil(BLOCK_SIZE) as usize,
+            piece_blocks: (piece_length / BLOCK_SIZE) as usize,
+            pieces,
+        })
+    }
+
+    pub fn pieces_root(&self) -> &Hash {
+        &self.pieces_root
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Layer of the piece hashes, blocks are layer 0.
+    pub fn piece_layer(&self) -> u32 {
+        self.piece_blocks.trailing_zeros()
+    }
+
+    fn root_layer(&self) -> u32 {
+        self.num_blocks.next_power_of_two().trailing_zeros()
+    }
+
+    /// Number of leaves below a piece hash, files no larger than a piece have fewer.
+    fn piece_width(&self) -> usize {
+        match self.pieces.len() {
+            1 => self.num_blocks.next_power_of_two(),
+            _ => self.piece_blocks,
+        }
+    }
+
+    /// Whether the hash of every piece is known.
+    pub fn has_piece_layer(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    pub fn piece_hash(&self, index: usize) -> Option<Hash> {
+        *self.pieces.get(index)?
+    }
+
+    /// Sets the piece layer from the `piece layers` of the metainfo, the concatenated hashes
+    /// must lead to the pieces root.
+    pub fn set_piece_layer(&mut self, layer: &[u8]) -> Result<()> {
+        if layer.len() != self.pieces.len() * 32 {
+            bail!(
+                "Piece layer of {} bytes for {} pieces",
+                layer.len(),
+                self.pieces.len()
+            );
+        }
+        let hashes: Vec<Hash> = layer
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        if MerkleTree::new(hashes.clone(), self.piece_layer()).root() != self.pieces_root {
+            bail!("Piece layer does not match the pieces root");
+        }
+        self.pieces = hashes.into_iter().map(Some).collect();
+        Ok(())
+    }
+
+    /// Checks the data of the piece at `index` of the file, `None` while its hash is unknown.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> Option<bool> {
+        let expected = self.piece_hash(index)?;
+        let mut leaves: Vec<Hash> = data.chunks(BLOCK_SIZE as usize).map(block_hash).collect();
+        leaves.resize(self.piece_width(), [0; 32]);
+        Some(MerkleTree::new(leaves, 0).root() == expected)
+    }
+
+    /// Blocks of a piece's `data` which do not match `block_hashes`, the verified hashes
+    /// answering the [FileHashes::block_request] of the piece.
+    pub fn bad_blocks(&self, data: &[u8], block_hashes: &[Hash]) -> Vec<usize> {
+        data.chunks(BLOCK_SIZE as usize)
+            .zip(block_hashes)
+            .enumerate()
+            .filter(|(_, (block, hash))| block_hash(block) != **hash)
+            .map(|(number, _)| number)
+            .collect()
+    }
+
+    /// Requests for the missing parts of the piece layer, for torrents added without it like
+    /// from a magnet link. Each answer carries the uncle hashes leading to the pieces root.
+    pub fn piece_layer_requests(&self) -> Vec<HashRequest> {
+        // Nothing to ask for, files no larger than a piece have their root as piece hash.
+        if self.has_piece_layer() {
+            return Vec::new();
+        }
+        let length = self.pieces.len().next_power_of_two().min(MAX_HASHES);
+        let proof_layers = self.root_layer() - self.piece_layer() - length.trailing_zeros();
+        (0..self.pieces.len())
+            .step_by(length)
+            .filter(|index| {
+                let end = (index + length).min(self.pieces.len());
+                self.pieces[*index..end].iter().any(Option::is_none)
+            })
+            .map(|index| HashRequest {
+                pieces_root: self.pieces_root,
+                base_layer: self.piece_layer(),
+                index: index as u32,
+                length: length as u32,
+                proof_layers,
+            })
+            .collect()
+    }
+
+    /// Request for the block hashes of the piece at `index`, which point out the blocks of a
+    /// piece failing verification so only those are downloaded again. `None` while the
+    /// piece hash is unknown, or if the piece is a single block.
+    pub fn block_request(&self, index: usize) -> Option<HashRequest> {
+        self.piece_hash(index)?;
+        let width = self.piece_width();
+        if width < 2 {
+            return None;
+        }
+        Some(HashRequest {
+            pieces_root: self.pieces_root,
+            base_layer: 0,
+            index: (index * width) as u32,
+            length: width as u32,
+            proof_layers: 0,
+        })
+    }
+
+    /// Checks the hashes a peer answered `request` with, the requested hashes followed by
+    /// the uncle hashes. They must lead to the pieces root or to a known piece hash.
+    ///
+    /// Returns the verified hashes of the base layer, piece hashes are remembered.
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash]) -> Result<Vec<Hash>> {
+        let length = request.length as usize;
+        let index = request.index as usize;
+        if request.pieces_root != self.pieces_root {
+            bail!("Hashes of another file");
+        }
+        if !length.is_power_of_two() || !index.is_multiple_of(length) {
+            bail!("Invalid hash request {request:?}");
+        }
+        if hashes.len() != length + request.proof_layers as usize {
+            bail!(
+                "Expected {} hashes, got {}",
+                length + request.proof_layers as usize,
+                hashes.len()
+            );
+        }
+
+        let (base, uncles) = hashes.split_at(length);
+        let subtree = MerkleTree::new(base.to_vec(), request.base_layer);
+        let node = root_from_proof(subtree.root(), index / length, uncles);
+        let layer = subtree.root_layer() + request.proof_layers;
+        let position = (index / length) >> request.proof_layers;
+        let expected = if layer == self.root_layer() && position == 0 {
+            Some(self.pieces_root)
+        } else if layer == self.piece_layer() {
+            self.piece_hash(position)
+        } else {
+            None
+        };
+        match expected {
+            Some(expected) if expected == node => {}
+            Some(_) => bail!("Hashes do not match the merkle tree"),
+            None => bail!("Hashes can not be verified, their proof ends at layer {layer}"),
+        }
+
+        if request.base_layer == self.piece_layer() {
+            for (piece, hash) in self.pieces.iter_mut().skip(index).zip(base) {
+                *piece = Some(*hash);
+            }
+        }
+        Ok(base.to_vec())
+    }
+
+    /// Answers a peer's `request` from the piece layer and the layers above it, the requested
+    /// hashes followed by the uncle hashes. `None` if we do not hold them.
+    pub fn answer(&self, request: &HashRequest) -> Option<Vec<Hash>> {
+        let length = request.length as usize;
+        let index = request.index as usize;
+        if request.pieces_root != self.pieces_root
+            || request.base_layer < self.piece_layer()
+            || !length.is_power_of_two()
+            || length > MAX_HASHES
+            || !index.is_multiple_of(length)
+        {
+            return None;
+        }
+        let pieces = self.pieces.iter().copied().collect::<Option<Vec<_>>>()?;
+        let tree = MerkleTree::new(pieces, self.piece_layer());
+        let hashes = tree.layer(request.base_layer)?.get(index..index + length)?;
+        let uncles = tree.proof(request.base_layer + length.trailing_zeros(), index / length);
+        let uncles = uncles.get(..request.proof_layers as usize)?;
+        Some([hashes, uncles].concat())
+    }
+}
+
+/// [FileHashes] of the files of a v2 or hybrid torrent which have data, in the order of
+/// [torrus_core::prelude::Info::files], with the piece layers the metainfo carries.
+pub fn file_hashes(metainfo: &Metainfo) -> Result<Vec<FileHashes>> {
+    metainfo
+        .info
+        .files()
+        .into_iter()
+        .filter_map(|file| Some((file.pieces_root?, file.length)))
+        .map(|(pieces_root, length)| hashes_of(metainfo, pieces_root, length))
+        .collect()
+}
+
+fn hashes_of(metainfo: &Metainfo, pieces_root: Hash, length: u64) -> Result<FileHashes> {
+    let mut hashes = FileHashes::new(pieces_root, length, metainfo.info.piece_length)?;
+    if let Some(layer) = metainfo.piece_layer(&pieces_root) {
+        hashes.set_piece_layer(layer)?;
+    }
+    Ok(hashes)
+}
+
+/// Merkle hashes of the pieces of a hybrid torrent, which are checked against both their
+/// SHA-1 hash and the tree of their file. Padding files make every file start at a piece
+/// boundary, so no piece holds data of two files.
+#[derive(Debug, Clone)]
+pub struct TorrentHashes {
+    piece_length: u64,
+    /// Files with data by the index of their first piece, with their length.
+    files: Vec<(usize, u64, FileHashes)>,
+}
+
+impl TorrentHashes {
+    pub fn new(metainfo: &Metainfo) -> Result<Self> {
+        let piece_length = metainfo.info.piece_length;
+        let mut files = Vec::new();
+        for file in metainfo.info.files() {
+            let Some(pieces_root) = file.pieces_root else {
+                continue;
+            };
+            if !file.offset.is_multiple_of(piece_length) {
+                bail!("File {:?} does not start at a piece boundary", file.path);
+            }
+            let hashes = hashes_of(metainfo, pieces_root, file.length)?;
+            files.push(((file.offset / piece_length) as usize, file.length, hashes));
+        }
+        Ok(Self {
+            piece_length,
+            files,
+        })
+    }
+
+    /// The file holding the torrent's piece at `index`, the index of the piece in that file
+    /// and the length of the file's data in the piece. `None` if the piece only holds padding.
+    fn locate(&self, index: usize) -> Option<(&FileHashes, usize, usize)> {
+        let position = self.files.partition_point(|(first, ..)| *first <= index);
+        let (first, length, hashes) = &self.files[position.checked_sub(1)?];
+        let start = (index - first) as u64 * self.piece_length;
+        let end = length.checked_sub(start).filter(|end| *end > 0)?;
+        Some((hashes, index - first, end.min(self.piece_length) as usize))
+    }
+
+    /// Whether the piece at `index` can be verified, which needs the piece layer of its file.
+    pub fn can_verify(&self, index: usize) -> bool {
+        self.locate(index)
+            .is_none_or(|(hashes, piece, _)| hashes.piece_hash(piece).is_some())
+    }
+
+    /// Whether the piece layer of every file is known.
+    pub fn has_piece_layers(&self) -> bool {
+        self.files
+            .iter()
+            .all(|(.., hashes)| hashes.has_piece_layer())
+    }
+
+    /// Checks the data of the torrent's piece at `index`, padding included. Pieces which only
+    /// hold padding pass, pieces whose hash is not known yet fail.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        let Some((hashes, piece, length)) = self.locate(index) else {
+            return true;
+        };
+        data.get(..length)
+            .and_then(|data| hashes.verify_piece(piece, data))
+            .unwrap_or(false)
+    }
+
+    /// Requests for the missing parts of every file's piece layer.
+    pub fn piece_layer_requests(&self) -> Vec<HashRequest> {
+        self.files
+            .iter()
+            .flat_map(|(.., hashes)| hashes.piece_layer_requests())
+            .collect()
+    }
+
+    /// Request for the block hashes of the torrent's piece at `index`, see
+    /// [FileHashes::block_request].
+    pub fn block_request(&self, index: usize) -> Option<HashRequest> {
+        let (hashes, piece, _) = self.locate(index)?;
+        hashes.block_request(piece)
+    }
+
+    /// Blocks of the torrent's piece at `index` which do not match `block_hashes`, see
+    /// [FileHashes::bad_blocks].
+    pub fn bad_blocks(&self, index: usize, data: &[u8], block_hashes: &[Hash]) -> Vec<usize> {
+        match self.locate(index) {
+            Some((hashes, _, length)) => hashes.bad_blocks(&data[..length], block_hashes),
+            None => Vec::new(),
+        }
+    }
+
+    /// Checks the hashes a peer answered `request` with, see [FileHashes::add_hashes].
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash]) -> Result<Vec<Hash>> {
+        match self.file_mut(&request.pieces_root) {
+            Some(file) => file.add_hashes(request, hashes),
+            None => bail!("Hashes of an unknown file"),
+        }
+    }
+
+    /// Answers a peer's `request`, see [FileHashes::answer].
+    pub fn answer(&self, request: &HashRequest) -> Option<Vec<Hash>> {
+        self.files
+            .iter()
+            .find(|(.., hashes)| *hashes.pieces_root() == request.pieces_root)
+            .and_then(|(.., hashes)| hashes.answer(request))
+    }
+
+    fn file_mut(&mut self, pieces_root: &Hash) -> Option<&mut FileHashes> {
+        self.files
+            .iter_mut()
+            .map(|(.., hashes)| hashes)
+            .find(|hashes| hashes.pieces_root() == pieces_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECE_LENGTH: u64 = BLOCK_SIZE * 2;
+
+    /// Five blocks, the last one short, in three pieces of two blocks.
+    fn file_data() -> Vec<u8> {
+        (0..BLOCK_SIZE * 4 + 100).map(|n| (n % 251) as u8).collect()
+    }
+
+    fn piece(data: &[u8], index: usize) -> &[u8] {
+        let start = index * PIECE_LENGTH as usize;
+        &data[start..data.len().min(start + PIECE_LENGTH as usize)]
+    }
+
+    #[test]
+    fn test_file_hashes_piece_layer() -> Result<()> {
+        let data = file_data();
+        let tree = MerkleTree::from_data(&data);
+        let layer = tree.layer(1).unwrap()[..3].concat();
+
+        let mut hashes = FileHashes::new(tree.root(), data.len() as u64, PIECE_LENGTH)?;
+        assert_eq!(hashes.num_pieces(), 3);
+        assert!(!hashes.has_piece_layer());
+        assert_eq!(hashes.verify_piece(0, piece(&data, 0)), None);
+        assert!(hashes.set_piece_layer(&layer[32..]).is_err());
+        assert!(hashes
+            .set_piece_layer(&[&layer[..64], &[0; 32]].concat())
+            .is_err());
+
+        hashes.set_piece_layer(&layer)?;
+        for index in 0..3 {
+            assert_eq!(hashes.verify_piece(index, piece(&data, index)), Some(true));
+        }
+        assert_eq!(hashes.verify_piece(0, piece(&data, 1)), Some(false));
+
+        // A single piece file is verified against its root.
+        let small = &data[..BLOCK_SIZE as usize + 1];
+        let hashes = FileHashes::new(MerkleTree::from_data(small).root(), 16385, 1 << 16)?;
+        assert!(hashes.has_piece_layer());
+        assert_eq!(hashes.verify_piece(0, small), Some(true));
+        assert!(FileHashes::new(tree.root(), 10, 3 << 14).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_hashes_from_peers() -> Result<()> {
+        let data = file_data();
+        let tree = MerkleTree::from_data(&data);
+        let mut hashes = FileHashes::new(tree.root(), data.len() as u64, PIECE_LENGTH)?;
+
+        // The whole piece layer fits into one request, which reaches the root on its own.
+        let requests = hashes.piece_layer_requests();
+        assert_eq!(
+            requests,
+            [HashRequest {
+                pieces_root: tree.root(),
+                base_layer: 1,
+                index: 0,
+                length: 4,
+                proof_layers: 0,
+            }]
+        );
+        let mut forged = tree.layer(1).unwrap().to_vec();
+        forged[1] = [1; 32];
+        assert!(hashes.add_hashes(&requests[0], &forged).is_err());
+        assert!(!hashes.has_piece_layer());
+
+        // Half of the layer, proven by the uncle above it.
+        let half = HashRequest {
+            length: 2,
+            proof_layers: 1,
+            ..requests[0]
+        };
+        let answer = [&tree.layer(1).unwrap()[..2], &tree.proof(2, 0)[..]].concat();
+        hashes.add_hashes(&half, &answer)?;
+        assert_eq!(hashes.piece_hash(1), Some(tree.layer(1).unwrap()[1]));
+        assert_eq!(hashes.piece_hash(2), None);
+
+        hashes.add_hashes(&requests[0], tree.layer(1).unwrap())?;
+        assert!(hashes.has_piece_layer());
+        assert!(hashes.piece_layer_requests().is_empty());
+
+        // A file smaller than a piece has nothing to ask for.
+        let small = &data[..100];
+        let hashes = FileHashes::new(MerkleTree::from_data(small).root(), 100, 1 << 16)?;
+        assert!(hashes.piece_layer_requests().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_hashes_answer() -> Result<()> {
+        let data = file_data();
+        let tree = MerkleTree::from_data(&data);
+        let mut ours = FileHashes::new(tree.root(), data.len() as u64, PIECE_LENGTH)?;
+        let mut theirs = ours.clone();
+        let request = theirs.piece_layer_requests()[0];
+        assert_eq!(ours.answer(&request), None);
+
+        ours.set_piece_layer(&tree.layer(1).unwrap()[..3].concat())?;
+        let answer = ours.answer(&request).unwrap();
+        theirs.add_hashes(&request, &answer)?;
+        assert!(theirs.has_piece_layer());
+
+        // Part of the layer comes with the uncles proving it.
+        let half = HashRequest {
+            index: 2,
+            length: 2,
+            proof_layers: 1,
+            ..request
+        };
+        let answer = ours.answer(&half).unwrap();
+        assert_eq!(answer.len(), 3);
+        ours.add_hashes(&half, &answer)?;
+
+        // Block hashes are not kept and other files are not ours.
+        let blocks = ours.block_request(0).unwrap();
+        assert_eq!(ours.answer(&blocks), None);
+        let other = HashRequest {
+            pieces_root: [1; 32],
+            ..request
+        };
+        assert_eq!(ours.answer(&other), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_hashes_bad_blocks() -> Result<()> {
+        let data = file_data();
+        let tree = MerkleTree::from_data(&data);
+        let mut hashes = FileHashes::new(tree.root(), data.len() as u64, PIECE_LENGTH)?;
+        assert_eq!(hashes.block_request(1), None);
+        hashes.set_piece_layer(&tree.layer(1).unwrap()[..3].concat())?;
+
+        let mut corrupt = piece(&data, 1).to_vec();
+        corrupt[BLOCK_SIZE as usize + 7] ^= 1;
+        assert_eq!(hashes.verify_piece(1, &corrupt), Some(false));
+
+        let request = hashes.block_request(1).unwrap();
+        assert_eq!(
+            (request.base_layer, request.index, request.length),
+            (0, 2, 2)
+        );
+        let block_hashes = hashes.add_hashes(&request, &tree.layer(0).unwrap()[2..4])?;
+        assert_eq!(hashes.bad_blocks(&corrupt, &block_hashes), [1]);
+
+        // Block hashes of another piece do not lead to this piece's hash.
+        assert!(hashes
+            .add_hashes(&request, &tree.layer(0).unwrap()[..2])
+            .is_err());
+        Ok(())
+    }
+}
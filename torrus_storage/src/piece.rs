@@ -1,11 +1,12 @@
 use anyhow::Result;
-use std::{io::prelude::*, io::Cursor, io::SeekFrom};
+use sha1::{Digest, Sha1};
 use torrus_core::{
     id::ID,
     prelude::{Block, Blockinfo, Sha1Hash},
 };
 
-const BLOCK_SIZE: u64 = 2 << 14;
+/// Length of the blocks a piece is split into, only the last block of a piece may be shorter.
+pub const BLOCK_SIZE: u64 = 1 << 14;
 
 pub struct PieceInfo {
     length: u64,
@@ -13,24 +14,38 @@ pub struct PieceInfo {
     piece_index: usize,
 }
 
+impl PieceInfo {
+    pub fn new(piece_index: usize, length: u64, hash: ID) -> Self {
+        Self {
+            length,
+            hash,
+            piece_index,
+        }
+    }
+}
+
 /// A [Piece] should be able to :-
 ///
 /// 1. Verify it's integrity
-/// 2. Rject a [Block] which does not match it's current byte offset
+/// 2. Assemble [Block]s arriving in any order, rejecting the ones which do not fit
 ///
 /// It does not currently however check if it is completed, that is the job of the caller
 pub struct Piece {
     piece_info: PieceInfo,
     data: Vec<u8>,
-    byte_index: u64,
+    /// Which blocks of the piece were written.
+    blocks: Vec<bool>,
+    written: u64,
 }
 
 impl Piece {
     pub fn new(piece_info: PieceInfo) -> Self {
+        let num_blocks = piece_info.length.div_ceil(BLOCK_SIZE) as usize;
         Self {
+            data: vec![0; piece_info.length as usize],
+            blocks: vec![false; num_blocks],
+            written: 0,
             piece_info,
-            data: Vec::new(),
-            byte_index: 0,
         }
     }
 
@@ -38,43 +53,94 @@ impl Piece {
         self.size() == self.piece_info.length
     }
 
+    /// Number of bytes written so far.
     pub fn size(&self) -> u64 {
-        self.byte_index
+        self.written
     }
 
-    /// For simplicity [write] only accepts data whose offset is exactly the current index.
+    /// Writes a block, which must start at a multiple of [BLOCK_SIZE] and cover the whole block.
+    ///
+    /// Blocks of other pieces, misaligned or partial blocks and blocks which were already written
+    /// are rejected.
     pub fn write(&mut self, block: Block) -> Result<()> {
-        assert!(self.byte_index + block.block_info.length <= self.piece_info.length);
-        assert_eq!(block.block_info.offset, self.byte_index);
-        let mut cursor = Cursor::new(&mut self.data);
-        cursor.seek(SeekFrom::Start(self.byte_index))?;
-        let _written = cursor.write(&block)?;
-        self.byte_index += block.len() as u64;
+        let block_info = block.block_info;
+        if block_info.index != self.piece_info.piece_index {
+            anyhow::bail!(
+                "Block of piece {} written to piece {}",
+                block_info.index,
+                self.piece_info.piece_index
+            );
+        }
+        if !block_info.offset.is_multiple_of(BLOCK_SIZE)
+            || block_info.offset >= self.piece_info.length
+        {
+            anyhow::bail!("Invalid block offset {}", block_info.offset);
+        }
+
+        let expected = self.block_length(block_info.offset);
+        if block.len() as u64 != expected || block_info.length != expected {
+            anyhow::bail!(
+                "Block at offset {} has {} bytes, expected {expected}",
+                block_info.offset,
+                block.len()
+            );
+        }
+
+        let number = (block_info.offset / BLOCK_SIZE) as usize;
+        if self.blocks[number] {
+            anyhow::bail!("Block at offset {} was already written", block_info.offset);
+        }
+
+        let start = block_info.offset as usize;
+        self.data[start..start + block.len()].copy_from_slice(&block);
+        self.blocks[number] = true;
+        self.written += expected;
         Ok(())
     }
 
+    pub fn has_block(&self, offset: u64) -> bool {
+        offset.is_multiple_of(BLOCK_SIZE)
+            && self
+                .blocks
+                .get((offset / BLOCK_SIZE) as usize)
+                .copied()
+                .unwrap_or(false)
+    }
+
     pub fn get_raw_data(self) -> Vec<u8> {
         self.data
     }
 
-    fn check_integrity(&self) -> bool {
-        todo!()
+    /// Compares the SHA-1 hash of the data against the expected hash of the piece.
+    ///
+    /// Hashing a large piece takes a while, async callers should run it on a blocking pool.
+    pub fn check_integrity(&self) -> bool {
+        self.is_full() && verify(&self.data, &self.piece_info.hash)
     }
 
-    pub fn next_block(&self) -> Blockinfo {
-        let required_length = self.piece_info.length - self.byte_index;
-
-        let mut length = BLOCK_SIZE;
+    /// The first block which was not written yet.
+    pub fn next_block(&self) -> Option<Blockinfo> {
+        self.missing_blocks().next()
+    }
 
-        if required_length < BLOCK_SIZE {
-            length = required_length;
-        }
+    /// Every block which was not written yet, in order.
+    pub fn missing_blocks(&self) -> impl Iterator<Item = Blockinfo> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, written)| !**written)
+            .map(|(number, _)| {
+                let offset = number as u64 * BLOCK_SIZE;
+                Blockinfo {
+                    offset,
+                    index: self.piece_info.piece_index,
+                    length: self.block_length(offset),
+                }
+            })
+    }
 
-        Blockinfo {
-            offset: self.byte_index,
-            index: self.piece_info.piece_index,
-            length,
-        }
+    fn block_length(&self, offset: u64) -> u64 {
+        BLOCK_SIZE.min(self.piece_info.length - offset)
     }
 
     pub fn can_be_flushed(&self) -> bool {
@@ -90,6 +156,19 @@ impl Piece {
     }
 }
 
+/// Checks `data` against the SHA-1 hash of a piece.
+pub fn verify(data: &[u8], hash: &ID) -> bool {
+    Sha1::digest(data)[..] == hash[..]
+}
+
+/// SHA-1 digest of every block of a piece's `data`, to tell which blocks differ between two
+/// downloads of the piece.
+pub fn block_digests(data: &[u8]) -> Vec<ID> {
+    data.chunks(BLOCK_SIZE as usize)
+        .map(|block| ID::from(Sha1::digest(block).to_vec()))
+        .collect()
+}
+
 impl Sha1Hash for Piece {
     fn as_sha1(&self) -> ID {
         self.piece_info.hash
@@ -114,55 +193,123 @@ mod tests {
         let piece_info = PieceInfo {
             piece_index: 0,
             hash: ID::default(),
-            length: 20,
+            length: BLOCK_SIZE * 2 + 10,
         };
-        let block = get_test_block(0, 10, 0);
-
         let mut piece = Piece::new(piece_info);
-        piece.write(block)?;
 
-        let block = get_test_block(0, 10, 10);
+        // Blocks can arrive in any order.
+        piece.write(get_test_block(0, 10, BLOCK_SIZE * 2))?;
+        piece.write(get_test_block(0, BLOCK_SIZE, 0))?;
+        assert!(!piece.is_full());
+        assert!(piece.has_block(0));
+        assert!(!piece.has_block(BLOCK_SIZE));
 
-        piece.write(block)?;
+        piece.write(get_test_block(0, BLOCK_SIZE, BLOCK_SIZE))?;
+        assert!(piece.is_full());
 
-        assert_eq!(vec![10; 20], piece.get_raw_data());
+        assert_eq!(vec![10; BLOCK_SIZE as usize * 2 + 10], piece.get_raw_data());
         Ok(())
     }
 
     #[test]
-    #[should_panic]
     fn test_wrong_offset_piece_write() {
         let piece_info = PieceInfo {
             piece_index: 0,
             hash: ID::default(),
-            length: 10,
+            length: BLOCK_SIZE * 2,
         };
-
-        let block = get_test_block(0, 10, 3);
-
         let mut piece = Piece::new(piece_info);
-        piece.write(block).unwrap();
+
+        assert!(piece.write(get_test_block(0, BLOCK_SIZE, 3)).is_err());
+        assert!(piece
+            .write(get_test_block(0, BLOCK_SIZE, BLOCK_SIZE * 2))
+            .is_err());
+        assert_eq!(piece.size(), 0);
     }
 
     #[test]
-    #[should_panic]
     fn test_piece_overwrite() {
         let piece_info = PieceInfo {
             piece_index: 0,
             hash: ID::default(),
             length: 10,
         };
+        let mut piece = Piece::new(piece_info);
+
+        piece.write(get_test_block(0, 10, 0)).unwrap();
+        assert!(piece.write(get_test_block(0, 10, 0)).is_err());
 
-        let block = get_test_block(0, 10, 0);
+        assert_eq!(piece.size(), 10);
+        assert_eq!(vec![10; 10], piece.get_raw_data());
+    }
 
+    #[test]
+    fn test_piece_rejects_invalid_blocks() {
+        let piece_info = PieceInfo {
+            piece_index: 1,
+            hash: ID::default(),
+            length: BLOCK_SIZE + 10,
+        };
         let mut piece = Piece::new(piece_info);
-        piece.write(block).unwrap();
 
-        let block = get_test_block(0, 10, 10);
+        // Another piece.
+        assert!(piece.write(get_test_block(0, BLOCK_SIZE, 0)).is_err());
+        // Partial and oversized blocks.
+        assert!(piece.write(get_test_block(1, BLOCK_SIZE - 1, 0)).is_err());
+        assert!(piece.write(get_test_block(1, 11, BLOCK_SIZE)).is_err());
+        // Length not matching the data.
+        let mut block = get_test_block(1, 10, BLOCK_SIZE);
+        block.block_info.length = 9;
+        assert!(piece.write(block).is_err());
+
+        assert_eq!(piece.size(), 0);
+        assert!(piece.write(get_test_block(1, 10, BLOCK_SIZE)).is_ok());
+    }
 
-        piece.write(block).unwrap();
+    #[test]
+    fn test_piece_check_integrity() -> Result<()> {
+        let length = BLOCK_SIZE + 20;
+        let hash = ID::from(Sha1::digest(vec![10; length as usize]).to_vec());
+        let piece_info = PieceInfo::new(0, length, hash);
+        let mut piece = Piece::new(piece_info);
+
+        piece.write(get_test_block(0, BLOCK_SIZE, 0))?;
+        assert!(!piece.check_integrity());
+        assert!(!piece.can_be_flushed());
+
+        piece.write(get_test_block(0, 20, BLOCK_SIZE))?;
+        assert!(piece.check_integrity());
+        assert!(piece.can_be_flushed());
 
-        assert_eq!(vec![10; 20], piece.get_raw_data());
+        let piece_info = PieceInfo::new(0, 20, ID::default());
+        let mut corrupted = Piece::new(piece_info);
+        corrupted.write(get_test_block(0, 20, 0))?;
+        assert!(!corrupted.check_integrity());
+        assert!(!corrupted.can_be_flushed());
+        Ok(())
+    }
+
+    #[test]
+    fn test_piece_block_digests() -> Result<()> {
+        let mut piece = Piece::new(PieceInfo::new(0, BLOCK_SIZE + 10, ID::default()));
+        piece.write(get_test_block(0, BLOCK_SIZE, 0))?;
+        piece.write(get_test_block(0, 10, BLOCK_SIZE))?;
+
+        let mut other = Piece::new(PieceInfo::new(0, BLOCK_SIZE + 10, ID::default()));
+        other.write(get_test_block(0, BLOCK_SIZE, 0))?;
+        let last = Blockinfo {
+            offset: BLOCK_SIZE,
+            length: 10,
+            index: 0,
+        };
+        other.write(Block::new(&[11; 10], last))?;
+
+        let digests = block_digests(&piece.get_raw_data());
+        let other_digests = block_digests(&other.get_raw_data());
+        assert_eq!(digests.len(), 2);
+        assert_eq!(digests[0], other_digests[0]);
+        assert_ne!(digests[1], other_digests[1]);
+        Ok(())
     }
 
     #[test]
@@ -173,15 +320,45 @@ mod tests {
             length: 10,
         };
         let mut piece = Piece::new(piece_info);
-        for index in 0..=10 {
-            let block = get_test_block(0, 1, index);
 
-            piece.write(block)?;
+        let next_block = piece.next_block().unwrap();
+        assert_eq!(next_block.offset, 0);
+        assert_eq!(next_block.length, 10);
+
+        piece.write(get_test_block(0, 10, 0))?;
+        assert_eq!(piece.next_block(), None);
+        Ok(())
+    }
 
-            let next_block = piece.next_block();
+    #[test]
+    fn test_next_block_is_first_missing() -> Result<()> {
+        let piece_info = PieceInfo {
+            piece_index: 2,
+            hash: ID::default(),
+            length: BLOCK_SIZE * 3 + 5,
+        };
+        let mut piece = Piece::new(piece_info);
 
-            assert_eq!(next_block.offset, index + 1);
-        }
+        piece.write(get_test_block(2, BLOCK_SIZE, 0))?;
+        piece.write(get_test_block(2, BLOCK_SIZE, BLOCK_SIZE * 2))?;
+
+        let missing: Vec<_> = piece.missing_blocks().collect();
+        assert_eq!(
+            missing,
+            vec![
+                Blockinfo {
+                    offset: BLOCK_SIZE,
+                    length: BLOCK_SIZE,
+                    index: 2
+                },
+                Blockinfo {
+                    offset: BLOCK_SIZE * 3,
+                    length: 5,
+                    index: 2
+                },
+            ]
+        );
+        assert_eq!(piece.next_block(), Some(missing[0]));
         Ok(())
     }
 }
@@ -0,0 +1,226 @@
+use crate::{Event, TrackerList, TrackerRequest, TrackerResponse};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    sync::{mpsc, watch},
+    time::{sleep_until, Instant},
+};
+
+/// First delay after a failed announce, doubled on every consecutive failure.
+const DEFAULT_RETRY: Duration = Duration::from_secs(15);
+const MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+
+/// Transfer statistics of a torrent reported to trackers on every announce.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AnnounceStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+/// Handle used to tell a running [Announcer] about lifecycle changes of its torrent.
+///
+/// Dropping every handle has the same effect as [AnnouncerHandle::stop].
+#[derive(Clone)]
+pub struct AnnouncerHandle {
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl AnnouncerHandle {
+    /// Sends `completed`, only the first call has an effect.
+    pub fn completed(&self) {
+        let _ = self.events.send(Event::Completed);
+    }
+
+    /// Sends `stopped` and ends the announce loop.
+    pub fn stop(&self) {
+        let _ = self.events.send(Event::Stopped);
+    }
+
+    /// Announces as soon as the tracker's min interval allows, e.g. when running low on peers.
+    pub fn reannounce(&self) {
+        let _ = self.events.send(Event::None);
+    }
+}
+
+/// Result of an announce that at least one tracker answered.
+struct Outcome {
+    interval: Duration,
+    min_interval: Duration,
+    peers: Vec<SocketAddr>,
+}
+
+/// Announce loop of a single torrent.
+///
+/// Sends `started` first, then announces at the interval requested by the tracker, sends
+/// `completed` once and `stopped` when told to stop. Failed announces are retried with
+/// exponential backoff. Peers from every successful announce are forwarded to `peers`.
+pub struct Announcer {
+    trackers: TrackerList,
+    request: TrackerRequest,
+    stats: watch::Receiver<AnnounceStats>,
+    events: mpsc::UnboundedReceiver<Event>,
+    peers: mpsc::Sender<Vec<SocketAddr>>,
+    all_tiers: bool,
+    retry: Duration,
+}
+
+impl Announcer {
+    /// `request` is the template for every announce, its transfer statistics and event are
+    /// overwritten from `stats` and the lifecycle of the torrent.
+    pub fn new(
+        trackers: TrackerList,
+        request: TrackerRequest,
+        stats: watch::Receiver<AnnounceStats>,
+        peers: mpsc::Sender<Vec<SocketAddr>>,
+    ) -> (Self, AnnouncerHandle) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let announcer = Self {
+            trackers,
+            request,
+            stats,
+            events: rx,
+            peers,
+            all_tiers: false,
+            retry: DEFAULT_RETRY,
+        };
+
+        (announcer, AnnouncerHandle { events: tx })
+    }
+
+    /// Announce to every tier concurrently instead of only the first tier that responds.
+    pub fn announce_to_all_tiers(mut self, all_tiers: bool) -> Self {
+        self.all_tiers = all_tiers;
+        self
+    }
+
+    /// Overrides the delay after the first failed announce.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub async fn run(mut self) {
+        let mut event = Event::Started;
+        // A torrent which is complete when added never sends `completed`.
+        let mut completed = self.stats.borrow().left == 0;
+        // `completed` arriving before `started` went through waits for it.
+        let mut completed_pending = false;
+        let mut min_interval = Duration::ZERO;
+        let mut failures = 0;
+
+        loop {
+            let announced_at = Instant::now();
+            let outcome = self.announce(event).await;
+
+            if event == Event::Stopped {
+                return;
+            }
+
+            let mut wake = match outcome {
+                Some(outcome) => {
+                    failures = 0;
+                    min_interval = outcome.min_interval;
+                    if !outcome.peers.is_empty() {
+                        let _ = self.peers.send(outcome.peers).await;
+                    }
+                    if completed_pending {
+                        completed_pending = false;
+                        event = Event::Completed;
+                        announced_at + min_interval
+                    } else {
+                        event = Event::None;
+                        announced_at + outcome.interval
+                    }
+                }
+                // Keep the event so `started` and `completed` are retried.
+                None => {
+                    failures += 1;
+                    announced_at + self.backoff(failures)
+                }
+            };
+            let earliest = announced_at + min_interval;
+
+            loop {
+                tokio::select! {
+                    _ = sleep_until(wake) => break,
+                    received = self.events.recv() => match received {
+                        Some(Event::Stopped) | None => {
+                            event = Event::Stopped;
+                            break;
+                        }
+                        Some(Event::Completed) if !completed => {
+                            completed = true;
+                            if event == Event::Started {
+                                completed_pending = true;
+                            } else {
+                                event = Event::Completed;
+                                wake = earliest;
+                            }
+                        }
+                        Some(Event::None) => wake = wake.min(earliest),
+                        Some(_) => {}
+                    },
+                }
+            }
+        }
+    }
+
+    async fn announce(&mut self, event: Event) -> Option<Outcome> {
+        let stats = *self.stats.borrow();
+        let request = self
+            .request
+            .clone()
+            .set_event(event)
+            .set_uploaded(stats.uploaded)
+            .set_downloaded(stats.downloaded)
+            .set_left(stats.left);
+
+        let responses = if self.all_tiers {
+            self.trackers.send_request_all(request).await
+        } else {
+            vec![self.trackers.send_request(request).await]
+        };
+
+        let mut outcome: Option<Outcome> = None;
+
+        for response in responses {
+            let Ok(TrackerResponse::Response {
+                interval,
+                min_interval,
+                peers,
+                ..
+            }) = response
+            else {
+                continue;
+            };
+
+            let interval = Duration::from_secs(interval.max(1) as u64);
+            let min_interval = Duration::from_secs(min_interval.unwrap_or(0));
+            let peers = peers.addrs.into_iter();
+
+            match &mut outcome {
+                Some(outcome) => {
+                    outcome.interval = outcome.interval.min(interval);
+                    outcome.min_interval = outcome.min_interval.max(min_interval);
+                    outcome.peers.extend(peers);
+                }
+                None => {
+                    outcome = Some(Outcome {
+                        interval,
+                        min_interval,
+                        peers: peers.collect(),
+                    })
+                }
+            }
+        }
+
+        outcome
+    }
+
+    fn backoff(&self, failures: u32) -> Duration {
+        self.retry
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(MAX_RETRY.max(self.retry))
+    }
+}
@@ -1,7 +1,9 @@
+mod announcer;
 mod request;
 mod response;
 mod tracker;
 
+pub use crate::announcer::*;
 pub use crate::request::*;
 pub use crate::response::*;
-pub use tracker::Tracker;
+pub use tracker::{HttpTracker, Tracker, TrackerList, UdpTracker};
@@ -1,6 +1,30 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
 use torrus_core::id::ID;
 
-#[derive(Default)]
+/// Announce event, the discriminants are the values used by the UDP tracker protocol.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A regular announce performed at the interval requested by the tracker.
+    #[default]
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl Event {
+    /// Value of the `event` query parameter, regular announces leave it out.
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+}
+
+#[derive(Default, Clone)]
 pub struct TrackerRequest {
     pub(crate) info_hash: ID,
     pub(crate) peer_id: ID,
@@ -8,10 +32,13 @@ pub struct TrackerRequest {
     pub(crate) uploaded: u64,
     pub(crate) downloaded: u64,
     pub(crate) left: u64,
-    pub(crate) event: String,
+    pub(crate) event: Event,
     pub(crate) ip_address: u32,
     pub(crate) key: u32,
     pub(crate) num_want: i32,
+    pub(crate) tracker_id: Option<String>,
+    pub(crate) ipv4: Option<Ipv4Addr>,
+    pub(crate) ipv6: Option<Ipv6Addr>,
 }
 
 impl TrackerRequest {
@@ -39,11 +66,21 @@ impl TrackerRequest {
         self
     }
 
-    pub fn set_event(mut self, left: u64) -> Self {
+    pub fn set_uploaded(mut self, uploaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self
+    }
+
+    pub fn set_left(mut self, left: u64) -> Self {
         self.left = left;
         self
     }
 
+    pub fn set_event(mut self, event: Event) -> Self {
+        self.event = event;
+        self
+    }
+
     pub fn set_ip(mut self, ip: u32) -> Self {
         self.ip_address = ip;
         self
@@ -58,4 +95,22 @@ impl TrackerRequest {
         self.num_want = num_want;
         self
     }
+
+    /// Our IPv4 address, lets a tracker reached over IPv6 hand it out to IPv4 peers (BEP 7).
+    pub fn set_ipv4(mut self, ip: Ipv4Addr) -> Self {
+        self.ipv4 = Some(ip);
+        self
+    }
+
+    /// Our IPv6 address, lets a tracker reached over IPv4 hand it out to IPv6 peers (BEP 7).
+    pub fn set_ipv6(mut self, ip: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ip);
+        self
+    }
+
+    /// Tracker id received in an earlier response, trackers expect it echoed back.
+    pub fn set_tracker_id(mut self, tracker_id: String) -> Self {
+        self.tracker_id = Some(tracker_id);
+        self
+    }
 }
@@ -3,14 +3,35 @@ use serde::Deserializer;
 use serde_bytes::ByteBuf;
 use serde_derive::Deserialize;
 use std::{
+    collections::HashMap,
     marker::PhantomData,
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
 };
+use torrus_core::id::ID;
 
+/// Response to an announce. IPv6 peers from `peers6` are merged into `peers`.
 #[derive(Debug, Deserialize)]
-#[serde(untagged)]
+#[serde(from = "RawTrackerResponse")]
 pub enum TrackerResponse {
+    Error {
+        failure_reason: String,
+    },
+    Response {
+        warning_message: Option<String>,
+        complete: u32,
+        interval: u32,
+        min_interval: Option<u64>,
+        tracker_id: Option<String>,
+        incomplete: u32,
+        peers: Peers,
+    },
+}
+
+/// Announce response as it is encoded by HTTP trackers.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTrackerResponse {
     Error {
         #[serde(rename = "failure reason")]
         failure_reason: String,
@@ -22,15 +43,77 @@ pub enum TrackerResponse {
         interval: u32,
         #[serde(rename = "min interval")]
         min_interval: Option<u64>,
+        #[serde(rename = "tracker id")]
         tracker_id: Option<String>,
         incomplete: u32,
+        #[serde(default)]
         peers: Peers,
+        #[serde(default, deserialize_with = "deserialize_compact_peers6")]
+        peers6: Vec<SocketAddr>,
     },
 }
 
-#[derive(Debug)]
+impl From<RawTrackerResponse> for TrackerResponse {
+    fn from(value: RawTrackerResponse) -> Self {
+        match value {
+            RawTrackerResponse::Error { failure_reason } => {
+                TrackerResponse::Error { failure_reason }
+            }
+            RawTrackerResponse::Response {
+                warning_message,
+                complete,
+                interval,
+                min_interval,
+                tracker_id,
+                incomplete,
+                mut peers,
+                peers6,
+            } => {
+                peers.addrs.extend(peers6);
+                TrackerResponse::Response {
+                    warning_message,
+                    complete,
+                    interval,
+                    min_interval,
+                    tracker_id,
+                    incomplete,
+                    peers,
+                }
+            }
+        }
+    }
+}
+
+/// Swarm statistics for a single torrent as reported by a scrape request.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScrapeStats {
+    /// Number of peers with the entire file, i.e. seeders.
+    #[serde(default)]
+    pub complete: u32,
+    /// Number of times the tracker registered a completion, some trackers leave it out.
+    #[serde(default)]
+    pub downloaded: u32,
+    /// Number of non-seeder peers, i.e. leechers.
+    #[serde(default)]
+    pub incomplete: u32,
+}
+
+/// Body of a HTTP scrape response.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ScrapeResponse {
+    Error {
+        #[serde(rename = "failure reason")]
+        failure_reason: String,
+    },
+    Response {
+        files: HashMap<ID, ScrapeStats>,
+    },
+}
+
+#[derive(Debug, Default)]
 pub struct Peers {
-    pub addrs: Vec<DictPeer>,
+    pub addrs: Vec<SocketAddr>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -42,20 +125,24 @@ pub struct DictPeer {
     pub port: u16,
 }
 
-impl From<SocketAddrV4> for DictPeer {
-    fn from(value: SocketAddrV4) -> Self {
-        let port = value.port();
-        let ip = value.ip();
-
+impl From<SocketAddr> for DictPeer {
+    fn from(value: SocketAddr) -> Self {
         DictPeer {
-            ip: IpAddr::V4(*ip),
+            ip: value.ip(),
             peer_id: None,
-            port,
+            port: value.port(),
         }
     }
 }
 
-fn parse_compact_peers(b: &[u8]) -> Vec<SocketAddrV4> {
+impl From<DictPeer> for SocketAddr {
+    fn from(value: DictPeer) -> Self {
+        SocketAddr::new(value.ip, value.port)
+    }
+}
+
+/// Parses IPv4 peers in compact form, 4 bytes of address followed by 2 bytes of port.
+pub(crate) fn parse_compact_peers(b: &[u8]) -> Vec<SocketAddr> {
     let mut ips = Vec::new();
 
     for chunk in b.chunks_exact(6) {
@@ -63,12 +150,34 @@ fn parse_compact_peers(b: &[u8]) -> Vec<SocketAddrV4> {
         let port_chunk = &chunk[4..6];
         let ipaddr = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
         let port = byteorder::BigEndian::read_u16(port_chunk);
-        ips.push(SocketAddrV4::new(ipaddr, port))
+        ips.push(SocketAddr::new(IpAddr::V4(ipaddr), port))
     }
 
     ips
 }
 
+/// Parses IPv6 peers in compact form, 16 bytes of address followed by 2 bytes of port.
+pub(crate) fn parse_compact_peers6(b: &[u8]) -> Vec<SocketAddr> {
+    let mut ips = Vec::new();
+
+    for chunk in b.chunks_exact(18) {
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&chunk[..16]);
+        let port = byteorder::BigEndian::read_u16(&chunk[16..18]);
+        ips.push(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+    }
+
+    ips
+}
+
+fn deserialize_compact_peers6<'de, D>(de: D) -> std::result::Result<Vec<SocketAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let bytes: ByteBuf = serde::Deserialize::deserialize(de)?;
+    Ok(parse_compact_peers6(&bytes))
+}
+
 fn deserialize_ip_string<'de, D>(de: D) -> std::result::Result<IpAddr, D::Error>
 where
     D: Deserializer<'de>,
@@ -121,7 +230,7 @@ impl<'de> serde::Deserialize<'de> for Peers {
             {
                 let mut peers = Vec::new();
                 while let Some(peer) = seq.next_element::<DictPeer>()? {
-                    peers.push(peer)
+                    peers.push(peer.into())
                 }
                 Ok(Peers { addrs: peers })
             }
@@ -131,10 +240,7 @@ impl<'de> serde::Deserialize<'de> for Peers {
                 E: serde::de::Error,
             {
                 Ok(Peers {
-                    addrs: parse_compact_peers(v)
-                        .into_iter()
-                        .map(|v| v.into())
-                        .collect(),
+                    addrs: parse_compact_peers(v),
                 })
             }
         }
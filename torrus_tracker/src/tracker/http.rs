@@ -1,9 +1,14 @@
 use anyhow::{Ok, Result};
 use reqwest::{Client, StatusCode};
 use std::{collections::HashMap, ops::Deref};
-use url::Url;
+use torrus_core::id::ID;
+use url::{form_urlencoded::byte_serialize, Url};
 
-use crate::{request::TrackerRequest, TrackerResponse};
+use crate::{request::TrackerRequest, ScrapeResponse, ScrapeStats, TrackerResponse};
+
+/// Number of info hashes sent in a single scrape request, keeps the url at a size trackers
+/// accept.
+const SCRAPE_BATCH_SIZE: usize = 64;
 
 pub struct HttpTracker {
     url: Url,
@@ -18,49 +23,108 @@ impl HttpTracker {
         }
     }
 
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
     pub async fn send_request(&mut self, request: TrackerRequest) -> Result<TrackerResponse> {
-        let query = Self::request_to_hash_map(request);
+        let query = Self::request_to_query(request);
+        let bytes = self.get(&self.url, &query).await?;
+        let tracker_response = serde_bencode::de::from_bytes(&bytes)?;
+        Ok(tracker_response)
+    }
+
+    pub async fn scrape(&mut self, info_hashes: &[ID]) -> Result<HashMap<ID, ScrapeStats>> {
+        let url = self.scrape_url()?;
+        let mut stats = HashMap::with_capacity(info_hashes.len());
+
+        for batch in info_hashes.chunks(SCRAPE_BATCH_SIZE) {
+            let query: Vec<_> = batch
+                .iter()
+                .map(|info_hash| ("info_hash", byte_serialize(info_hash.deref()).collect()))
+                .collect();
+
+            let bytes = self.get(&url, &query).await?;
+            match serde_bencode::de::from_bytes(&bytes)? {
+                ScrapeResponse::Response { files } => stats.extend(files),
+                ScrapeResponse::Error { failure_reason } => anyhow::bail!(failure_reason),
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Derives the scrape url following the convention that the last path segment of the
+    /// announce url starts with `announce`, which is replaced by `scrape`.
+    fn scrape_url(&self) -> Result<Url> {
+        let path = self.url.path();
+        let (base, last) = path.rsplit_once('/').unwrap_or(("", path));
 
-        let resp = self
-            .client
-            .get(self.url.to_string())
-            .query(&query)
-            .send()
-            .await?;
+        let Some(rest) = last.strip_prefix("announce") else {
+            anyhow::bail!("Tracker {} does not support scrape", self.url);
+        };
+
+        let mut url = self.url.clone();
+        url.set_path(&format!("{base}/scrape{rest}"));
+        Ok(url)
+    }
+
+    /// Issues a GET request with the already percent encoded `query` appended to `url`.
+    async fn get(&self, url: &Url, query: &[(&str, String)]) -> Result<Vec<u8>> {
+        let mut url = url.clone();
+        let pairs = query
+            .iter()
+            .map(|(key, val)| format!("{key}={val}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{existing}&{pairs}"),
+            _ => pairs,
+        };
+        url.set_query(Some(&query));
+
+        let resp = self.client.get(url).send().await?;
 
         if StatusCode::OK != resp.status() {
             anyhow::bail!("Error HTTP");
         }
 
-        let bytes = resp.bytes().await?;
-        let tracker_response = serde_bencode::de::from_bytes(&bytes)?;
-        Ok(tracker_response)
+        Ok(resp.bytes().await?.to_vec())
     }
 
-    fn request_to_hash_map(request: TrackerRequest) -> HashMap<String, String> {
-        use url::form_urlencoded::byte_serialize;
-
-        let mut query_map = HashMap::new();
+    fn request_to_query(request: TrackerRequest) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
 
-        let mut append_pair = |key: String, val: String| {
-            query_map.insert(key, val);
+        let mut append_pair = |key: &'static str, val: String| {
+            query.push((key, val));
         };
 
         let info_hash_str: String = byte_serialize(request.info_hash.deref()).collect();
         let peer_id_str: String = byte_serialize(request.peer_id.deref()).collect();
 
-        append_pair("info_hash".to_string(), info_hash_str);
-        append_pair("peer_id".to_string(), peer_id_str);
-        append_pair("downloaded".to_string(), request.downloaded.to_string());
-        append_pair("left".to_string(), request.left.to_string());
-        append_pair("uploaded".to_string(), request.uploaded.to_string());
-        append_pair("event".to_string(), request.event);
-        append_pair("ip_address".to_string(), request.ip_address.to_string());
-        append_pair("key".to_string(), request.key.to_string());
-        append_pair("num_want".to_string(), request.num_want.to_string());
-        append_pair("port".to_string(), request.port.to_string());
-        append_pair("no_peer_id".to_string(), "0".to_string());
-        append_pair("compact".to_string(), "1".to_string());
-        query_map
+        append_pair("info_hash", info_hash_str);
+        append_pair("peer_id", peer_id_str);
+        append_pair("downloaded", request.downloaded.to_string());
+        append_pair("left", request.left.to_string());
+        append_pair("uploaded", request.uploaded.to_string());
+        if let Some(event) = request.event.as_str() {
+            append_pair("event", event.to_string());
+        }
+        if let Some(ip) = request.ipv4 {
+            append_pair("ipv4", ip.to_string());
+        }
+        if let Some(ip) = request.ipv6 {
+            append_pair("ipv6", byte_serialize(ip.to_string().as_bytes()).collect());
+        }
+        if let Some(tracker_id) = request.tracker_id {
+            append_pair("trackerid", byte_serialize(tracker_id.as_bytes()).collect());
+        }
+        append_pair("ip_address", request.ip_address.to_string());
+        append_pair("key", request.key.to_string());
+        append_pair("num_want", request.num_want.to_string());
+        append_pair("port", request.port.to_string());
+        append_pair("no_peer_id", "0".to_string());
+        append_pair("compact", "1".to_string());
+        query
     }
 }
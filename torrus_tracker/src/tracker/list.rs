@@ -0,0 +1,120 @@
+use super::tracker::Tracker;
+use crate::{TrackerRequest, TrackerResponse};
+use anyhow::Result;
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use torrus_core::{id::ID, metainfo::Metainfo};
+
+/// Tiers of trackers as described in BEP 12.
+///
+/// Trackers within a tier are shuffled once on creation. Announces go through the tiers in
+/// order and through the trackers of a tier in order, the first tracker to respond is moved to
+/// the front of its tier so it is tried first next time.
+pub struct TrackerList {
+    tiers: Vec<Vec<Tracker>>,
+}
+
+impl TrackerList {
+    /// Builds the tiers from tracker urls, urls which are malformed or use an unsupported
+    /// scheme are skipped.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<_> = tier
+                    .iter()
+                    .filter_map(|url| Tracker::try_new(url).ok())
+                    .collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        Self { tiers }
+    }
+
+    /// Uses `announce-list` when present and falls back to a single tier holding `announce`.
+    pub fn from_metainfo(metainfo: &Metainfo) -> Self {
+        match (&metainfo.announce_list, &metainfo.announce) {
+            (Some(announce_list), _) if !announce_list.is_empty() => {
+                Self::new(announce_list.clone())
+            }
+            (_, Some(announce)) => Self::new(vec![vec![announce.clone()]]),
+            _ => Self::new(Vec::new()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Tracker urls per tier, in the order they will be tried.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.tiers
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .map(|tracker| tracker.url().to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Announces to the first tracker that responds, falling through tiers on failure.
+    ///
+    /// A [TrackerResponse::Error] counts as a failure, when no tracker responds the outcome of
+    /// the last attempt is returned.
+    pub async fn send_request(&mut self, request: TrackerRequest) -> Result<TrackerResponse> {
+        let mut last = None;
+
+        for tier in &mut self.tiers {
+            match announce_tier(tier, &request).await {
+                Ok(response @ TrackerResponse::Response { .. }) => return Ok(response),
+                outcome => last = Some(outcome),
+            }
+        }
+
+        last.unwrap_or_else(|| Err(anyhow::anyhow!("No trackers to announce to")))
+    }
+
+    /// Announces to every tier concurrently, returning the outcome of each tier in order.
+    pub async fn send_request_all(
+        &mut self,
+        request: TrackerRequest,
+    ) -> Vec<Result<TrackerResponse>> {
+        join_all(
+            self.tiers
+                .iter_mut()
+                .map(|tier| announce_tier(tier, &request)),
+        )
+        .await
+    }
+
+    pub async fn announce(&mut self, id: ID) -> Result<TrackerResponse> {
+        let tracker_request = TrackerRequest::builder().info_hash(id).set_port(6881);
+        self.send_request(tracker_request).await
+    }
+}
+
+async fn announce_tier(
+    tier: &mut Vec<Tracker>,
+    request: &TrackerRequest,
+) -> Result<TrackerResponse> {
+    let mut last = None;
+
+    for index in 0..tier.len() {
+        match tier[index].send_request(request.clone()).await {
+            Ok(response @ TrackerResponse::Response { .. }) => {
+                let tracker = tier.remove(index);
+                tier.insert(0, tracker);
+                return Ok(response);
+            }
+            outcome => last = Some(outcome),
+        }
+    }
+
+    last.unwrap_or_else(|| Err(anyhow::anyhow!("Tier has no trackers")))
+}
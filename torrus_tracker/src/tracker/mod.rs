@@ -1,5 +1,10 @@
 mod http;
+mod list;
+#[allow(clippy::module_inception)]
 mod tracker;
 mod udp;
 
+pub use http::HttpTracker;
+pub use list::TrackerList;
 pub use tracker::Tracker;
+pub use udp::UdpTracker;
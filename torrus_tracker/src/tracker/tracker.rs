@@ -1,33 +1,81 @@
 use super::{http::HttpTracker, udp::UdpTracker};
-use crate::{TrackerRequest, TrackerResponse};
+use crate::{ScrapeStats, TrackerRequest, TrackerResponse};
 use anyhow::Result;
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr};
 use torrus_core::id::ID;
 use url::Url;
 
 pub struct Tracker {
     tracker_type: Type,
+    tracker_id: Option<String>,
 }
 
 impl Tracker {
+    /// Tracker for an http(s) or udp announce url, panics on anything else. See
+    /// [Tracker::try_new].
     pub fn new(url: &str) -> Self {
-        let url = Url::from_str(url).unwrap();
+        Self::try_new(url).expect("Invalid tracker url")
+    }
+
+    /// Like [Tracker::new] but fails instead of panicking on malformed urls and unsupported
+    /// schemes.
+    pub fn try_new(url: &str) -> Result<Self> {
+        let url = Url::from_str(url)?;
         use Type::*;
         let tracker_type = match url.scheme() {
             "https" | "http" => Http(HttpTracker::new(url)),
             "udp" => Udp(UdpTracker::new(url)),
-            _ => panic!("Magnet Links not supported yet!"),
+            scheme => anyhow::bail!("Unsupported tracker scheme {scheme}"),
         };
 
-        Self { tracker_type }
+        Ok(Self {
+            tracker_type,
+            tracker_id: None,
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        use Type::*;
+
+        match &self.tracker_type {
+            Http(tracker) => tracker.url(),
+            Udp(tracker) => tracker.url(),
+        }
     }
 
-    pub async fn send_request(&mut self, request: TrackerRequest) -> Result<TrackerResponse> {
+    /// Sends `request`, echoing back the tracker id the tracker handed out in an earlier
+    /// response.
+    pub async fn send_request(&mut self, mut request: TrackerRequest) -> Result<TrackerResponse> {
+        use Type::*;
+
+        if request.tracker_id.is_none() {
+            request.tracker_id = self.tracker_id.clone();
+        }
+
+        let response = match &mut self.tracker_type {
+            Http(ref mut tracker) => tracker.send_request(request).await?,
+            Udp(ref mut tracker) => tracker.send_request(request).await?,
+        };
+
+        if let TrackerResponse::Response {
+            tracker_id: Some(tracker_id),
+            ..
+        } = &response
+        {
+            self.tracker_id = Some(tracker_id.clone());
+        }
+
+        Ok(response)
+    }
+
+    /// Asks the tracker for swarm statistics of every torrent in `info_hashes` without
+    /// announcing ourselves.
+    pub async fn scrape(&mut self, info_hashes: &[ID]) -> Result<HashMap<ID, ScrapeStats>> {
         use Type::*;
 
         match &mut self.tracker_type {
-            Http(ref mut tracker) => tracker.send_request(request).await,
-            Udp(ref mut tracker) => tracker.send_request(request).await,
+            Http(ref mut tracker) => tracker.scrape(info_hashes).await,
+            Udp(ref mut tracker) => tracker.scrape(info_hashes).await,
         }
     }
 
@@ -37,6 +85,24 @@ impl Tracker {
     }
 }
 
+impl From<HttpTracker> for Tracker {
+    fn from(tracker: HttpTracker) -> Self {
+        Self {
+            tracker_type: Type::Http(tracker),
+            tracker_id: None,
+        }
+    }
+}
+
+impl From<UdpTracker> for Tracker {
+    fn from(tracker: UdpTracker) -> Self {
+        Self {
+            tracker_type: Type::Udp(tracker),
+            tracker_id: None,
+        }
+    }
+}
+
 enum Type {
     Http(HttpTracker),
     Udp(UdpTracker),
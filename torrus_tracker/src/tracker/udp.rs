@@ -1,17 +1,320 @@
-use crate::{TrackerRequest, TrackerResponse};
-use anyhow::Result;
-use url::Url;
+use crate::{
+    response::{parse_compact_peers, parse_compact_peers6},
+    Peers, ScrapeStats, TrackerRequest, TrackerResponse,
+};
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use tokio::{net::UdpSocket, time::Instant};
+use torrus_core::id::ID;
+use url::{Host, Url};
 
+/// Magic constant sent in every connect request, see BEP 15.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection id may be reused for one minute after the tracker handed it out.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// Retransmission follows `15 * 2^n` seconds for `n` in `0..=8`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_MAX_RETRIES: u32 = 8;
+
+const MAX_PACKET_SIZE: usize = 65_536;
+
+/// Most trackers cap a scrape at 74 info hashes so the request fits into a single packet.
+const SCRAPE_BATCH_SIZE: usize = 74;
+
+/// Reply to a request, with the action and transaction id already stripped.
+enum Reply {
+    Payload(Vec<u8>),
+    Error(String),
+}
+
+/// Tracker speaking the UDP tracker protocol described in BEP 15.
+///
+/// The connection id obtained from the connect handshake is cached and reused
+/// for subsequent requests until it expires.
 pub struct UdpTracker {
     url: Url,
+    socket: Option<UdpSocket>,
+    connection: Option<(u64, Instant)>,
+    timeout: Duration,
+    max_retries: u32,
 }
 
 impl UdpTracker {
     pub fn new(url: Url) -> Self {
-        Self { url }
+        Self {
+            url,
+            socket: None,
+            connection: None,
+            timeout: DEFAULT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Overrides the base timeout `t` and the highest `n` of the `t * 2^n` retransmission
+    /// schedule.
+    pub fn with_retransmission(mut self, timeout: Duration, max_retries: u32) -> Self {
+        self.timeout = timeout;
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub async fn send_request(&mut self, request: TrackerRequest) -> Result<TrackerResponse> {
+        if self.socket.is_none() {
+            self.socket = Some(self.bind().await?);
+        }
+
+        let body = Self::announce_body(&request, self.is_ipv6()?)?;
+
+        let payload = match self.transact(ACTION_ANNOUNCE, &body).await? {
+            Reply::Payload(payload) => payload,
+            Reply::Error(failure_reason) => return Ok(TrackerResponse::Error { failure_reason }),
+        };
+
+        if payload.len() < 12 {
+            anyhow::bail!("Announce response too short: {} bytes", payload.len());
+        }
+
+        let interval = BigEndian::read_u32(&payload[0..4]);
+        let incomplete = BigEndian::read_u32(&payload[4..8]);
+        let complete = BigEndian::read_u32(&payload[8..12]);
+        // Trackers reached over IPv6 answer with 18 byte IPv6 peer entries.
+        let addrs = if self.is_ipv6()? {
+            parse_compact_peers6(&payload[12..])
+        } else {
+            parse_compact_peers(&payload[12..])
+        };
+        let peers = Peers { addrs };
+
+        Ok(TrackerResponse::Response {
+            warning_message: None,
+            complete,
+            interval,
+            min_interval: None,
+            tracker_id: None,
+            incomplete,
+            peers,
+        })
+    }
+
+    pub async fn scrape(&mut self, info_hashes: &[ID]) -> Result<HashMap<ID, ScrapeStats>> {
+        let mut stats = HashMap::with_capacity(info_hashes.len());
+
+        for batch in info_hashes.chunks(SCRAPE_BATCH_SIZE) {
+            let body: Vec<u8> = batch.iter().flat_map(|id| id.iter().copied()).collect();
+
+            let payload = match self.transact(ACTION_SCRAPE, &body).await? {
+                Reply::Payload(payload) => payload,
+                Reply::Error(failure_reason) => anyhow::bail!(failure_reason),
+            };
+
+            if payload.len() < batch.len() * 12 {
+                anyhow::bail!(
+                    "Scrape response too short: {} bytes for {} torrents",
+                    payload.len(),
+                    batch.len()
+                );
+            }
+
+            // Entries come back in the order the info hashes were sent.
+            for (info_hash, entry) in batch.iter().zip(payload.chunks_exact(12)) {
+                let scrape = ScrapeStats {
+                    complete: BigEndian::read_u32(&entry[0..4]),
+                    downloaded: BigEndian::read_u32(&entry[4..8]),
+                    incomplete: BigEndian::read_u32(&entry[8..12]),
+                };
+                stats.insert(*info_hash, scrape);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn is_ipv6(&self) -> Result<bool> {
+        let socket = self
+            .socket
+            .as_ref()
+            .context("UDP tracker socket is not bound")?;
+        Ok(socket.peer_addr()?.is_ipv6())
     }
 
-    pub async fn send_request(&mut self, _udp_request: TrackerRequest) -> Result<TrackerResponse> {
-        todo!("Implement UDP tracker")
+    /// The IP address field is 32 bits wide and is therefore zeroed in IPv6 announces.
+    fn announce_body(request: &TrackerRequest, ipv6: bool) -> Result<Vec<u8>> {
+        let ip_address = if ipv6 { 0 } else { request.ip_address };
+
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&*request.info_hash);
+        body.extend_from_slice(&*request.peer_id);
+        body.write_u64::<BigEndian>(request.downloaded)?;
+        body.write_u64::<BigEndian>(request.left)?;
+        body.write_u64::<BigEndian>(request.uploaded)?;
+        body.write_u32::<BigEndian>(request.event as u32)?;
+        body.write_u32::<BigEndian>(ip_address)?;
+        body.write_u32::<BigEndian>(request.key)?;
+        body.write_i32::<BigEndian>(request.num_want)?;
+        body.write_u16::<BigEndian>(request.port)?;
+        Ok(body)
+    }
+
+    /// Sends `action` with `body` to the tracker, connecting first when there is no valid
+    /// connection id, and retransmits until the schedule is exhausted.
+    async fn transact(&mut self, action: u32, body: &[u8]) -> Result<Reply> {
+        if self.socket.is_none() {
+            self.socket = Some(self.bind().await?);
+        }
+
+        for attempt in 0..=self.max_retries {
+            let timeout = self.timeout * 2u32.pow(attempt);
+
+            let connection_id = match self.connection_id() {
+                Some(connection_id) => connection_id,
+                None => match self.connect(timeout).await? {
+                    Some(Reply::Payload(payload)) => {
+                        if payload.len() < 8 {
+                            anyhow::bail!("Connect response too short: {} bytes", payload.len());
+                        }
+                        let connection_id = BigEndian::read_u64(&payload[0..8]);
+                        self.connection = Some((connection_id, Instant::now()));
+                        connection_id
+                    }
+                    Some(error) => return Ok(error),
+                    None => continue,
+                },
+            };
+
+            let transaction_id = rand::random();
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.write_u64::<BigEndian>(connection_id)?;
+            packet.write_u32::<BigEndian>(action)?;
+            packet.write_u32::<BigEndian>(transaction_id)?;
+            packet.extend_from_slice(body);
+
+            match self
+                .exchange(&packet, action, transaction_id, timeout)
+                .await?
+            {
+                Some(reply) => return Ok(reply),
+                None => continue,
+            }
+        }
+
+        anyhow::bail!(
+            "UDP tracker {} did not respond after {} attempts",
+            self.url,
+            self.max_retries + 1
+        )
+    }
+
+    fn connection_id(&self) -> Option<u64> {
+        match self.connection {
+            Some((connection_id, obtained)) if obtained.elapsed() < CONNECTION_ID_TTL => {
+                Some(connection_id)
+            }
+            _ => None,
+        }
+    }
+
+    async fn connect(&mut self, timeout: Duration) -> Result<Option<Reply>> {
+        self.connection = None;
+
+        let transaction_id = rand::random();
+        let mut packet = Vec::with_capacity(16);
+        packet.write_u64::<BigEndian>(PROTOCOL_ID)?;
+        packet.write_u32::<BigEndian>(ACTION_CONNECT)?;
+        packet.write_u32::<BigEndian>(transaction_id)?;
+
+        self.exchange(&packet, ACTION_CONNECT, transaction_id, timeout)
+            .await
+    }
+
+    /// Sends a single packet and waits up to `timeout` for the reply carrying
+    /// `transaction_id`, returning `None` on timeout. Packets with any other transaction id are
+    /// discarded.
+    async fn exchange(
+        &self,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+        timeout: Duration,
+    ) -> Result<Option<Reply>> {
+        let socket = self
+            .socket
+            .as_ref()
+            .expect("socket is bound before exchange");
+        socket.send(packet).await?;
+
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+        loop {
+            let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(len) => len?,
+                Err(_) => return Ok(None),
+            };
+
+            if len < 8 || BigEndian::read_u32(&buf[4..8]) != transaction_id {
+                continue;
+            }
+
+            let payload = buf[8..len].to_vec();
+            return match BigEndian::read_u32(&buf[0..4]) {
+                ACTION_ERROR => Ok(Some(Reply::Error(
+                    String::from_utf8_lossy(&payload).into_owned(),
+                ))),
+                received if received == action => Ok(Some(Reply::Payload(payload))),
+                received => anyhow::bail!("Expected action {action} but received {received}"),
+            };
+        }
+    }
+
+    /// Binds a socket connected to the first resolved tracker address which is reachable, so
+    /// hosts with only one of IPv4 or IPv6 connectivity use the family they have.
+    async fn bind(&self) -> Result<UdpSocket> {
+        let port = self.url.port().context("UDP tracker url has no port")?;
+
+        let addrs: Vec<SocketAddr> = match self.url.host() {
+            Some(Host::Ipv4(ip)) => vec![(ip, port).into()],
+            Some(Host::Ipv6(ip)) => vec![(ip, port).into()],
+            Some(Host::Domain(host)) => tokio::net::lookup_host((host, port)).await?.collect(),
+            None => anyhow::bail!("UDP tracker url has no host"),
+        };
+
+        let mut last_error = None;
+
+        for addr in addrs {
+            let local: SocketAddr = match addr {
+                SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+                SocketAddr::V6(_) => "[::]:0".parse()?,
+            };
+
+            let socket = match UdpSocket::bind(local).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+
+            match socket.connect(addr).await {
+                Ok(()) => return Ok(socket),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(e.into()),
+            None => anyhow::bail!("Could not resolve {}", self.url),
+        }
     }
 }
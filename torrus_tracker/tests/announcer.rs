@@ -0,0 +1,180 @@
+mod common;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use common::{connect_reply, header, spawn_tracker};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use torrus_core::id::ID;
+use torrus_tracker::{AnnounceStats, Announcer, TrackerList, TrackerRequest};
+use url::Url;
+
+/// Fields of an announce as seen by the tracker.
+#[derive(Debug)]
+struct Announce {
+    event: u32,
+    downloaded: u64,
+    left: u64,
+    uploaded: u64,
+    at: Instant,
+}
+
+/// Spawns a tracker asking for announces every second which fails the first `failures`
+/// announces, every announce it receives is forwarded to the returned receiver.
+async fn recording_tracker(mut failures: usize) -> (Url, mpsc::UnboundedReceiver<Announce>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let (url, _) = spawn_tracker(move |request| {
+        if request.action == 0 {
+            return vec![connect_reply(&request)];
+        }
+
+        let data = &request.data;
+        tx.send(Announce {
+            downloaded: BigEndian::read_u64(&data[40..48]),
+            left: BigEndian::read_u64(&data[48..56]),
+            uploaded: BigEndian::read_u64(&data[56..64]),
+            event: BigEndian::read_u32(&data[64..68]),
+            at: Instant::now(),
+        })
+        .unwrap();
+
+        if failures > 0 {
+            failures -= 1;
+            let mut reply = header(3, request.transaction_id);
+            reply.extend_from_slice(b"try again later");
+            return vec![reply];
+        }
+
+        let mut reply = header(1, request.transaction_id);
+        reply.write_u32::<BigEndian>(1).unwrap();
+        reply.write_u32::<BigEndian>(0).unwrap();
+        reply.write_u32::<BigEndian>(1).unwrap();
+        reply.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        vec![reply]
+    })
+    .await;
+
+    (url, rx)
+}
+
+fn request() -> TrackerRequest {
+    TrackerRequest::builder()
+        .info_hash(ID::from(vec![1; 20]))
+        .set_peer_id(ID::from(vec![2; 20]))
+        .set_port(6881)
+}
+
+#[tokio::test]
+async fn test_announcer_lifecycle() {
+    let (url, mut announces) = recording_tracker(0).await;
+    let (stats_tx, stats) = watch::channel(AnnounceStats {
+        left: 100,
+        ..Default::default()
+    });
+    let (peers_tx, mut peers) = mpsc::channel(8);
+
+    let trackers = TrackerList::new(vec![vec![url.to_string()]]);
+    let (announcer, handle) = Announcer::new(trackers, request(), stats, peers_tx);
+    let task = tokio::spawn(announcer.run());
+
+    let started = announces.recv().await.unwrap();
+    assert_eq!(started.event, 2);
+    assert_eq!(started.left, 100);
+    assert_eq!(
+        peers.recv().await.unwrap(),
+        vec!["127.0.0.1:6881".parse().unwrap()]
+    );
+
+    stats_tx.send_replace(AnnounceStats {
+        uploaded: 10,
+        downloaded: 50,
+        left: 50,
+    });
+
+    // The regular announce honors the interval and reports the live statistics.
+    let regular = announces.recv().await.unwrap();
+    assert_eq!(regular.event, 0);
+    assert!(regular.at - started.at >= Duration::from_millis(900));
+    assert_eq!(regular.uploaded, 10);
+    assert_eq!(regular.downloaded, 50);
+    assert_eq!(regular.left, 50);
+
+    stats_tx.send_replace(AnnounceStats {
+        uploaded: 10,
+        downloaded: 100,
+        left: 0,
+    });
+    handle.completed();
+    handle.completed();
+    let completed = announces.recv().await.unwrap();
+    assert_eq!(completed.event, 1);
+    assert_eq!(completed.left, 0);
+    assert!(completed.at - regular.at < Duration::from_millis(900));
+
+    handle.stop();
+    loop {
+        // `completed` is only sent once, anything else before `stopped` is a regular announce.
+        let announce = announces.recv().await.unwrap();
+        if announce.event == 3 {
+            break;
+        }
+        assert_eq!(announce.event, 0);
+    }
+    task.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_announcer_backoff() {
+    let (url, mut announces) = recording_tracker(2).await;
+    let (_stats_tx, stats) = watch::channel(AnnounceStats::default());
+    let (peers_tx, mut peers) = mpsc::channel(8);
+
+    let trackers = TrackerList::new(vec![vec![url.to_string()]]);
+    let (announcer, handle) = Announcer::new(trackers, request(), stats, peers_tx);
+    let task = tokio::spawn(announcer.with_retry(Duration::from_millis(100)).run());
+
+    let first = announces.recv().await.unwrap();
+    let second = announces.recv().await.unwrap();
+    let third = announces.recv().await.unwrap();
+
+    // `started` is retried until a tracker accepts it.
+    assert_eq!((first.event, second.event, third.event), (2, 2, 2));
+    assert!(second.at - first.at >= Duration::from_millis(100));
+    assert!(third.at - second.at >= Duration::from_millis(200));
+    assert!(peers.recv().await.is_some());
+
+    drop(handle);
+    assert_eq!(announces.recv().await.unwrap().event, 3);
+    task.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_announcer_completed_while_starting() {
+    let (url, mut announces) = recording_tracker(1).await;
+    let (_stats_tx, stats) = watch::channel(AnnounceStats {
+        left: 100,
+        ..Default::default()
+    });
+    let (peers_tx, _peers) = mpsc::channel(8);
+
+    let trackers = TrackerList::new(vec![vec![url.to_string()]]);
+    let (announcer, handle) = Announcer::new(trackers, request(), stats, peers_tx);
+    let task = tokio::spawn(announcer.with_retry(Duration::from_millis(100)).run());
+
+    // `started` failed and waits to be retried when the download completes.
+    assert_eq!(announces.recv().await.unwrap().event, 2);
+    handle.completed();
+
+    assert_eq!(announces.recv().await.unwrap().event, 2);
+    assert_eq!(announces.recv().await.unwrap().event, 1);
+
+    handle.stop();
+    loop {
+        let announce = announces.recv().await.unwrap();
+        if announce.event == 3 {
+            break;
+        }
+        assert_eq!(announce.event, 0);
+    }
+    task.await.unwrap();
+}
@@ -0,0 +1,87 @@
+#![allow(dead_code)]
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::net::UdpSocket;
+use url::Url;
+
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+pub const CONNECTION_ID: u64 = 0xdead_beef;
+
+/// A request received by the stand-in tracker.
+pub struct Request {
+    pub action: u32,
+    pub transaction_id: u32,
+    pub data: Vec<u8>,
+}
+
+/// Spawns a UDP tracker stand-in on localhost which answers every packet with whatever
+/// `handler` returns. Returns the tracker url and the number of connect requests seen.
+pub async fn spawn_tracker<F>(handler: F) -> (Url, Arc<AtomicUsize>)
+where
+    F: FnMut(Request) -> Vec<Vec<u8>> + Send + 'static,
+{
+    spawn_tracker_on("127.0.0.1:0", handler).await
+}
+
+/// Like [spawn_tracker] but listening on `addr`.
+pub async fn spawn_tracker_on<F>(addr: &str, mut handler: F) -> (Url, Arc<AtomicUsize>)
+where
+    F: FnMut(Request) -> Vec<Vec<u8>> + Send + 'static,
+{
+    let socket = UdpSocket::bind(addr).await.unwrap();
+    let url = Url::parse(&format!("udp://{}/announce", socket.local_addr().unwrap())).unwrap();
+    let connects = Arc::new(AtomicUsize::new(0));
+    let counter = connects.clone();
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let packet = &buf[..len];
+            let action = BigEndian::read_u32(&packet[8..12]);
+            if action == 0 {
+                assert_eq!(BigEndian::read_u64(&packet[0..8]), PROTOCOL_ID);
+                counter.fetch_add(1, Ordering::SeqCst);
+            } else {
+                assert_eq!(BigEndian::read_u64(&packet[0..8]), CONNECTION_ID);
+            }
+            let request = Request {
+                action,
+                transaction_id: BigEndian::read_u32(&packet[12..16]),
+                data: packet[16..].to_vec(),
+            };
+            for reply in handler(request) {
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        }
+    });
+
+    (url, connects)
+}
+
+pub fn header(action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut reply = Vec::new();
+    reply.write_u32::<BigEndian>(action).unwrap();
+    reply.write_u32::<BigEndian>(transaction_id).unwrap();
+    reply
+}
+
+pub fn connect_reply(request: &Request) -> Vec<u8> {
+    let mut reply = header(0, request.transaction_id);
+    reply.write_u64::<BigEndian>(CONNECTION_ID).unwrap();
+    reply
+}
+
+pub fn announce_reply(request: &Request) -> Vec<u8> {
+    let mut reply = header(1, request.transaction_id);
+    reply.write_u32::<BigEndian>(1800).unwrap();
+    reply.write_u32::<BigEndian>(3).unwrap();
+    reply.write_u32::<BigEndian>(7).unwrap();
+    reply.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+    reply.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe2]);
+    reply
+}
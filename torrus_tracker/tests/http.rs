@@ -1,35 +1,167 @@
-use anyhow;
-use std::fs;
-use torrus_core::{metainfo::Metainfo, prelude::Sha1Hash};
-use torrus_tracker::Tracker;
+use std::{
+    fs,
+    net::{Ipv6Addr, SocketAddr},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+use torrus_core::{id::ID, metainfo::Metainfo, prelude::Sha1Hash};
+use torrus_tracker::{Event, Tracker, TrackerList, TrackerRequest, TrackerResponse};
+
+/// Serves `body` to every request on a local port, forwarding the request target of each
+/// request to the returned receiver.
+async fn spawn_tracker(body: Vec<u8>) -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 8192];
+            let mut len = 0;
+            while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                len += stream.read(&mut buf[len..]).await.unwrap();
+            }
+            let head = String::from_utf8_lossy(&buf[..len]);
+            let target = head.split(' ').nth(1).unwrap().to_string();
+            tx.send(target).unwrap();
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        }
+    });
+
+    (format!("http://{addr}"), rx)
+}
 
 #[tokio::test]
 async fn test_http_tracker() -> anyhow::Result<()> {
     let bytes = fs::read("../resources/ubuntu-22.10-desktop-amd64.iso.torrent")?;
     let metainfo = Metainfo::new(&bytes)?;
 
-    if let Some(announce_url) = metainfo.announce {
-        let mut tracker = Tracker::new(&announce_url);
-        let id = metainfo.info.as_sha1();
-        let response = match tracker.announce(id).await {
-            Ok(response) => response,
-            Err(msg) => anyhow::bail!("Error {msg}"),
-        };
-        println!("{:?}", response);
-    } else {
-        println!("{}", metainfo);
-        if let Some(al) = metainfo.announce_list {
-            for a in al {
-                let mut tracker = Tracker::new(&a[0]);
-                let id = metainfo.info.as_sha1();
-                let response = match tracker.announce(id).await {
-                    Ok(response) => response,
-                    Err(msg) => anyhow::bail!("Error {msg}"),
-                };
-                println!("{:?}", response);
-            }
-        }
-    }
+    let mut trackers = TrackerList::from_metainfo(&metainfo);
+    let id = metainfo.as_sha1();
+    let response = match trackers.announce(id).await {
+        Ok(response) => response,
+        Err(msg) => anyhow::bail!("Error {msg}"),
+    };
+    println!("{:?}", response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_scrape() -> anyhow::Result<()> {
+    let first = ID::from(vec![b'a'; 20]);
+    let second = ID::from(vec![0xff; 20]);
+
+    let mut body = b"d5:filesd20:".to_vec();
+    body.extend_from_slice(&*first);
+    body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10ee20:");
+    body.extend_from_slice(&*second);
+    // Trackers may leave out fields.
+    body.extend_from_slice(b"d8:completei1e10:incompletei3e4:name3:fooeee");
+
+    let (base, mut requests) = spawn_tracker(body).await;
+    let mut tracker = Tracker::new(&format!("{base}/x/announce.php?passkey=abc"));
+    let stats = tracker.scrape(&[first, second]).await?;
+
+    let target = requests.recv().await.unwrap();
+    assert_eq!(
+        target,
+        format!(
+            "/x/scrape.php?passkey=abc&info_hash={}&info_hash={}",
+            "a".repeat(20),
+            "%FF".repeat(20)
+        )
+    );
+
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[&first].complete, 5);
+    assert_eq!(stats[&first].downloaded, 50);
+    assert_eq!(stats[&first].incomplete, 10);
+    assert_eq!(stats[&second].downloaded, 0);
+    assert_eq!(stats[&second].incomplete, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_scrape_unsupported() {
+    let mut tracker = Tracker::new("http://127.0.0.1:1/tracker");
+    assert!(tracker.scrape(&[ID::default()]).await.is_err());
+}
+
+#[tokio::test]
+async fn test_http_tracker_id() -> anyhow::Result<()> {
+    let body = b"d8:completei1e10:incompletei2e8:intervali1800e10:tracker id3:xyz5:peers0:e";
+    let (base, mut requests) = spawn_tracker(body.to_vec()).await;
+    let mut tracker = Tracker::new(&format!("{base}/announce"));
+
+    let request = TrackerRequest::builder().set_event(Event::Started);
+    let response = tracker.send_request(request).await?;
+    assert!(
+        matches!(response, TrackerResponse::Response { tracker_id: Some(ref id), .. } if id == "xyz")
+    );
+
+    let first = requests.recv().await.unwrap();
+    assert!(first.contains("event=started"));
+    assert!(!first.contains("trackerid"));
+
+    tracker.send_request(TrackerRequest::builder()).await?;
+    let second = requests.recv().await.unwrap();
+    assert!(!second.contains("event="));
+    assert!(second.contains("trackerid=xyz"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_ipv6_peers() -> anyhow::Result<()> {
+    let mut body = b"d8:completei1e10:incompletei2e8:intervali1800e5:peers6:".to_vec();
+    body.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+    body.extend_from_slice(b"6:peers618:");
+    body.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>()?.octets());
+    body.extend_from_slice(&[0x1a, 0xe2]);
+    body.extend_from_slice(b"e");
+
+    let (base, mut requests) = spawn_tracker(body).await;
+    let mut tracker = Tracker::new(&format!("{base}/announce"));
+
+    let request = TrackerRequest::builder()
+        .set_ipv4("203.0.113.7".parse()?)
+        .set_ipv6("2001:db8::2".parse()?);
+    let peers = match tracker.send_request(request).await? {
+        TrackerResponse::Response { peers, .. } => peers.addrs,
+        TrackerResponse::Error { failure_reason } => anyhow::bail!(failure_reason),
+    };
+
+    let expected: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse()?, "[2001:db8::1]:6882".parse()?];
+    assert_eq!(peers, expected);
+
+    let target = requests.recv().await.unwrap();
+    assert!(target.contains("&ipv4=203.0.113.7&"));
+    assert!(target.contains("&ipv6=2001%3Adb8%3A%3A2&"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_dict_peers() -> anyhow::Result<()> {
+    let body = b"d8:completei1e10:incompletei2e8:intervali1800e5:peersld2:ip3:::17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip9:127.0.0.14:porti6882eeee";
+    let (base, _requests) = spawn_tracker(body.to_vec()).await;
+    let mut tracker = Tracker::new(&format!("{base}/announce"));
+
+    let peers = match tracker.send_request(TrackerRequest::builder()).await? {
+        TrackerResponse::Response { peers, .. } => peers.addrs,
+        TrackerResponse::Error { failure_reason } => anyhow::bail!(failure_reason),
+    };
 
+    let expected: Vec<SocketAddr> = vec!["[::1]:6881".parse()?, "127.0.0.1:6882".parse()?];
+    assert_eq!(peers, expected);
     Ok(())
 }
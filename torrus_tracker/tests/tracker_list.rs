@@ -0,0 +1,127 @@
+mod common;
+
+use common::{announce_reply, connect_reply, header, spawn_tracker};
+use std::fs;
+use torrus_core::{id::ID, metainfo::Metainfo};
+use torrus_tracker::{TrackerList, TrackerResponse};
+use url::Url;
+
+async fn live_tracker() -> Url {
+    let (url, _) = spawn_tracker(|request| match request.action {
+        0 => vec![connect_reply(&request)],
+        _ => vec![announce_reply(&request)],
+    })
+    .await;
+    url
+}
+
+/// A tracker which rejects every announce.
+async fn dead_tracker() -> String {
+    let (url, _) = spawn_tracker(|request| match request.action {
+        0 => vec![connect_reply(&request)],
+        _ => {
+            let mut reply = header(3, request.transaction_id);
+            reply.extend_from_slice(b"unregistered torrent");
+            vec![reply]
+        }
+    })
+    .await;
+    url.to_string()
+}
+
+#[tokio::test]
+async fn test_tracker_list_falls_through_tiers() -> anyhow::Result<()> {
+    let live = live_tracker().await.to_string();
+    let mut trackers = TrackerList::new(vec![
+        vec![dead_tracker().await, dead_tracker().await],
+        vec!["wss://unsupported.example/announce".to_string()],
+        vec![live.clone()],
+    ]);
+
+    // The unsupported tier is dropped entirely.
+    assert_eq!(trackers.tiers().len(), 2);
+
+    let response = trackers.announce(ID::default()).await?;
+    assert!(matches!(
+        response,
+        TrackerResponse::Response { interval: 1800, .. }
+    ));
+    assert_eq!(trackers.tiers()[1], vec![live]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tracker_list_promotes_responding_tracker() -> anyhow::Result<()> {
+    let live = live_tracker().await.to_string();
+    let mut trackers = TrackerList::new(vec![vec![
+        dead_tracker().await,
+        live.clone(),
+        dead_tracker().await,
+    ]]);
+
+    trackers.announce(ID::default()).await?;
+    assert_eq!(trackers.tiers()[0][0], live);
+
+    // Succeeds without having to go through the dead trackers again.
+    trackers.announce(ID::default()).await?;
+    assert_eq!(trackers.tiers()[0][0], live);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tracker_list_all_tiers() {
+    let first = live_tracker().await.to_string();
+    let second = live_tracker().await.to_string();
+    let mut trackers =
+        TrackerList::new(vec![vec![first], vec![dead_tracker().await], vec![second]]);
+
+    let request = torrus_tracker::TrackerRequest::builder();
+    let outcomes = trackers.send_request_all(request).await;
+
+    assert_eq!(outcomes.len(), 3);
+    assert!(matches!(outcomes[0], Ok(TrackerResponse::Response { .. })));
+    assert!(matches!(outcomes[1], Ok(TrackerResponse::Error { .. })));
+    assert!(matches!(outcomes[2], Ok(TrackerResponse::Response { .. })));
+}
+
+#[tokio::test]
+async fn test_tracker_list_all_dead() {
+    let mut trackers =
+        TrackerList::new(vec![vec![dead_tracker().await], vec![dead_tracker().await]]);
+    match trackers.announce(ID::default()).await {
+        Ok(TrackerResponse::Error { failure_reason }) => {
+            assert_eq!(failure_reason, "unregistered torrent")
+        }
+        outcome => panic!("Expected the last failure, got {outcome:?}"),
+    }
+
+    let mut trackers = TrackerList::new(Vec::new());
+    assert!(trackers.is_empty());
+    assert!(trackers.announce(ID::default()).await.is_err());
+}
+
+#[test]
+fn test_tracker_list_from_metainfo() -> anyhow::Result<()> {
+    let bytes = fs::read("../resources/ubuntu-22.10-desktop-amd64.iso.torrent")?;
+    let trackers = TrackerList::from_metainfo(&Metainfo::new(&bytes)?);
+    assert_eq!(
+        trackers.tiers(),
+        vec![
+            vec!["https://torrent.ubuntu.com/announce".to_string()],
+            vec!["https://ipv6.torrent.ubuntu.com/announce".to_string()],
+        ]
+    );
+
+    let bytes = fs::read("../resources/multi.torrent")?;
+    let trackers = TrackerList::from_metainfo(&Metainfo::new(&bytes)?);
+    assert_eq!(
+        trackers.tiers(),
+        vec![vec![
+            "udp://tracker.opentrackr.org:1337/announce".to_string()
+        ]]
+    );
+
+    let bytes = fs::read("../resources/archlinux-2022.12.01-x86_64.iso.torrent")?;
+    assert!(TrackerList::from_metainfo(&Metainfo::new(&bytes)?).is_empty());
+    Ok(())
+}
@@ -0,0 +1,206 @@
+mod common;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use common::{announce_reply, connect_reply, header, spawn_tracker, spawn_tracker_on};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use torrus_core::id::ID;
+use torrus_tracker::{TrackerRequest, TrackerResponse, UdpTracker};
+
+fn request() -> TrackerRequest {
+    TrackerRequest::builder()
+        .info_hash(ID::from(vec![1; 20]))
+        .set_peer_id(ID::from(vec![2; 20]))
+        .set_port(6881)
+}
+
+#[tokio::test]
+async fn test_udp_announce() -> anyhow::Result<()> {
+    let (url, connects) = spawn_tracker(|request| match request.action {
+        0 => vec![connect_reply(&request)],
+        _ => {
+            assert_eq!(&request.data[..20], &[1; 20]);
+            assert_eq!(&request.data[20..40], &[2; 20]);
+            assert_eq!(BigEndian::read_u16(&request.data[80..82]), 6881);
+            vec![announce_reply(&request)]
+        }
+    })
+    .await;
+
+    let mut tracker = UdpTracker::new(url);
+
+    for _ in 0..2 {
+        match tracker.send_request(request()).await? {
+            TrackerResponse::Response {
+                interval,
+                complete,
+                incomplete,
+                peers,
+                ..
+            } => {
+                assert_eq!(interval, 1800);
+                assert_eq!(incomplete, 3);
+                assert_eq!(complete, 7);
+                assert_eq!(peers.addrs.len(), 2);
+                assert_eq!(peers.addrs[0].ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+                assert_eq!(peers.addrs[0].port(), 6881);
+                assert_eq!(peers.addrs[1].port(), 6882);
+            }
+            TrackerResponse::Error { failure_reason } => anyhow::bail!(failure_reason),
+        }
+    }
+
+    // The connection id is cached, only the first announce needs to connect.
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_mismatched_transaction_id() -> anyhow::Result<()> {
+    let (url, _) = spawn_tracker(|request| match request.action {
+        0 => {
+            let mut stale = connect_reply(&request);
+            stale[4..8].copy_from_slice(&request.transaction_id.wrapping_add(1).to_be_bytes());
+            vec![stale, connect_reply(&request)]
+        }
+        _ => {
+            let mut stale = announce_reply(&request);
+            stale[4..8].copy_from_slice(&request.transaction_id.wrapping_add(1).to_be_bytes());
+            stale[8..12].copy_from_slice(&60u32.to_be_bytes());
+            vec![stale, announce_reply(&request)]
+        }
+    })
+    .await;
+
+    let mut tracker = UdpTracker::new(url);
+    match tracker.send_request(request()).await? {
+        TrackerResponse::Response { interval, .. } => assert_eq!(interval, 1800),
+        TrackerResponse::Error { failure_reason } => anyhow::bail!(failure_reason),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_only_mismatched_transaction_ids_time_out() {
+    let (url, _) = spawn_tracker(|request| {
+        let mut reply = connect_reply(&request);
+        reply[4..8].copy_from_slice(&request.transaction_id.wrapping_add(1).to_be_bytes());
+        vec![reply]
+    })
+    .await;
+
+    let mut tracker = UdpTracker::new(url).with_retransmission(Duration::from_millis(20), 1);
+    assert!(tracker.send_request(request()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_udp_error_reply() -> anyhow::Result<()> {
+    let (url, _) = spawn_tracker(|request| match request.action {
+        0 => vec![connect_reply(&request)],
+        _ => {
+            let mut reply = header(3, request.transaction_id);
+            reply.extend_from_slice(b"torrent not registered");
+            vec![reply]
+        }
+    })
+    .await;
+
+    let mut tracker = UdpTracker::new(url);
+    match tracker.send_request(request()).await? {
+        TrackerResponse::Error { failure_reason } => {
+            assert_eq!(failure_reason, "torrent not registered")
+        }
+        response => anyhow::bail!("Expected an error, got {response:?}"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_timeout_retransmits() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counter = attempts.clone();
+    let (url, connects) = spawn_tracker(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Vec::new()
+    })
+    .await;
+
+    let mut tracker = UdpTracker::new(url).with_retransmission(Duration::from_millis(25), 2);
+    let start = Instant::now();
+    assert!(tracker.send_request(request()).await.is_err());
+
+    // 25ms + 50ms + 100ms
+    assert!(start.elapsed() >= Duration::from_millis(175));
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert_eq!(connects.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_udp_scrape() -> anyhow::Result<()> {
+    let (url, connects) = spawn_tracker(|request| match request.action {
+        0 => vec![connect_reply(&request)],
+        _ => {
+            assert_eq!(request.action, 2);
+            let mut reply = header(2, request.transaction_id);
+            for (n, info_hash) in request.data.chunks_exact(20).enumerate() {
+                let n = n as u32;
+                reply.write_u32::<BigEndian>(info_hash[0] as u32).unwrap();
+                reply.write_u32::<BigEndian>(n * 10).unwrap();
+                reply.write_u32::<BigEndian>(n).unwrap();
+            }
+            vec![reply]
+        }
+    })
+    .await;
+
+    // More than fit into a single scrape packet.
+    let info_hashes: Vec<ID> = (0..100u8).map(|n| ID::from(vec![n; 20])).collect();
+
+    let mut tracker = UdpTracker::new(url);
+    let stats = tracker.scrape(&info_hashes).await?;
+
+    assert_eq!(stats.len(), 100);
+    for info_hash in &info_hashes {
+        assert_eq!(stats[info_hash].complete, info_hash[0] as u32);
+    }
+    assert_eq!(stats[&info_hashes[75]].downloaded, 10);
+    assert_eq!(stats[&info_hashes[75]].incomplete, 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_ipv6_announce() -> anyhow::Result<()> {
+    let (url, _) = spawn_tracker_on("[::1]:0", |request| match request.action {
+        0 => vec![connect_reply(&request)],
+        _ => {
+            // The 32 bit ip field is meaningless over IPv6.
+            assert_eq!(BigEndian::read_u32(&request.data[68..72]), 0);
+            let mut reply = header(1, request.transaction_id);
+            reply.write_u32::<BigEndian>(1800).unwrap();
+            reply.write_u32::<BigEndian>(0).unwrap();
+            reply.write_u32::<BigEndian>(1).unwrap();
+            reply.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+            reply.extend_from_slice(&[0x1a, 0xe1]);
+            vec![reply]
+        }
+    })
+    .await;
+    assert_eq!(url.host_str(), Some("[::1]"));
+
+    let mut tracker = UdpTracker::new(url);
+    match tracker.send_request(request().set_ip(0x7f000001)).await? {
+        TrackerResponse::Response { peers, .. } => {
+            let expected: SocketAddr = "[::1]:6881".parse()?;
+            assert_eq!(peers.addrs, vec![expected]);
+        }
+        TrackerResponse::Error { failure_reason } => anyhow::bail!(failure_reason),
+    }
+    Ok(())
+}
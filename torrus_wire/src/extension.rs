@@ -0,0 +1,261 @@
+use crate::Message;
+use anyhow::Result;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::IpAddr};
+
+/// Extended message id of the extended handshake, the ids of the extensions are assigned
+/// through its `m` dictionary.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// First extended message sent in both directions, announces the supported extensions and
+/// a few facts about the sender. Every field is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the id the sender wants to receive them with, 0 disables an
+    /// extension.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Size of the info dictionary in bytes, announced by peers serving it over ut_metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+    /// Port the sender accepts connections on.
+    #[serde(default, rename = "p", skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Number of outstanding requests the sender accepts.
+    #[serde(default, rename = "reqq", skip_serializing_if = "Option::is_none")]
+    pub request_queue: Option<u32>,
+    /// Client name and version.
+    #[serde(default, rename = "v", skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Address the sender sees the receiver connecting from, 4 or 16 bytes.
+    #[serde(default, rename = "yourip", skip_serializing_if = "Option::is_none")]
+    pub your_ip: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(payload)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    /// Id the sender assigned to an extension, `None` if it does not support it.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+
+    /// Our address as the sender sees it, `None` if it is missing or malformed.
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let bytes: &[u8] = self.your_ip.as_ref()?;
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+            _ => None,
+        }
+    }
+
+    pub fn set_your_ip(&mut self, ip: IpAddr) {
+        let bytes = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        self.your_ip = Some(ByteBuf::from(bytes));
+    }
+}
+
+/// Handles the messages peers send for one extension, turning them into `T`.
+pub trait ExtensionHandler<T>: Send + Sync {
+    /// Decodes the payload of an extended message, `None` if there is nothing to report.
+    fn handle(&mut self, payload: &[u8]) -> Result<Option<T>>;
+}
+
+impl<T, F> ExtensionHandler<T> for F
+where
+    F: FnMut(&[u8]) -> Result<Option<T>> + Send + Sync,
+{
+    fn handle(&mut self, payload: &[u8]) -> Result<Option<T>> {
+        self(payload)
+    }
+}
+
+/// Extensions spoken on a connection, see BEP 10.
+///
+/// Every extension registers under its name and is assigned the id peers send its messages
+/// with, announced through the `m` dictionary of our [ExtendedHandshake]. The peer assigns
+/// its own ids in its handshake, those are used for the messages we send.
+pub struct ExtensionRegistry<T> {
+    /// Registered extensions, the local id of an extension is its position plus one.
+    handlers: Vec<(&'static str, Box<dyn ExtensionHandler<T>>)>,
+    peer: Option<ExtendedHandshake>,
+}
+
+impl<T> ExtensionRegistry<T> {
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+            peer: None,
+        }
+    }
+
+    /// Registers an extension, returns the id peers send its messages with.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        handler: impl ExtensionHandler<T> + 'static,
+    ) -> u8 {
+        assert!(
+            self.local_id(name).is_none(),
+            "{name} is already registered"
+        );
+        assert!(
+            self.handlers.len() < u8::MAX as usize,
+            "Too many extensions"
+        );
+        self.handlers.push((name, Box::new(handler)));
+        self.handlers.len() as u8
+    }
+
+    /// Id peers send messages of the extension with.
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        let position = self.handlers.iter().position(|(n, _)| *n == name)?;
+        Some(position as u8 + 1)
+    }
+
+    /// Our extended handshake announcing every registered extension, the other fields are
+    /// left for the caller to fill in.
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let m = self
+            .handlers
+            .iter()
+            .enumerate()
+            .map(|(position, (name, _))| (name.to_string(), position as u8 + 1))
+            .collect();
+        ExtendedHandshake {
+            m,
+            ..Default::default()
+        }
+    }
+
+    /// Remembers the ids the peer assigned, a later handshake replaces them.
+    pub fn set_peer_handshake(&mut self, handshake: ExtendedHandshake) {
+        self.peer = Some(handshake);
+    }
+
+    /// The extended handshake the peer sent, if any.
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer.as_ref()
+    }
+
+    /// Whether the peer supports the extension.
+    pub fn peer_supports(&self, name: &str) -> bool {
+        self.peer_id(name).is_some()
+    }
+
+    /// Id the peer wants to receive messages of the extension with.
+    pub fn peer_id(&self, name: &str) -> Option<u8> {
+        self.peer.as_ref()?.extension_id(name)
+    }
+
+    /// Wraps the payload of an extension message for the peer, `None` if the peer does not
+    /// support the extension.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        let id = self.peer_id(name)?;
+        Some(Message::Extended { id, payload })
+    }
+
+    /// Hands an extended message the peer sent to the extension registered under `id`.
+    /// Messages for ids we never assigned are ignored.
+    pub fn dispatch(&mut self, id: u8, payload: &[u8]) -> Result<Option<T>> {
+        let Some((_, handler)) = self.handlers.get_mut(usize::from(id).wrapping_sub(1)) else {
+            return Ok(None);
+        };
+        handler.handle(payload)
+    }
+}
+
+impl<T> Default for ExtensionRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extended_handshake_round_trip() -> Result<()> {
+        let mut handshake = ExtendedHandshake {
+            m: BTreeMap::from([("ut_metadata".into(), 3), ("ut_pex".into(), 0)]),
+            metadata_size: Some(31235),
+            port: Some(6881),
+            request_queue: Some(250),
+            client: Some("torrus 0.1".into()),
+            your_ip: None,
+        };
+        handshake.set_your_ip([10, 0, 0, 1].into());
+        let bytes = handshake.to_bytes()?;
+        assert_eq!(
+            bytes,
+            &b"d1:md11:ut_metadatai3e6:ut_pexi0ee13:metadata_sizei31235e\
+                1:pi6881e4:reqqi250e1:v10:torrus 0.16:yourip4:\x0a\x00\x00\x01e"[..]
+        );
+        assert_eq!(handshake.your_ip(), Some([10, 0, 0, 1].into()));
+        assert_eq!(ExtendedHandshake::from_bytes(&bytes)?, handshake);
+        assert_eq!(handshake.extension_id("ut_metadata"), Some(3));
+        assert_eq!(handshake.extension_id("ut_pex"), None);
+        assert_eq!(handshake.extension_id("lt_donthave"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extended_handshake_unknown_keys() -> Result<()> {
+        let handshake = ExtendedHandshake::from_bytes(b"d1:md6:ut_pexi1ee1:v4:test4:reqqi250ee")?;
+        assert_eq!(handshake.extension_id("ut_pex"), Some(1));
+        assert_eq!(handshake.metadata_size, None);
+        assert_eq!(handshake.client.as_deref(), Some("test"));
+        assert_eq!(handshake.request_queue, Some(250));
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_registry() -> Result<()> {
+        let mut registry = ExtensionRegistry::new();
+        let metadata = registry.register("ut_metadata", |payload: &[u8]| {
+            Ok(Some(format!("metadata {}", payload.len())))
+        });
+        let pex = registry.register("ut_pex", |_: &[u8]| Ok(None));
+        assert_eq!((metadata, pex), (1, 2));
+        assert_eq!(registry.local_id("ut_pex"), Some(2));
+        assert_eq!(
+            registry.handshake().m,
+            BTreeMap::from([("ut_metadata".into(), 1), ("ut_pex".into(), 2)])
+        );
+
+        // Incoming messages carry the ids we assigned.
+        assert_eq!(registry.dispatch(1, b"abc")?, Some("metadata 3".into()));
+        assert_eq!(registry.dispatch(2, b"abc")?, None);
+        assert_eq!(registry.dispatch(0, b"abc")?, None);
+        assert_eq!(registry.dispatch(9, b"abc")?, None);
+
+        // Outgoing messages carry the ids the peer assigned.
+        assert_eq!(registry.message("ut_metadata", vec![1]), None);
+        registry.set_peer_handshake(ExtendedHandshake {
+            m: BTreeMap::from([("ut_metadata".into(), 7), ("ut_pex".into(), 0)]),
+            ..Default::default()
+        });
+        assert_eq!(
+            registry.message("ut_metadata", vec![1]),
+            Some(Message::Extended {
+                id: 7,
+                payload: vec![1]
+            })
+        );
+        assert!(!registry.peer_supports("ut_pex"));
+        assert!(!registry.peer_supports("lt_donthave"));
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use torrus_core::id::ID;
+
+/// Protocol string every handshake starts with.
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length of the handshake on the wire.
+pub const HANDSHAKE_LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+/// Byte and bit of `reserved` announcing the extension protocol of BEP 10.
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+/// Byte and bit of `reserved` announcing support for v2 torrents of BEP 52.
+const V2: (usize, u8) = (7, 0x10);
+
+/// The first message exchanged in both directions of a peer connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    /// Bits announcing protocol extensions, all zero when none are supported.
+    pub reserved: [u8; 8],
+    pub info_hash: ID,
+    pub peer_id: ID,
+}
+
+impl Handshake {
+    pub fn new(info_hash: ID, peer_id: ID) -> Self {
+        Self {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Announces support for the extension protocol.
+    pub fn with_extensions(mut self) -> Self {
+        let (byte, bit) = EXTENSION_PROTOCOL;
+        self.reserved[byte] |= bit;
+        self
+    }
+
+    /// Whether the peer speaks the extension protocol, extended messages may only be sent
+    /// when both ends announce it.
+    pub fn supports_extensions(&self) -> bool {
+        let (byte, bit) = EXTENSION_PROTOCOL;
+        self.reserved[byte] & bit != 0
+    }
+
+    /// Announces support for v2 torrents.
+    pub fn with_v2(mut self) -> Self {
+        let (byte, bit) = V2;
+        self.reserved[byte] |= bit;
+        self
+    }
+
+    /// Whether the peer supports v2 torrents and answers hash requests.
+    pub fn supports_v2(&self) -> bool {
+        let (byte, bit) = V2;
+        self.reserved[byte] & bit != 0
+    }
+}
+
+/// Codec for the handshake, replaced by [crate::MessageCodec] once the handshake is done.
+#[derive(Debug, Default)]
+pub struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        // Bail out on a foreign protocol as early as possible instead of waiting for the whole
+        // handshake to arrive.
+        let prefix = src.len().min(1 + PROTOCOL.len());
+        if src[0] as usize != PROTOCOL.len() || src[1..prefix] != PROTOCOL[..prefix - 1] {
+            anyhow::bail!("Peer does not speak the BitTorrent protocol");
+        }
+
+        if src.len() < HANDSHAKE_LENGTH {
+            src.reserve(HANDSHAKE_LENGTH - src.len());
+            return Ok(None);
+        }
+
+        src.advance(1 + PROTOCOL.len());
+
+        let mut reserved = [0; 8];
+        src.copy_to_slice(&mut reserved);
+        let info_hash = ID::from(src.split_to(20).to_vec());
+        let peer_id = ID::from(src.split_to(20).to_vec());
+
+        Ok(Some(Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        }))
+    }
+}
+
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Handshake, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(HANDSHAKE_LENGTH);
+        dst.put_u8(PROTOCOL.len() as u8);
+        dst.put_slice(PROTOCOL);
+        dst.put_slice(&item.reserved);
+        dst.put_slice(&*item.info_hash);
+        dst.put_slice(&*item.peer_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> Handshake {
+        Handshake {
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0],
+            info_hash: ID::from(vec![1; 20]),
+            peer_id: ID::from(vec![2; 20]),
+        }
+    }
+
+    #[test]
+    fn test_handshake_round_trip() -> Result<()> {
+        let mut buf = BytesMut::new();
+        HandshakeCodec.encode(handshake(), &mut buf)?;
+        assert_eq!(buf.len(), HANDSHAKE_LENGTH);
+
+        // Trailing bytes belong to the message stream and must be left alone.
+        buf.put_slice(&[0, 0, 0, 1, 2]);
+
+        assert_eq!(HandshakeCodec.decode(&mut buf)?, Some(handshake()));
+        assert_eq!(&buf[..], &[0, 0, 0, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_partial() -> Result<()> {
+        let mut encoded = BytesMut::new();
+        HandshakeCodec.encode(handshake(), &mut encoded)?;
+
+        let mut buf = BytesMut::new();
+        for byte in &encoded[..HANDSHAKE_LENGTH - 1] {
+            buf.put_u8(*byte);
+            assert_eq!(HandshakeCodec.decode(&mut buf)?, None);
+        }
+        buf.put_u8(encoded[HANDSHAKE_LENGTH - 1]);
+        assert_eq!(HandshakeCodec.decode(&mut buf)?, Some(handshake()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_extensions() {
+        let plain = Handshake::new(ID::default(), ID::default());
+        assert!(!plain.supports_extensions());
+        assert!(plain.with_extensions().supports_extensions());
+        assert!(handshake().supports_extensions());
+    }
+
+    #[test]
+    fn test_handshake_v2() {
+        let plain = Handshake::new(ID::default(), ID::default()).with_extensions();
+        assert!(!plain.supports_v2());
+        let v2 = plain.with_v2();
+        assert!(v2.supports_v2());
+        assert!(v2.supports_extensions());
+        assert_eq!(v2.reserved[7], 0x10);
+    }
+
+    #[test]
+    fn test_handshake_foreign_protocol() {
+        let mut buf = BytesMut::from(&b"\x13BitTorrent protocoX"[..]);
+        assert!(HandshakeCodec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        assert!(HandshakeCodec.decode(&mut buf).is_err());
+    }
+}
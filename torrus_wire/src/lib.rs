@@ -0,0 +1,9 @@
+mod extension;
+mod handshake;
+mod message;
+mod metadata;
+
+pub use extension::*;
+pub use handshake::*;
+pub use message::*;
+pub use metadata::*;
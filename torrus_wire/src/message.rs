@@ -0,0 +1,488 @@
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use torrus_core::prelude::{Block, Blockinfo, HashRequest};
+
+/// Default upper bound for the length of a single message. Large enough for a 16 KiB piece
+/// message and the bitfield of a torrent with two million pieces.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 18;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const EXTENDED: u8 = 20;
+const HASH_REQUEST: u8 = 21;
+const HASHES: u8 = 22;
+const HASH_REJECT: u8 = 23;
+
+/// Length of an encoded [HashRequest].
+const HASH_REQUEST_LENGTH: usize = 48;
+
+/// Messages of the peer wire protocol as described in BEP 3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(Blockinfo),
+    Piece(Block),
+    Cancel(Blockinfo),
+    /// Listen port of the peer's DHT node.
+    Port(u16),
+    /// Message of the extension protocol, `id` 0 is the extended handshake and the others are
+    /// assigned by the receiving end. See [crate::ExtendedHandshake].
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// Asks for hashes of a v2 file's merkle tree, see BEP 52.
+    HashRequest(HashRequest),
+    /// The requested hashes followed by the uncle hashes proving them.
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    /// The peer does not hand out the requested hashes.
+    HashReject(HashRequest),
+}
+
+/// Codec for the length prefixed messages following the handshake.
+#[derive(Debug)]
+pub struct MessageCodec {
+    max_length: usize,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self {
+            max_length: MAX_MESSAGE_LENGTH,
+        }
+    }
+
+    /// Frames longer than `max_length` are rejected with an error.
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn expect_length(id: u8, payload: &[u8], length: usize) -> Result<()> {
+    if payload.len() != length {
+        anyhow::bail!(
+            "Message {id} has a payload of {} bytes, expected {length}",
+            payload.len()
+        );
+    }
+    Ok(())
+}
+
+fn decode_blockinfo(mut payload: &[u8]) -> Blockinfo {
+    let index = payload.get_u32() as usize;
+    let offset = payload.get_u32() as u64;
+    let length = payload.get_u32() as u64;
+    Blockinfo {
+        offset,
+        length,
+        index,
+    }
+}
+
+fn encode_blockinfo(block_info: &Blockinfo, dst: &mut BytesMut) -> Result<()> {
+    dst.put_u32(u32::try_from(block_info.index)?);
+    dst.put_u32(u32::try_from(block_info.offset)?);
+    dst.put_u32(u32::try_from(block_info.length)?);
+    Ok(())
+}
+
+fn decode_hash_request(mut payload: &[u8]) -> HashRequest {
+    let mut pieces_root = [0; 32];
+    payload.copy_to_slice(&mut pieces_root);
+    HashRequest {
+        pieces_root,
+        base_layer: payload.get_u32(),
+        index: payload.get_u32(),
+        length: payload.get_u32(),
+        proof_layers: payload.get_u32(),
+    }
+}
+
+fn encode_hash_request(request: &HashRequest, dst: &mut BytesMut) {
+    dst.put_slice(&request.pieces_root);
+    dst.put_u32(request.base_layer);
+    dst.put_u32(request.index);
+    dst.put_u32(request.length);
+    dst.put_u32(request.proof_layers);
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if length > self.max_length {
+            anyhow::bail!(
+                "Message of {length} bytes exceeds the limit of {}",
+                self.max_length
+            );
+        }
+
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        if length == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+
+        let frame = src.split_to(length);
+        let id = frame[0];
+        let payload = &frame[1..];
+
+        let message = match id {
+            CHOKE | UNCHOKE | INTERESTED | NOT_INTERESTED => {
+                expect_length(id, payload, 0)?;
+                match id {
+                    CHOKE => Message::Choke,
+                    UNCHOKE => Message::Unchoke,
+                    INTERESTED => Message::Interested,
+                    _ => Message::NotInterested,
+                }
+            }
+            HAVE => {
+                expect_length(id, payload, 4)?;
+                Message::Have(u32::from_be_bytes([
+                    payload[0], payload[1], payload[2], payload[3],
+                ]))
+            }
+            BITFIELD => Message::Bitfield(payload.to_vec()),
+            REQUEST => {
+                expect_length(id, payload, 12)?;
+                Message::Request(decode_blockinfo(payload))
+            }
+            CANCEL => {
+                expect_length(id, payload, 12)?;
+                Message::Cancel(decode_blockinfo(payload))
+            }
+            PIECE => {
+                if payload.len() < 8 {
+                    anyhow::bail!("Piece message of {} bytes is too short", payload.len());
+                }
+                let mut header = &payload[..8];
+                let data = &payload[8..];
+                let block_info = Blockinfo {
+                    index: header.get_u32() as usize,
+                    offset: header.get_u32() as u64,
+                    length: data.len() as u64,
+                };
+                Message::Piece(Block::new(data, block_info))
+            }
+            PORT => {
+                expect_length(id, payload, 2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            EXTENDED => {
+                let Some((id, payload)) = payload.split_first() else {
+                    anyhow::bail!("Extended message without extension id");
+                };
+                Message::Extended {
+                    id: *id,
+                    payload: payload.to_vec(),
+                }
+            }
+            HASH_REQUEST | HASH_REJECT => {
+                expect_length(id, payload, HASH_REQUEST_LENGTH)?;
+                let request = decode_hash_request(payload);
+                match id {
+                    HASH_REQUEST => Message::HashRequest(request),
+                    _ => Message::HashReject(request),
+                }
+            }
+            HASHES => {
+                let Some(hashes) = payload.get(HASH_REQUEST_LENGTH..) else {
+                    anyhow::bail!("Hashes message of {} bytes is too short", payload.len());
+                };
+                if hashes.len() % 32 != 0 {
+                    anyhow::bail!("Hashes message with {} bytes of hashes", hashes.len());
+                }
+                Message::Hashes {
+                    request: decode_hash_request(payload),
+                    hashes: hashes
+                        .chunks_exact(32)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                }
+            }
+            id => anyhow::bail!("Unknown message id {id}"),
+        };
+
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
+        match item {
+            Message::KeepAlive => dst.put_u32(0),
+            Message::Choke => put_header(dst, 1, CHOKE),
+            Message::Unchoke => put_header(dst, 1, UNCHOKE),
+            Message::Interested => put_header(dst, 1, INTERESTED),
+            Message::NotInterested => put_header(dst, 1, NOT_INTERESTED),
+            Message::Have(index) => {
+                put_header(dst, 5, HAVE);
+                dst.put_u32(index);
+            }
+            Message::Bitfield(bitfield) => {
+                put_header(dst, 1 + u32::try_from(bitfield.len())?, BITFIELD);
+                dst.put_slice(&bitfield);
+            }
+            Message::Request(block_info) => {
+                put_header(dst, 13, REQUEST);
+                encode_blockinfo(&block_info, dst)?;
+            }
+            Message::Cancel(block_info) => {
+                put_header(dst, 13, CANCEL);
+                encode_blockinfo(&block_info, dst)?;
+            }
+            Message::Piece(block) => {
+                put_header(dst, 9 + u32::try_from(block.len())?, PIECE);
+                dst.put_u32(u32::try_from(block.block_info.index)?);
+                dst.put_u32(u32::try_from(block.block_info.offset)?);
+                dst.put_slice(&block);
+            }
+            Message::Port(port) => {
+                put_header(dst, 3, PORT);
+                dst.put_u16(port);
+            }
+            Message::Extended { id, payload } => {
+                put_header(dst, 2 + u32::try_from(payload.len())?, EXTENDED);
+                dst.put_u8(id);
+                dst.put_slice(&payload);
+            }
+            Message::HashRequest(request) => {
+                put_header(dst, 1 + HASH_REQUEST_LENGTH as u32, HASH_REQUEST);
+                encode_hash_request(&request, dst);
+            }
+            Message::Hashes { request, hashes } => {
+                let length = HASH_REQUEST_LENGTH + hashes.len() * 32;
+                put_header(dst, 1 + u32::try_from(length)?, HASHES);
+                encode_hash_request(&request, dst);
+                for hash in hashes {
+                    dst.put_slice(&hash);
+                }
+            }
+            Message::HashReject(request) => {
+                put_header(dst, 1 + HASH_REQUEST_LENGTH as u32, HASH_REJECT);
+                encode_hash_request(&request, dst);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_header(dst: &mut BytesMut, length: u32, id: u8) {
+    dst.reserve(4 + length as usize);
+    dst.put_u32(length);
+    dst.put_u8(id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, RngCore};
+
+    fn blockinfo(index: usize, offset: u64, length: u64) -> Blockinfo {
+        Blockinfo {
+            offset,
+            length,
+            index,
+        }
+    }
+
+    fn hash_request() -> HashRequest {
+        HashRequest {
+            pieces_root: [9; 32],
+            base_layer: 0,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(42),
+            Message::Bitfield(vec![0b1010_0000, 0xff]),
+            Message::Request(blockinfo(1, 16384, 16384)),
+            Message::Piece(Block::new(&[7; 100], blockinfo(3, 32768, 100))),
+            Message::Cancel(blockinfo(1, 16384, 16384)),
+            Message::Port(6881),
+            Message::Extended {
+                id: 3,
+                payload: b"d1:ai1ee".to_vec(),
+            },
+            Message::HashRequest(hash_request()),
+            Message::Hashes {
+                request: hash_request(),
+                hashes: vec![[1; 32], [2; 32], [3; 32], [4; 32], [5; 32]],
+            },
+            Message::HashReject(hash_request()),
+        ]
+    }
+
+    fn encode(messages: &[Message]) -> Result<BytesMut> {
+        let mut buf = BytesMut::new();
+        for message in messages {
+            MessageCodec::new().encode(message.clone(), &mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_message_round_trip() -> Result<()> {
+        let mut buf = encode(&messages())?;
+
+        let mut codec = MessageCodec::new();
+        for message in messages() {
+            assert_eq!(codec.decode(&mut buf)?, Some(message));
+        }
+        assert_eq!(codec.decode(&mut buf)?, None);
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_wire_format() -> Result<()> {
+        let buf = encode(&[Message::Request(blockinfo(1, 2, 3))])?;
+        assert_eq!(
+            &buf[..],
+            &[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+
+        let buf = encode(&[Message::Have(258)])?;
+        assert_eq!(&buf[..], &[0, 0, 0, 5, 4, 0, 0, 1, 2]);
+
+        let buf = encode(&[Message::HashReject(hash_request())])?;
+        assert_eq!(&buf[..5], &[0, 0, 0, 49, HASH_REJECT]);
+        assert_eq!(&buf[5..37], &[9; 32]);
+        assert_eq!(
+            &buf[37..],
+            &[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_byte_by_byte() -> Result<()> {
+        let encoded = encode(&messages())?;
+
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            buf.put_u8(*byte);
+            while let Some(message) = codec.decode(&mut buf)? {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, messages());
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_oversized_frame() {
+        let mut codec = MessageCodec::new().with_max_length(1024);
+
+        // Rejected from the length prefix alone, before the payload arrives.
+        let mut buf = BytesMut::from(&[0, 0, 4, 1, 7][..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = encode(&[Message::Bitfield(vec![0; 1023])]).unwrap();
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_message_invalid_payloads() {
+        let mut hashes = encode(&[Message::Hashes {
+            request: hash_request(),
+            hashes: vec![[1; 32]],
+        }])
+        .unwrap();
+        // Claim one byte less, cutting the hash short.
+        hashes[3] -= 1;
+        hashes.truncate(hashes.len() - 1);
+        let short_request = [&[0, 0, 0, 48, HASH_REQUEST][..], &[0; 47]].concat();
+
+        let invalid: [&[u8]; 9] = [
+            &hashes,
+            &short_request,
+            &[0, 0, 0, 2, CHOKE, 0],
+            &[0, 0, 0, 4, HAVE, 0, 0, 0],
+            &[0, 0, 0, 12, REQUEST, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 5, PIECE, 0, 0, 0, 0],
+            &[0, 0, 0, 2, PORT, 0],
+            &[0, 0, 0, 1, EXTENDED],
+            &[0, 0, 0, 1, 99],
+        ];
+        for frame in invalid {
+            let mut buf = BytesMut::from(frame);
+            assert!(MessageCodec::new().decode(&mut buf).is_err(), "{frame:?}");
+        }
+    }
+
+    /// Feeds random garbage to the decoder, it must only ever fail with an error.
+    #[test]
+    fn test_message_fuzz() {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10_000 {
+            let mut frame = vec![0u8; rng.gen_range(0..64)];
+            rng.fill_bytes(&mut frame);
+            if frame.len() >= 5 && rng.gen_bool(0.8) {
+                // Mostly produce plausible frames so the payload checks are reached.
+                let length = (frame.len() - 4) as u32;
+                frame[..4].copy_from_slice(&length.to_be_bytes());
+                frame[4] %= 24;
+            }
+
+            let mut codec = MessageCodec::new();
+            let mut buf = BytesMut::from(&frame[..]);
+            while let Ok(Some(message)) = codec.decode(&mut buf) {
+                // Whatever decodes must encode back to the same bytes.
+                let mut encoded = BytesMut::new();
+                codec.encode(message.clone(), &mut encoded).unwrap();
+                assert_eq!(codec.decode(&mut encoded).unwrap(), Some(message));
+            }
+        }
+    }
+}
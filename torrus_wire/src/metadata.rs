@@ -0,0 +1,141 @@
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use torrus_core::bencode;
+
+/// Name of the metadata exchange extension of BEP 9 in the `m` dictionary.
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The info dictionary is exchanged in pieces of 16 KiB, only the last one may be shorter.
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+/// Messages of the ut_metadata extension, the payload of an extended message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    /// Asks for a piece of the info dictionary.
+    Request(u32),
+    /// A piece of the info dictionary and the size of the whole dictionary.
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    /// The peer does not hand out the piece.
+    Reject(u32),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<u64>,
+}
+
+impl MetadataMessage {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self> {
+        // Data messages append the piece to the bencoded dictionary.
+        let length = bencode::value_length(payload)?;
+        let header: Header = serde_bencode::from_bytes(&payload[..length])?;
+        let trailing = &payload[length..];
+
+        let message = match header.msg_type {
+            REQUEST => MetadataMessage::Request(header.piece),
+            DATA => MetadataMessage::Data {
+                piece: header.piece,
+                total_size: header
+                    .total_size
+                    .ok_or_else(|| anyhow::anyhow!("Metadata piece without total_size"))?,
+                data: trailing.to_vec(),
+            },
+            REJECT => MetadataMessage::Reject(header.piece),
+            msg_type => anyhow::bail!("Unknown ut_metadata message type {msg_type}"),
+        };
+        if !trailing.is_empty() && !matches!(message, MetadataMessage::Data { .. }) {
+            anyhow::bail!("Unexpected data after ut_metadata message");
+        }
+        Ok(message)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let (header, data) = match self {
+            MetadataMessage::Request(piece) => (header(REQUEST, *piece, None), &[][..]),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (header(DATA, *piece, Some(*total_size)), &data[..]),
+            MetadataMessage::Reject(piece) => (header(REJECT, *piece, None), &[][..]),
+        };
+        let mut bytes = serde_bencode::to_bytes(&header)?;
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+    }
+}
+
+fn header(msg_type: u8, piece: u32, total_size: Option<u64>) -> Header {
+    Header {
+        msg_type,
+        piece,
+        total_size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_wire_format() -> Result<()> {
+        assert_eq!(
+            MetadataMessage::Request(0).to_bytes()?,
+            b"d8:msg_typei0e5:piecei0ee"
+        );
+        assert_eq!(
+            MetadataMessage::Reject(3).to_bytes()?,
+            b"d8:msg_typei2e5:piecei3ee"
+        );
+        let data = MetadataMessage::Data {
+            piece: 1,
+            total_size: 20000,
+            data: b"d4:name".to_vec(),
+        };
+        assert_eq!(
+            data.to_bytes()?,
+            b"d8:msg_typei1e5:piecei1e10:total_sizei20000eed4:name"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_round_trip() -> Result<()> {
+        for message in [
+            MetadataMessage::Request(7),
+            MetadataMessage::Reject(2),
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 3,
+                data: vec![b'e'; 3],
+            },
+        ] {
+            assert_eq!(MetadataMessage::from_bytes(&message.to_bytes()?)?, message);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_invalid() {
+        for invalid in [
+            &b"d8:msg_typei1e5:piecei0eexyz"[..],
+            b"d8:msg_typei9e5:piecei0ee",
+            b"d8:msg_typei0e5:piecei0eexyz",
+            b"d5:piecei0ee",
+            b"d8:msg_typei0e",
+        ] {
+            assert!(MetadataMessage::from_bytes(invalid).is_err(), "{invalid:?}");
+        }
+    }
+}